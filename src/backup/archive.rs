@@ -0,0 +1,604 @@
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{fmt, fs, io};
+
+use log::{info, warn};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tar::{Archive, Builder, EntryType, Header};
+use uuid::Uuid;
+use crate::backup::archive::BackupError::{DatabaseError, InvalidArchive, IoError};
+use crate::config::settings::{Settings, LIVE_SETTINGS};
+use crate::config::setup::DeviceConfiguration;
+use crate::rfid::card_store::CardStore;
+use crate::rfid::sequencer::CardAction;
+use crate::video_handler::default_images::{is_custom_idle, is_generated_screen, mark_idle_custom, IDLE_SCREEN};
+use crate::web_server::api::DeviceIdentity;
+use crate::web_server::chunked_upload::sha256_file;
+use crate::web_server::media_path::resolve_new_media_path;
+use crate::web_server::upload_handler::staging_dir;
+
+/// Bumped whenever the layout changes in a way older devices can't restore.
+const FORMAT_VERSION: u32 = 1;
+const MANIFEST: &str = "manifest.json";
+const CONFIG: &str = "config/Config.yaml";
+const CARDS: &str = "cards.json";
+const MEDIA_DIR: &str = "media/";
+const SCREENS_DIR: &str = "screens/";
+/// Screens an admin can replace, the others are always generated by the device
+const CUSTOM_SCREENS: [&str; 1] = [IDLE_SCREEN];
+/// Largest manifest, config or card list that is read into memory
+const MAX_METADATA_SIZE: u64 = 16 * 1024 * 1024;
+/// Restores are staged in `.staging/restore-<uuid>`, anything left there after a restart is removed
+pub const RESTORE_DIR_PREFIX: &str = "restore-";
+
+/// Where the parts of a device that go into a backup live.
+pub struct DeviceFiles<'a> {
+    pub files_dir: &'a Path,
+    pub config_path: &'a Path,
+    pub cards: &'a CardStore,
+}
+
+/// First entry of every backup, describing what else is in it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Manifest {
+    pub format: u32,
+    /// Seconds since the unix epoch
+    pub created: u64,
+    pub device: DeviceIdentity,
+    pub media: Vec<String>,
+    pub screens: Vec<String>,
+    pub cards: usize,
+}
+
+/// A pairing by media file name rather than path, so it can be restored into a library somewhere else.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CardMapping {
+    pub card_id: String,
+    /// The first file the card plays, all older backups have
+    pub media: String,
+    #[serde(default)]
+    pub actions: Vec<CardAction>,
+}
+
+impl CardMapping {
+    /// What the card does, a card from an older backup only plays its media.
+    fn actions(&self) -> Vec<CardAction> {
+        if self.actions.is_empty() {
+            vec![CardAction::PlayMedia { media: self.media.clone() }]
+        } else {
+            self.actions.clone()
+        }
+    }
+}
+
+/// What restoring an archive would do, or did when it was not a dry run.
+#[derive(Debug, Serialize)]
+pub struct RestoreReport {
+    pub dry_run: bool,
+    pub device: DeviceIdentity,
+    pub created: u64,
+    pub media: Vec<RestoredFile>,
+    pub screens: Vec<RestoredFile>,
+    pub cards: Vec<RestoredCard>,
+    /// Missing when the archive holds no configuration
+    pub config: Option<ConfigChanges>,
+    pub warnings: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Change {
+    Add,
+    Replace,
+    Unchanged,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RestoredFile {
+    pub name: String,
+    pub size: u64,
+    pub change: Change,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RestoredCard {
+    pub card_id: String,
+    pub media: String,
+    pub actions: Vec<CardAction>,
+    pub change: Change,
+    /// False when the media is neither in the archive nor already on the device
+    pub media_available: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ConfigChanges {
+    /// Settings that differ from the running device
+    pub changed: Vec<&'static str>,
+    /// The part of `changed` that only applies after a restart
+    pub restart_required: Vec<&'static str>,
+    pub users: Vec<String>,
+}
+
+/// An archive that has been read completely and checked, its files waiting in the staging directory.
+pub struct Restore {
+    report: RestoreReport,
+    config: Option<DeviceConfiguration>,
+    staged: Vec<StagedFile>,
+    staging: Option<StagingDir>,
+}
+
+struct StagedFile {
+    name: String,
+    path: PathBuf,
+    change: Change,
+}
+
+/// Removes the staging directory and whatever was not moved out of it.
+struct StagingDir(PathBuf);
+
+impl Drop for StagingDir {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_dir_all(&self.0) {
+            warn!("Failed to remove restore staging dir {}: {:?}", self.0.display(), err);
+        }
+    }
+}
+
+/// Writes a tar archive of the media library, custom screens, card pairings and `Config.yaml` into `writer`.
+///
+/// Media is already compressed so the archive is not, which keeps exports cheap on a pi.
+pub fn export<W: Write>(writer: W, device: &DeviceFiles, identity: DeviceIdentity) -> Result<W, BackupError> {
+    let media = library_files(device.files_dir)?;
+    let screens = CUSTOM_SCREENS.iter()
+        .filter(|name| **name != IDLE_SCREEN || is_custom_idle(device.files_dir))
+        .map(|name| name.to_string())
+        .collect::<Vec<_>>();
+    let cards = device.cards.list()?
+        .into_iter()
+        .filter(|card| !card.actions.is_empty())
+        .map(|card| CardMapping { card_id: card.card_id, media: card.media, actions: card.actions })
+        .collect::<Vec<_>>();
+
+    let manifest = Manifest {
+        format: FORMAT_VERSION,
+        created: now(),
+        device: identity,
+        media: media.clone(),
+        screens: screens.clone(),
+        cards: cards.len(),
+    };
+    info!("Exporting backup with {} media files, {} screens and {} cards", media.len(), screens.len(), cards.len());
+
+    let mut builder = Builder::new(writer);
+    append_bytes(&mut builder, MANIFEST, &to_json(&manifest)?)?;
+    if device.config_path.is_file() {
+        builder.append_path_with_name(device.config_path, CONFIG)?;
+    }
+    append_bytes(&mut builder, CARDS, &to_json(&cards)?)?;
+    for name in &media {
+        builder.append_path_with_name(device.files_dir.join(name), format!("{}{}", MEDIA_DIR, name))?;
+    }
+    for name in &screens {
+        builder.append_path_with_name(device.files_dir.join(name), format!("{}{}", SCREENS_DIR, name))?;
+    }
+    Ok(builder.into_inner()?)
+}
+
+/// How an archive is restored.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RestoreOptions {
+    /// Only report what would change
+    pub dry_run: bool,
+    /// Take over the `device_uuid` of the backed up device, for replacing it. Otherwise the device
+    /// keeps its own so restoring a backup onto a second device doesn't leave two with the same identity.
+    pub take_identity: bool,
+}
+
+/// Reads a whole archive, comparing it to the device and staging its files when it is not a dry run.
+/// Nothing on the device changes until [`Restore::apply`], so a broken archive is rejected as a whole.
+pub fn read_archive<R: Read>(reader: R, device: &DeviceFiles, running: &DeviceConfiguration, options: RestoreOptions) -> Result<Restore, BackupError> {
+    let RestoreOptions { dry_run, take_identity } = options;
+    let staging = if dry_run {
+        None
+    } else {
+        let dir = staging_dir(device.files_dir).join(format!("{}{}", RESTORE_DIR_PREFIX, Uuid::new_v4()));
+        fs::create_dir_all(&dir)?;
+        Some(StagingDir(dir))
+    };
+
+    let mut manifest = None;
+    let mut config = None;
+    let mut mappings = Vec::new();
+    let mut media = Vec::new();
+    let mut screens = Vec::new();
+    let mut staged = Vec::new();
+    let mut warnings = Vec::new();
+
+    let mut archive = Archive::new(reader);
+    for entry in archive.entries().map_err(invalid)? {
+        let mut entry = entry.map_err(invalid)?;
+        let path = String::from_utf8_lossy(&entry.path_bytes()).into_owned();
+        match entry.header().entry_type() {
+            EntryType::Regular | EntryType::Continuous => {}
+            EntryType::Directory => continue,
+            _ => {
+                warnings.push(format!("Skipped {}, only plain files are restored", path));
+                continue;
+            }
+        }
+
+        if path == MANIFEST {
+            manifest = Some(read_json::<Manifest, _>(&mut entry, &path)?);
+        } else if path == CONFIG {
+            let yaml = read_metadata(&mut entry, &path)?;
+            config = Some(serde_yaml::from_slice::<DeviceConfiguration>(&yaml)
+                .map_err(|err| InvalidArchive(format!("{} is not a valid configuration: {}", path, err)))?);
+        } else if path == CARDS {
+            mappings = read_json::<Vec<CardMapping>, _>(&mut entry, &path)?;
+        } else if let Some((name, is_screen)) = path.strip_prefix(MEDIA_DIR).map(|name| (name, false))
+            .or_else(|| path.strip_prefix(SCREENS_DIR).map(|name| (name, true))) {
+            if is_screen && !CUSTOM_SCREENS.contains(&name) {
+                warnings.push(format!("Skipped {}, it is not a screen that can be replaced", path));
+                continue;
+            }
+            let destination = match resolve_new_media_path(device.files_dir, name) {
+                Ok(destination) => destination,
+                Err(err) => {
+                    warnings.push(format!("Skipped {}: {}", path, err));
+                    continue;
+                }
+            };
+
+            let staged_path = staging.as_ref().map(|dir| dir.0.join(format!("{}.part", Uuid::new_v4())));
+            let (size, hash) = match &staged_path {
+                Some(staged_path) => copy_hashed(&mut entry, File::create(staged_path)?)?,
+                None => copy_hashed(&mut entry, io::sink())?,
+            };
+            let change = if !destination.is_file() {
+                Change::Add
+            } else if fs::metadata(&destination)?.len() == size && sha256_file(&destination)? == hash {
+                Change::Unchanged
+            } else {
+                Change::Replace
+            };
+
+            let file = RestoredFile { name: name.to_owned(), size, change };
+            if is_screen { screens.push(file) } else { media.push(file) }
+            if let Some(path) = staged_path {
+                staged.push(StagedFile { name: name.to_owned(), path, change });
+            }
+        } else {
+            warnings.push(format!("Skipped {}, it is not part of a device backup", path));
+        }
+    }
+
+    let manifest = manifest.ok_or(InvalidArchive(format!("There is no {}, this is not a device backup", MANIFEST)))?;
+    if manifest.format > FORMAT_VERSION {
+        return Err(InvalidArchive(format!("Backup format {} is newer than this device understands ({})", manifest.format, FORMAT_VERSION)));
+    }
+    for name in manifest.media.iter().filter(|name| !media.iter().any(|file| &file.name == *name)) {
+        warnings.push(format!("{} is listed in the manifest but missing from the archive", name));
+    }
+
+    let library = device.files_dir.canonicalize()?;
+    let archived = media.iter().map(|file| file.name.as_str()).collect::<HashSet<_>>();
+    let mut cards = Vec::new();
+    for mapping in mappings {
+        let actions = mapping.actions();
+        let media = actions.iter()
+            .filter_map(|action| match action {
+                CardAction::PlayMedia { media } => Some(media.as_str()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        if mapping.card_id.is_empty() || media.iter().any(|name| resolve_new_media_path(device.files_dir, name).is_err()) {
+            warnings.push(format!("Skipped pairing of card '{}' with '{}'", mapping.card_id, mapping.media));
+            continue;
+        }
+        let change = match device.cards.actions(&mapping.card_id)? {
+            None => Change::Add,
+            Some(current) if current == actions => Change::Unchanged,
+            Some(_) => Change::Replace,
+        };
+        let mut media_available = true;
+        for name in media.iter().filter(|name| !archived.contains(**name) && !library.join(name).is_file()) {
+            warnings.push(format!("Card {} is paired with {} which is not in the archive or on the device", mapping.card_id, name));
+            media_available = false;
+        }
+        cards.push(RestoredCard { card_id: mapping.card_id, media: mapping.media, actions, change, media_available });
+    }
+
+    if let Some(config) = config.as_mut() {
+        if !take_identity {
+            config.device_uuid = running.device_uuid.clone();
+        }
+        // The web interface would not come up after a restart without them
+        if config.tls.as_ref().is_some_and(|tls| !tls.certificate.is_file() || !tls.private_key.is_file()) {
+            let tls = config.tls.take().unwrap();
+            warnings.push(format!("Restored without https, {} and {} are not on the device",
+                                  tls.certificate.display(), tls.private_key.display()));
+        }
+    }
+
+    let config_changes = config.as_ref().map(|config: &DeviceConfiguration| {
+        let changed = Settings::from_config(config).changed_from(&Settings::from_config(running));
+        ConfigChanges {
+            restart_required: changed.iter().filter(|name| !LIVE_SETTINGS.contains(name)).copied().collect(),
+            changed,
+            users: config.users.iter().map(|user| user.username.clone()).collect(),
+        }
+    });
+
+    let report = RestoreReport {
+        dry_run,
+        device: manifest.device,
+        created: manifest.created,
+        media,
+        screens,
+        cards,
+        config: config_changes,
+        warnings,
+    };
+    Ok(Restore { report, config, staged, staging })
+}
+
+impl Restore {
+    /// The configuration in the archive, saving and applying it is up to the caller.
+    pub fn config(&self) -> Option<&DeviceConfiguration> {
+        self.config.as_ref()
+    }
+
+    /// Moves the staged files into the library and pairs the cards, a dry run changes nothing.
+    pub fn apply(mut self, device: &DeviceFiles) -> Result<RestoreReport, BackupError> {
+        if self.staging.is_none() {
+            return Ok(self.report);
+        }
+
+        for file in self.staged.iter().filter(|file| file.change != Change::Unchanged) {
+            let destination = resolve_new_media_path(device.files_dir, &file.name).map_err(|err| InvalidArchive(err.to_string()))?;
+            fs::rename(&file.path, destination)?;
+            info!("Restored {}", file.name);
+        }
+        if self.report.screens.iter().any(|screen| screen.name == IDLE_SCREEN) {
+            mark_idle_custom(device.files_dir)?;
+        }
+
+        for card in self.report.cards.iter().filter(|card| card.change != Change::Unchanged) {
+            device.cards.set_actions(&card.card_id, &card.actions)?;
+        }
+        info!("Restored backup of {} with {} media files and {} cards",
+            self.report.device.device_uuid, self.report.media.len(), self.report.cards.len());
+
+        self.staging.take();
+        Ok(self.report)
+    }
+}
+
+/// Plain files in the library that are not screens, sorted by name.
+fn library_files(files_dir: &Path) -> io::Result<Vec<String>> {
+    let mut names = Vec::new();
+    for entry in fs::read_dir(files_dir)?.filter_map(|entry| entry.ok()) {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with('.') || is_generated_screen(&name) || CUSTOM_SCREENS.contains(&name.as_str()) {
+            continue;
+        }
+        if entry.metadata().is_ok_and(|metadata| metadata.is_file()) {
+            names.push(name);
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+fn append_bytes<W: Write>(builder: &mut Builder<W>, path: &str, data: &[u8]) -> io::Result<()> {
+    let mut header = Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(now());
+    builder.append_data(&mut header, path, data)
+}
+
+fn to_json<T: Serialize>(value: &T) -> io::Result<Vec<u8>> {
+    serde_json::to_vec_pretty(value).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+fn read_metadata<R: Read>(entry: &mut R, path: &str) -> Result<Vec<u8>, BackupError> {
+    let mut data = Vec::new();
+    entry.take(MAX_METADATA_SIZE + 1).read_to_end(&mut data).map_err(invalid)?;
+    if data.len() as u64 > MAX_METADATA_SIZE {
+        return Err(InvalidArchive(format!("{} is larger than {} bytes", path, MAX_METADATA_SIZE)));
+    }
+    Ok(data)
+}
+
+fn read_json<T: DeserializeOwned, R: Read>(entry: &mut R, path: &str) -> Result<T, BackupError> {
+    serde_json::from_slice(&read_metadata(entry, path)?)
+        .map_err(|err| InvalidArchive(format!("{} is not valid: {}", path, err)))
+}
+
+/// Copies `reader` into `writer` returning the size and sha256 of what was copied.
+/// Failing to read is blamed on the archive, failing to write on the device.
+fn copy_hashed<R: Read, W: Write>(reader: &mut R, mut writer: W) -> Result<(u64, String), BackupError> {
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 64 * 1024];
+    let mut size = 0;
+    loop {
+        let read = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(invalid(err)),
+        };
+        hasher.update(&buffer[..read]);
+        writer.write_all(&buffer[..read])?;
+        size += read as u64;
+    }
+    writer.flush()?;
+    Ok((size, hex::encode(hasher.finalize())))
+}
+
+fn invalid(error: io::Error) -> BackupError {
+    InvalidArchive(error.to_string())
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+#[derive(Debug)]
+pub enum BackupError {
+    InvalidArchive(String),
+    IoError(io::Error),
+    DatabaseError(sled::Error),
+}
+
+impl Display for BackupError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            InvalidArchive(reason) => {write!(f, "Not a valid backup: {}", reason)}
+            IoError(error) => {write!(f, "Io operation failed: {}", error)}
+            DatabaseError(error) => {write!(f, "Database operation failed: {}", error)}
+        }
+    }
+}
+
+impl From<io::Error> for BackupError {
+    fn from(error: io::Error) -> Self {
+        IoError(error)
+    }
+}
+
+impl From<sled::Error> for BackupError {
+    fn from(error: sled::Error) -> Self {
+        DatabaseError(error)
+    }
+}
+
+impl Error for BackupError {}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+    use crate::config::setup::TlsConfiguration;
+    use super::*;
+
+    struct TestDevice {
+        files_dir: PathBuf,
+        config_path: PathBuf,
+        cards: CardStore,
+        _dir: TempDir,
+    }
+
+    impl TestDevice {
+        /// An empty device in a temporary directory with a saved configuration
+        fn new() -> TestDevice {
+            let temp = TempDir::new().unwrap();
+            let dir = temp.path();
+            let files_dir = dir.join("files");
+            fs::create_dir_all(&files_dir).unwrap();
+            let config_path = dir.join("config/Config.yaml");
+            DeviceConfiguration::new().save(config_path.clone()).unwrap();
+            let cards = CardStore::open(&dir.join("data/card_database"), &files_dir).unwrap();
+            TestDevice { files_dir, config_path, cards, _dir: temp }
+        }
+
+        fn files(&self) -> DeviceFiles<'_> {
+            DeviceFiles { files_dir: &self.files_dir, config_path: &self.config_path, cards: &self.cards }
+        }
+
+        fn restore(&self, archive: &[u8], dry_run: bool) -> RestoreReport {
+            self.restore_with(archive, RestoreOptions { dry_run, take_identity: false }).0
+        }
+
+        /// Also returns the configuration that was restored
+        fn restore_with(&self, archive: &[u8], options: RestoreOptions) -> (RestoreReport, Option<DeviceConfiguration>) {
+            let running = DeviceConfiguration::read(&self.config_path).unwrap();
+            let restore = read_archive(archive, &self.files(), &running, options).unwrap();
+            let config = restore.config().cloned();
+            (restore.apply(&self.files()).unwrap(), config)
+        }
+    }
+
+    fn identity() -> DeviceIdentity {
+        DeviceIdentity { device_uuid: "source".to_owned(), name: "Study".to_owned(), version: "test".to_owned() }
+    }
+
+    #[test]
+    fn restores_media_screens_and_pairings_into_another_library() {
+        let source = TestDevice::new();
+        fs::write(source.files_dir.join("clue.mp4"), b"clue").unwrap();
+        fs::write(source.files_dir.join(IDLE_SCREEN), b"custom idle").unwrap();
+        fs::write(source.files_dir.join("startup.png"), b"generated").unwrap();
+        source.cards.set_actions("a", &[CardAction::PlayMedia { media: "clue.mp4".to_owned() }, CardAction::ReturnToIdle]).unwrap();
+        let archive = export(Vec::new(), &source.files(), identity()).unwrap();
+
+        let target = TestDevice::new();
+        let report = target.restore(&archive, true);
+        assert_eq!(report.media.iter().map(|file| (file.name.as_str(), file.change)).collect::<Vec<_>>(), vec![("clue.mp4", Change::Add)]);
+        assert_eq!(report.screens[0].name, IDLE_SCREEN);
+        assert!(report.cards[0].media_available && report.cards[0].change == Change::Add);
+        assert!(report.config.unwrap().changed.is_empty());
+        assert!(fs::read_dir(&target.files_dir).unwrap().next().is_none());
+        assert!(target.cards.list().unwrap().is_empty());
+
+        target.restore(&archive, false);
+        assert_eq!(fs::read(target.files_dir.join("clue.mp4")).unwrap(), b"clue");
+        assert!(is_custom_idle(&target.files_dir));
+        assert!(!target.files_dir.join("startup.png").exists());
+        assert_eq!(target.cards.actions("a").unwrap().unwrap()[1], CardAction::ReturnToIdle);
+        assert!(fs::read_dir(staging_dir(&target.files_dir)).unwrap().next().is_none());
+
+        let again = target.restore(&archive, true);
+        assert!(again.media.iter().chain(&again.screens).all(|file| file.change == Change::Unchanged));
+        assert_eq!(again.cards[0].change, Change::Unchanged);
+    }
+
+    #[test]
+    fn rejects_archives_without_a_manifest() {
+        let mut builder = Builder::new(Vec::new());
+        append_bytes(&mut builder, "media/clue.mp4", b"clue").unwrap();
+        let archive = builder.into_inner().unwrap();
+
+        let target = TestDevice::new();
+        let result = read_archive(&archive[..], &target.files(), &DeviceConfiguration::new(), RestoreOptions::default());
+        assert!(matches!(result, Err(InvalidArchive(_))));
+        assert!(fs::read_dir(staging_dir(&target.files_dir)).unwrap().next().is_none());
+    }
+
+    #[test]
+    fn keeps_the_device_identity_unless_taking_it_over() {
+        let source = TestDevice::new();
+        let archive = export(Vec::new(), &source.files(), identity()).unwrap();
+        let source_uuid = DeviceConfiguration::read(&source.config_path).unwrap().device_uuid;
+
+        let target = TestDevice::new();
+        let target_uuid = DeviceConfiguration::read(&target.config_path).unwrap().device_uuid;
+        let (_, config) = target.restore_with(&archive, RestoreOptions::default());
+        assert_eq!(config.unwrap().device_uuid, target_uuid);
+
+        let (report, config) = target.restore_with(&archive, RestoreOptions { dry_run: true, take_identity: true });
+        assert_eq!(report.config.unwrap().changed, vec!["device_uuid"]);
+        assert_eq!(config.unwrap().device_uuid, source_uuid);
+    }
+
+    #[test]
+    fn drops_https_when_the_certificate_is_missing() {
+        let source = TestDevice::new();
+        let mut config = DeviceConfiguration::read(&source.config_path).unwrap();
+        config.tls = Some(TlsConfiguration { certificate: "/missing/cert.pem".into(), private_key: "/missing/key.pem".into() });
+        config.save(source.config_path.clone()).unwrap();
+        let archive = export(Vec::new(), &source.files(), identity()).unwrap();
+
+        let target = TestDevice::new();
+        let (report, config) = target.restore_with(&archive, RestoreOptions::default());
+        assert!(config.unwrap().tls.is_none());
+        assert!(report.warnings.iter().any(|warning| warning.contains("/missing/cert.pem")));
+    }
+}
@@ -0,0 +1,138 @@
+use std::fmt::Formatter;
+use std::{fmt, fs};
+use std::io;
+use std::path::{Path, PathBuf};
+use log::error;
+
+use serde::{Deserialize, Serialize};
+use crate::config::setup::{default_allowed_extensions, default_http_workers, default_listen_address, default_max_upload_size, TlsConfiguration};
+use crate::config::users::{initial_users, users_with_default_passwords, UserAccount};
+
+/// Configuration of the fleet controller, read from `config/Controller.yaml`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ControllerConfiguration {
+    #[serde(default = "default_listen_address")]
+    pub listen_address: String,
+    #[serde(default = "default_controller_port")]
+    pub port: u16,
+    #[serde(default)]
+    pub tls: Option<TlsConfiguration>,
+    #[serde(default = "default_http_workers")]
+    pub http_workers: usize,
+    /// Find devices over mDNS as well as using `devices`
+    #[serde(default = "default_discover")]
+    pub discover: bool,
+    /// Seconds to listen for mDNS answers on every refresh
+    #[serde(default = "default_discovery_timeout")]
+    pub discovery_timeout: u64,
+    /// Seconds between refreshes of the dashboard
+    #[serde(default = "default_refresh_interval")]
+    pub refresh_interval: u64,
+    /// Base urls of devices that can't be discovered, e.g. `http://10.0.0.12:8000`
+    #[serde(default)]
+    pub devices: Vec<String>,
+    /// Admin account the controller logs into every device with,
+    /// devices can only be watched and not acted on until it is set
+    #[serde(default)]
+    pub device_credentials: Option<DeviceCredentials>,
+    #[serde(default = "default_max_upload_size")]
+    pub max_upload_size: u64,
+    #[serde(default = "default_allowed_extensions")]
+    pub allowed_extensions: Vec<String>,
+    /// Accounts for the controller's own web interface
+    #[serde(default)]
+    pub users: Vec<UserAccount>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct DeviceCredentials {
+    pub username: String,
+    pub password: String,
+}
+
+/// Leaves the password out so the configuration can be logged.
+impl fmt::Debug for DeviceCredentials {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("DeviceCredentials")
+            .field("username", &self.username)
+            .finish_non_exhaustive()
+    }
+}
+
+fn default_controller_port() -> u16 {
+    8080
+}
+
+fn default_discover() -> bool {
+    true
+}
+
+fn default_discovery_timeout() -> u64 {
+    3
+}
+
+fn default_refresh_interval() -> u64 {
+    30
+}
+
+impl ControllerConfiguration {
+    pub fn new() -> ControllerConfiguration {
+        ControllerConfiguration {
+            listen_address: default_listen_address(),
+            port: default_controller_port(),
+            tls: None,
+            http_workers: default_http_workers(),
+            discover: default_discover(),
+            discovery_timeout: default_discovery_timeout(),
+            refresh_interval: default_refresh_interval(),
+            devices: Vec::new(),
+            device_credentials: None,
+            max_upload_size: default_max_upload_size(),
+            allowed_extensions: default_allowed_extensions(),
+            users: Vec::new(),
+        }
+    }
+
+    /// Reads the configuration, creating it with the defaults when it doesn't exist yet.
+    pub fn load(path: PathBuf) -> ControllerConfiguration {
+        let mut config = if path.is_file() {
+            ControllerConfiguration::read(&path).expect("Failed to parse controller config file")
+        } else {
+            ControllerConfiguration::new()
+        };
+
+        if config.users.is_empty() {
+            println!("No users configured, creating accounts with random passwords");
+            config.users = initial_users();
+            config.save(path.clone()).expect("Unable to write controller config");
+        }
+        for username in users_with_default_passwords(&config.users) {
+            println!("Account {} still uses its username as password, change it", username);
+        }
+        if config.device_credentials.is_none() {
+            println!("No device_credentials configured in {}, devices can't be acted on until they are", path.display());
+        }
+        config
+    }
+
+    /// Reads the configuration as it is saved.
+    pub fn read(path: &Path) -> io::Result<ControllerConfiguration> {
+        let contents = fs::read_to_string(path)?;
+        serde_yaml::from_str(&contents).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    pub fn bind_address(&self) -> String {
+        format!("{}:{}", self.listen_address, self.port)
+    }
+
+    pub fn save(&self, path: PathBuf) -> io::Result<()> {
+        if let Some(parent_dir) = path.parent() {
+            fs::create_dir_all(parent_dir).inspect_err(|err| error!("Failed to create config dir: {:?}", err))?;
+        }
+
+        let serialized_yaml = serde_yaml::to_string(self).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        fs::write(&path, serialized_yaml)?;
+        println!("Config file saved: {}", path.display());
+        Ok(())
+    }
+}
@@ -1 +1,4 @@
-pub mod setup;
\ No newline at end of file
+pub mod setup;
+pub mod users;
+pub mod controller;
+pub mod settings;
@@ -0,0 +1,195 @@
+use std::collections::HashSet;
+use std::net::IpAddr;
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use crate::config::setup::{DeviceConfiguration, DuplicatePolicy};
+
+/// Longest name that still fits in an mDNS TXT record next to the other fields.
+const MAX_DEVICE_NAME_LENGTH: usize = 63;
+const MAX_CLUE_TIMEOUT: u64 = 60 * 60;
+const MAX_RFID_RETRYS: u32 = 100;
+const MAX_HTTP_WORKERS: usize = 64;
+const MAX_UPLOAD_SESSION_TIMEOUT: u64 = 30 * 24;
+
+/// Settings that are picked up by the running device as soon as they are saved,
+/// everything else is only read at startup.
+pub const LIVE_SETTINGS: [&str; 4] = ["clue_timeout", "max_upload_size", "allowed_extensions", "duplicate_uploads"];
+
+/// The part of `DeviceConfiguration` that can be edited from the web interface.
+/// Accounts and tls certificates are still only configured in `config/Config.yaml`.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    pub device_uuid: String,
+    pub device_name: String,
+    pub listen_address: String,
+    pub port: u16,
+    /// Seconds the reader ignores cards after a clue finished
+    pub clue_timeout: u64,
+    pub rfid_retrys: u32,
+    pub http_workers: usize,
+    pub max_upload_size: u64,
+    pub allowed_extensions: Vec<String>,
+    pub duplicate_uploads: DuplicatePolicy,
+    /// Hours an unfinished resumable upload is kept
+    pub upload_session_timeout: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FieldError {
+    pub field: &'static str,
+    pub message: String,
+}
+
+impl Settings {
+    pub fn from_config(config: &DeviceConfiguration) -> Settings {
+        Settings {
+            device_uuid: config.device_uuid.clone(),
+            device_name: config.device_name.clone(),
+            listen_address: config.listen_address.clone(),
+            port: config.port,
+            clue_timeout: config.clue_timeout,
+            rfid_retrys: config.rfid_retrys,
+            http_workers: config.http_workers,
+            max_upload_size: config.max_upload_size,
+            allowed_extensions: config.allowed_extensions.clone(),
+            duplicate_uploads: config.duplicate_uploads,
+            upload_session_timeout: config.upload_session_timeout,
+        }
+    }
+
+    /// Checks every field, returning the settings with names trimmed and extensions normalized
+    /// or every problem found so they can all be shown at once.
+    pub fn validate(mut self) -> Result<Settings, Vec<FieldError>> {
+        let mut errors = Vec::new();
+        let mut error = |field: &'static str, message: &str| errors.push(FieldError { field, message: message.to_owned() });
+
+        self.device_uuid = self.device_uuid.trim().to_lowercase();
+        if Uuid::parse_str(&self.device_uuid).is_err() {
+            error("device_uuid", "Must be a uuid");
+        }
+
+        self.device_name = self.device_name.trim().to_owned();
+        if self.device_name.is_empty() || self.device_name.len() > MAX_DEVICE_NAME_LENGTH {
+            error("device_name", &format!("Must be between 1 and {} bytes long", MAX_DEVICE_NAME_LENGTH));
+        } else if self.device_name.chars().any(char::is_control) {
+            error("device_name", "Must not contain control characters");
+        }
+
+        self.listen_address = self.listen_address.trim().to_owned();
+        if self.listen_address.parse::<IpAddr>().is_err() {
+            error("listen_address", "Must be an ip address, 0.0.0.0 listens on every interface");
+        }
+        if self.port == 0 {
+            error("port", "Must be between 1 and 65535");
+        }
+        if self.clue_timeout > MAX_CLUE_TIMEOUT {
+            error("clue_timeout", &format!("Must be at most {} seconds", MAX_CLUE_TIMEOUT));
+        }
+        if !(1..=MAX_RFID_RETRYS).contains(&self.rfid_retrys) {
+            error("rfid_retrys", &format!("Must be between 1 and {}", MAX_RFID_RETRYS));
+        }
+        if !(1..=MAX_HTTP_WORKERS).contains(&self.http_workers) {
+            error("http_workers", &format!("Must be between 1 and {}", MAX_HTTP_WORKERS));
+        }
+        if self.max_upload_size == 0 {
+            error("max_upload_size", "Must be more than 0 bytes");
+        }
+
+        let mut seen = HashSet::new();
+        self.allowed_extensions = self.allowed_extensions.iter()
+            .map(|ext| ext.trim().trim_start_matches('.').to_lowercase())
+            .filter(|ext| seen.insert(ext.clone()))
+            .collect();
+        if self.allowed_extensions.is_empty() {
+            error("allowed_extensions", "At least one extension has to be allowed");
+        } else if self.allowed_extensions.iter().any(|ext| ext.is_empty() || !ext.chars().all(|c| c.is_ascii_alphanumeric())) {
+            error("allowed_extensions", "Extensions may only contain letters and digits");
+        }
+
+        if !(1..=MAX_UPLOAD_SESSION_TIMEOUT).contains(&self.upload_session_timeout) {
+            error("upload_session_timeout", &format!("Must be between 1 and {} hours", MAX_UPLOAD_SESSION_TIMEOUT));
+        }
+
+        if errors.is_empty() { Ok(self) } else { Err(errors) }
+    }
+
+    pub fn apply_to(&self, config: &mut DeviceConfiguration) {
+        config.device_uuid = self.device_uuid.clone();
+        config.device_name = self.device_name.clone();
+        config.listen_address = self.listen_address.clone();
+        config.port = self.port;
+        config.clue_timeout = self.clue_timeout;
+        config.rfid_retrys = self.rfid_retrys;
+        config.http_workers = self.http_workers;
+        config.max_upload_size = self.max_upload_size;
+        config.allowed_extensions = self.allowed_extensions.clone();
+        config.duplicate_uploads = self.duplicate_uploads;
+        config.upload_session_timeout = self.upload_session_timeout;
+    }
+
+    /// Only the settings that can change while the device is running.
+    pub fn apply_live_to(&self, config: &mut DeviceConfiguration) {
+        config.clue_timeout = self.clue_timeout;
+        config.max_upload_size = self.max_upload_size;
+        config.allowed_extensions = self.allowed_extensions.clone();
+        config.duplicate_uploads = self.duplicate_uploads;
+    }
+
+    /// Names of the settings that differ from `other`.
+    pub fn changed_from(&self, other: &Settings) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+        let mut compare = |name: &'static str, differs: bool| if differs { changed.push(name) };
+        compare("device_uuid", self.device_uuid != other.device_uuid);
+        compare("device_name", self.device_name != other.device_name);
+        compare("listen_address", self.listen_address != other.listen_address);
+        compare("port", self.port != other.port);
+        compare("clue_timeout", self.clue_timeout != other.clue_timeout);
+        compare("rfid_retrys", self.rfid_retrys != other.rfid_retrys);
+        compare("http_workers", self.http_workers != other.http_workers);
+        compare("max_upload_size", self.max_upload_size != other.max_upload_size);
+        compare("allowed_extensions", self.allowed_extensions != other.allowed_extensions);
+        compare("duplicate_uploads", self.duplicate_uploads != other.duplicate_uploads);
+        compare("upload_session_timeout", self.upload_session_timeout != other.upload_session_timeout);
+        changed
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validation_normalizes_and_reports_every_problem() {
+        let mut settings = Settings::from_config(&DeviceConfiguration::new());
+        settings.device_name = "  Study  ".to_owned();
+        settings.allowed_extensions = vec![".MP4".to_owned(), "mp4".to_owned(), "png".to_owned()];
+        let valid = settings.clone().validate().unwrap();
+        assert_eq!(valid.device_name, "Study");
+        assert_eq!(valid.allowed_extensions, vec!["mp4", "png"]);
+
+        settings.device_uuid = "not a uuid".to_owned();
+        settings.listen_address = "localhost".to_owned();
+        settings.rfid_retrys = 0;
+        settings.allowed_extensions = vec!["m p4".to_owned()];
+        let fields = settings.validate().unwrap_err().into_iter().map(|error| error.field).collect::<Vec<_>>();
+        assert_eq!(fields, vec!["device_uuid", "listen_address", "rfid_retrys", "allowed_extensions"]);
+    }
+
+    #[test]
+    fn only_live_settings_are_applied_while_running() {
+        let mut running = DeviceConfiguration::new();
+        let mut settings = Settings::from_config(&running);
+        settings.port = 9000;
+        settings.clue_timeout = 30;
+        settings.max_upload_size = 1024;
+
+        settings.apply_live_to(&mut running);
+        let pending = settings.changed_from(&Settings::from_config(&running));
+        assert_eq!(pending, vec!["port"]);
+        assert_eq!(running.clue_timeout, 30);
+        assert!(LIVE_SETTINGS.iter().all(|setting| !pending.contains(setting)));
+    }
+}
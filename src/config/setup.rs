@@ -1,17 +1,212 @@
-use std::fs;
+use std::fmt::Formatter;
+use std::{fmt, fs};
 use std::fs::{File, OpenOptions};
+use std::io;
 use std::io::{Read, Write};
-use std::path::{PathBuf};
-use log::error;
+use std::path::{Path, PathBuf};
 
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
+use crate::config::users::{initial_users, users_with_default_passwords, UserAccount};
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct DeviceConfiguration {
     pub device_uuid: String,
+    /// Friendly name shown when devices are discovered on the network
+    #[serde(default = "default_device_name")]
+    pub device_name: String,
+    #[serde(default = "default_listen_address")]
+    pub listen_address: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    #[serde(default)]
+    pub tls: Option<TlsConfiguration>,
     pub clue_timeout: u64,
-    pub rfid_retrys: u32
+    pub rfid_retrys: u32,
+    #[serde(default = "default_http_workers")]
+    pub http_workers: usize,
+    #[serde(default = "default_max_upload_size")]
+    pub max_upload_size: u64,
+    #[serde(default = "default_allowed_extensions")]
+    pub allowed_extensions: Vec<String>,
+    #[serde(default)]
+    pub duplicate_uploads: DuplicatePolicy,
+    #[serde(default = "default_upload_session_timeout")]
+    pub upload_session_timeout: u64,
+    #[serde(default)]
+    pub webhooks: Vec<WebhookConfiguration>,
+    #[serde(default)]
+    pub mqtt: Option<MqttConfiguration>,
+    #[serde(default)]
+    pub osc: Option<OscConfiguration>,
+    #[serde(default)]
+    pub buttons: Vec<ButtonConfiguration>,
+    #[serde(default)]
+    pub outputs: Vec<OutputConfiguration>,
+    #[serde(default)]
+    pub users: Vec<UserAccount>
+}
+
+/// Paths to a PEM certificate chain and private key, relative to the working directory.
+/// When set the web interface is only served over https.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TlsConfiguration {
+    pub certificate: PathBuf,
+    pub private_key: PathBuf,
+}
+
+/// A url device events are posted to as json. Only the events named in `events` are sent,
+/// every event is when it is left empty.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct WebhookConfiguration {
+    pub url: String,
+    #[serde(default)]
+    pub events: Vec<String>,
+}
+
+/// A broker the device publishes its scans, player state and health to and takes commands from.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct MqttConfiguration {
+    pub host: String,
+    #[serde(default = "default_mqtt_port")]
+    pub port: u16,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    /// Topics are `<topic_prefix>/<device_uuid>/...`
+    #[serde(default = "default_topic_prefix")]
+    pub topic_prefix: String,
+    /// Seconds between health reports
+    #[serde(default = "default_health_interval")]
+    pub health_interval: u64,
+}
+
+/// Leaves the password out so the configuration can be logged.
+impl fmt::Debug for MqttConfiguration {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("MqttConfiguration")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| "<redacted>"))
+            .field("topic_prefix", &self.topic_prefix)
+            .field("health_interval", &self.health_interval)
+            .finish()
+    }
+}
+
+/// A udp port show control software sends osc commands to.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct OscConfiguration {
+    #[serde(default = "default_osc_port")]
+    pub port: u16,
+    /// Commands are `<address_prefix>/play`, `<address_prefix>/idle` and `<address_prefix>/pair`
+    #[serde(default = "default_address_prefix")]
+    pub address_prefix: String,
+    /// `host:port` card scans are sent to as osc, nothing is sent when it is left out
+    #[serde(default)]
+    pub send_scans_to: Option<String>,
+}
+
+/// A gpio input, like an arcade button or reed switch, that runs a command every time it is pressed.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ButtonConfiguration {
+    /// Gpio number, not the header pin number
+    pub pin: u64,
+    /// `play`, `idle` or `pair`
+    #[serde(default = "default_button_command")]
+    pub command: String,
+    #[serde(default)]
+    pub media: Option<String>,
+    /// Pressed pulls the pin low, like a button to ground with a pull up
+    #[serde(default = "default_active_low")]
+    pub active_low: bool,
+    /// Milliseconds the pin has to hold its level before a press counts
+    #[serde(default = "default_debounce")]
+    pub debounce: u64,
+}
+
+/// A gpio output driving a relay, like a maglock or prop light, that card scans can switch.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct OutputConfiguration {
+    /// What cards and the web interface call the output
+    pub name: String,
+    /// Gpio number, not the header pin number
+    pub pin: u64,
+    /// The relay switches on when the pin is pulled low
+    #[serde(default)]
+    pub active_low: bool,
+}
+
+/// What to do when an uploaded file has the same name as one already in the library.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize, Serialize)]
+pub enum DuplicatePolicy {
+    Reject,
+    // Uploading a new idle.png replaces the idle screen so this has to stay the default
+    #[default]
+    Overwrite,
+    Rename,
+}
+
+fn default_device_name() -> String {
+    "Clue Device".to_owned()
+}
+
+pub fn default_listen_address() -> String {
+    "0.0.0.0".to_owned()
+}
+
+fn default_port() -> u16 {
+    8000
+}
+
+pub fn default_http_workers() -> usize {
+    4
+}
+
+pub fn default_max_upload_size() -> u64 {
+    2 * 1024 * 1024 * 1024
+}
+
+fn default_upload_session_timeout() -> u64 {
+    24
+}
+
+fn default_mqtt_port() -> u16 {
+    1883
+}
+
+fn default_topic_prefix() -> String {
+    "clue".to_owned()
+}
+
+fn default_health_interval() -> u64 {
+    30
+}
+
+fn default_osc_port() -> u16 {
+    53001
+}
+
+fn default_address_prefix() -> String {
+    "/clue".to_owned()
+}
+
+fn default_button_command() -> String {
+    "play".to_owned()
+}
+
+fn default_active_low() -> bool {
+    true
+}
+
+fn default_debounce() -> u64 {
+    50
+}
+
+pub fn default_allowed_extensions() -> Vec<String> {
+    ["mp4", "jpeg", "jpg", "png"].iter().map(|ext| ext.to_string()).collect()
 }
 
 
@@ -19,48 +214,102 @@ impl DeviceConfiguration {
     pub fn new() -> DeviceConfiguration {
         DeviceConfiguration{
             device_uuid: Uuid::new_v4().to_string(),
+            device_name: default_device_name(),
+            listen_address: default_listen_address(),
+            port: default_port(),
+            tls: None,
             clue_timeout: 5,
-            rfid_retrys: 5
+            rfid_retrys: 5,
+            http_workers: default_http_workers(),
+            max_upload_size: default_max_upload_size(),
+            allowed_extensions: default_allowed_extensions(),
+            duplicate_uploads: DuplicatePolicy::default(),
+            upload_session_timeout: default_upload_session_timeout(),
+            webhooks: Vec::new(),
+            mqtt: None,
+            osc: None,
+            buttons: Vec::new(),
+            outputs: Vec::new(),
+            users: Vec::new()
         }
     }
 
 
     pub fn load(path: PathBuf) -> DeviceConfiguration{
-        let device_config: DeviceConfiguration;
+        let mut device_config: DeviceConfiguration;
         if !path.is_file() {
             // If the YAML file doesn't exist, create it and save the struct as YAML
             device_config = DeviceConfiguration::new();
-            device_config.save(path);
-
         } else {
             // If the YAML file exists, read and parse it into the struct
-            let mut file = OpenOptions::new()
-                .read(true)
-                .open(path)
-                .expect("Unable to open config");
-            let mut contents = String::new();
-            file.read_to_string(&mut contents).expect("Unable to read file");
-            device_config = serde_yaml::from_str(& contents).expect("Failed to parse config file");
+            device_config = DeviceConfiguration::read(&path).expect("Failed to parse config file");
             println!("Config file read: {:?}", device_config);
         }
 
+        if device_config.users.is_empty() {
+            println!("No users configured, creating accounts with random passwords");
+            device_config.users = initial_users();
+            device_config.save(path).expect("Unable to save config");
+        }
+        for username in users_with_default_passwords(&device_config.users) {
+            println!("Account {} still uses its username as password, change it", username);
+        }
+
         device_config
     }
 
-    pub fn save(&self, path: PathBuf){
+    /// Reads the configuration as it is saved, which can differ from the running one until a restart.
+    pub fn read(path: &Path) -> io::Result<DeviceConfiguration> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .open(path)?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        serde_yaml::from_str(&contents).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    pub fn scheme(&self) -> &'static str {
+        if self.tls.is_some() { "https" } else { "http" }
+    }
+
+    pub fn bind_address(&self) -> String {
+        format!("{}:{}", self.listen_address, self.port)
+    }
+
+    /// Writes the configuration next to the old one first and renames it over it,
+    /// so a failed write never leaves the device with a truncated config.
+    pub fn save(&self, path: PathBuf) -> io::Result<()> {
         let mut parent_dir = path.clone();
         parent_dir.pop();
         if !parent_dir.is_dir(){
-            fs::create_dir_all(parent_dir).unwrap_or_else(|err|{
-                error!("Faired to create database dir: {:?}", err);
-                panic!()
-            })
+            fs::create_dir_all(&parent_dir)?;
         }
 
-        let serialized_yaml = serde_yaml::to_string(self).unwrap();
-        let mut file = File::create(path.clone()).expect("Unable to create file");
-        file.write_all(serialized_yaml.as_bytes())
-            .expect("Unable to write data to file");
-        println!("Config file created: {}", path.display());
+        let serialized_yaml = serde_yaml::to_string(self).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        let temp_path = path.with_extension("yaml.tmp");
+        let mut file = File::create(&temp_path)?;
+        file.write_all(serialized_yaml.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&temp_path, &path)?;
+        println!("Config file saved: {}", path.display());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::users::{Role, UserAccount};
+    use super::*;
+
+    #[test]
+    fn debug_output_leaves_out_secrets() {
+        let mut config = DeviceConfiguration::new();
+        config.mqtt = Some(serde_yaml::from_str("{host: broker.local, username: clue, password: broker-secret}").unwrap());
+        config.users = vec![UserAccount::for_tests("admin", "secret", Role::Admin)];
+
+        let logged = format!("{:?}", config);
+        assert!(logged.contains("broker.local") && logged.contains("admin"));
+        assert!(!logged.contains("broker-secret"));
+        assert!(!logged.contains(&config.users[0].password_hash));
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,158 @@
+use std::fmt;
+use std::fmt::Formatter;
+
+use pbkdf2::pbkdf2_hmac;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use uuid::Uuid;
+
+const HASH_ITERATIONS: u32 = 100_000;
+const HASH_LENGTH: usize = 32;
+
+/// Roles are ordered so that a higher role can do everything a lower one can.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+pub enum Role {
+    GameMaster,
+    Admin,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct UserAccount {
+    pub username: String,
+    pub password_hash: String,
+    pub role: Role,
+}
+
+/// Leaves the password hash out so accounts can be logged.
+impl fmt::Debug for UserAccount {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("UserAccount")
+            .field("username", &self.username)
+            .field("role", &self.role)
+            .finish_non_exhaustive()
+    }
+}
+
+impl UserAccount {
+    pub fn new(username: &str, password: &str, role: Role) -> UserAccount {
+        UserAccount {
+            username: username.to_owned(),
+            password_hash: hash_password(password),
+            role,
+        }
+    }
+
+    /// An account hashed with few iterations so tests stay quick.
+    #[cfg(test)]
+    pub fn for_tests(username: &str, password: &str, role: Role) -> UserAccount {
+        UserAccount { username: username.to_owned(), password_hash: hash_with_iterations(password, 1_000), role }
+    }
+
+    pub fn set_password(&mut self, password: &str) {
+        self.password_hash = hash_password(password);
+    }
+
+    pub fn verify_password(&self, password: &str) -> bool {
+        let mut parts = self.password_hash.split('$');
+        let (Some("pbkdf2-sha256"), Some(iterations), Some(salt), Some(expected), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next(), parts.next()) else {
+            return false;
+        };
+        let (Ok(iterations), Ok(salt), Ok(expected)) = (iterations.parse::<u32>(), hex::decode(salt), hex::decode(expected)) else {
+            return false;
+        };
+
+        let mut hash = vec![0u8; expected.len()];
+        pbkdf2_hmac::<Sha256>(password.as_bytes(), &salt, iterations, &mut hash);
+
+        // Compare every byte so the time taken does not leak how much of the hash matched
+        hash.len() == expected.len() && hash.iter().zip(&expected).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+    }
+}
+
+/// Hashes a password as `pbkdf2-sha256$<iterations>$<salt>$<hash>` with a random salt.
+pub fn hash_password(password: &str) -> String {
+    hash_with_iterations(password, HASH_ITERATIONS)
+}
+
+fn hash_with_iterations(password: &str, iterations: u32) -> String {
+    let salt = Uuid::new_v4().into_bytes();
+    let mut hash = [0u8; HASH_LENGTH];
+    pbkdf2_hmac::<Sha256>(password.as_bytes(), &salt, iterations, &mut hash);
+
+    format!("pbkdf2-sha256${}${}${}", iterations, hex::encode(salt), hex::encode(hash))
+}
+
+/// Passwords have to be at least this long.
+const MIN_PASSWORD_LENGTH: usize = 8;
+
+pub fn check_new_password(password: &str) -> Result<(), String> {
+    if password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(format!("Passwords need at least {} characters", MIN_PASSWORD_LENGTH));
+    }
+    Ok(())
+}
+
+/// Accounts created when a configuration has none. Each gets a random password that is printed
+/// once, so it can only be read from the console or journal of the first boot.
+pub fn initial_users() -> Vec<UserAccount> {
+    [("admin", Role::Admin), ("gamemaster", Role::GameMaster)]
+        .into_iter()
+        .map(|(username, role)| {
+            let password = Uuid::new_v4().simple().to_string()[..12].to_owned();
+            println!("Created account {} with password {}, change it after logging in", username, password);
+            UserAccount::new(username, &password, role)
+        })
+        .collect()
+}
+
+/// Accounts whose password is still their username, like the ones older versions created.
+pub fn users_with_default_passwords(users: &[UserAccount]) -> Vec<&str> {
+    users.iter()
+        .filter(|user| user.verify_password(&user.username))
+        .map(|user| user.username.as_str())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn account(username: &str, password: &str) -> UserAccount {
+        UserAccount::for_tests(username, password, Role::Admin)
+    }
+
+    #[test]
+    fn verifies_only_the_right_password() {
+        let user = account("admin", "correct horse");
+        assert!(user.password_hash.starts_with("pbkdf2-sha256$1000$"));
+        assert!(user.verify_password("correct horse"));
+        assert!(!user.verify_password("Correct horse"));
+        assert!(!user.verify_password(""));
+    }
+
+    #[test]
+    fn rejects_malformed_hashes() {
+        let valid = account("admin", "secret").password_hash;
+        let parts = valid.split('$').collect::<Vec<_>>();
+        for hash in [
+            String::new(),
+            format!("md5${}${}${}", parts[1], parts[2], parts[3]),
+            format!("pbkdf2-sha256$many${}${}", parts[2], parts[3]),
+            format!("pbkdf2-sha256${}$not-hex${}", parts[1], parts[3]),
+            format!("pbkdf2-sha256${}${}", parts[1], parts[2]),
+            format!("{}$extra", valid),
+        ] {
+            let user = UserAccount { password_hash: hash.clone(), ..account("admin", "secret") };
+            assert!(!user.verify_password("secret"), "{} was accepted", hash);
+        }
+    }
+
+    #[test]
+    fn finds_accounts_still_using_their_username() {
+        let users = [account("admin", "admin"), account("gamemaster", "something else")];
+        assert_eq!(users_with_default_passwords(&users), vec!["admin"]);
+        assert!(check_new_password("short").is_err());
+        assert!(check_new_password("long enough").is_ok());
+    }
+}
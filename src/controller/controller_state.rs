@@ -0,0 +1,114 @@
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+use tera::Tera;
+use crate::config::controller::ControllerConfiguration;
+use crate::config::setup::DuplicatePolicy;
+use crate::config::users::UserAccount;
+use crate::controller::fleet::Fleet;
+use crate::web_server::api::ApiError;
+use crate::web_server::api::ApiError::NotFound;
+use crate::web_server::auth::SessionStore;
+use crate::web_server::media_library::MediaLibrary;
+use crate::web_server::router::ServerState;
+use crate::web_server::upload_handler::{clear_staging_dir, UploadPolicy};
+
+/// Everything the fleet controller's handlers need, the controller's counterpart of `AppState`.
+pub struct ControllerState {
+    pub project_dir: PathBuf,
+    pub config: ControllerConfiguration,
+    /// The accounts as they are now, `config.users` is what they were at startup
+    users: RwLock<Vec<UserAccount>>,
+    pub sessions: SessionStore,
+    pub fleet: Arc<Fleet>,
+    /// Files waiting to be pushed to devices
+    pub media: MediaLibrary,
+    pub tera: Tera,
+}
+
+impl ControllerState {
+    pub fn new(project_dir: PathBuf, config: ControllerConfiguration, fleet: Arc<Fleet>) -> ControllerState {
+        let mut tera = Tera::default();
+        tera.add_raw_template("fleet.html", include_str!("../../pages/fleet.html"))
+            .expect("Fleet page template should be valid");
+
+        clear_staging_dir(&project_dir.join("files"));
+        let media = MediaLibrary::new(project_dir.join("files"));
+
+        ControllerState {
+            project_dir,
+            users: RwLock::new(config.users.clone()),
+            config,
+            sessions: SessionStore::new(),
+            fleet,
+            media,
+            tera,
+        }
+    }
+
+    pub fn files_dir(&self) -> PathBuf {
+        self.project_dir.join("files")
+    }
+
+    pub fn config_path(&self) -> PathBuf {
+        self.project_dir.join("config/Controller.yaml")
+    }
+
+    pub fn upload_policy(&self) -> UploadPolicy {
+        UploadPolicy {
+            max_size: self.config.max_upload_size,
+            allowed_extensions: self.config.allowed_extensions.iter().map(|ext| ext.to_lowercase()).collect(),
+            duplicates: DuplicatePolicy::Overwrite,
+        }
+    }
+}
+
+impl ServerState for ControllerState {
+    fn sessions(&self) -> &SessionStore {
+        &self.sessions
+    }
+
+    fn find_user(&self, username: &str) -> Option<UserAccount> {
+        self.users.read().unwrap().iter().find(|user| user.username == username).cloned()
+    }
+
+    fn save_password(&self, username: &str, password: &str) -> Result<(), ApiError> {
+        let mut users = self.users.write().unwrap();
+
+        let mut saved = ControllerConfiguration::read(&self.config_path())?;
+        let user = saved.users.iter_mut().find(|user| user.username == username).ok_or(NotFound(username.to_owned()))?;
+        user.set_password(password);
+        let password_hash = user.password_hash.clone();
+        saved.save(self.config_path())?;
+
+        if let Some(user) = users.iter_mut().find(|user| user.username == username) {
+            user.password_hash = password_hash;
+        }
+        Ok(())
+    }
+
+    /// The controller keeps no request metrics.
+    fn http_request(&self, _: &str, _: u16) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+    use crate::config::users::Role;
+    use super::*;
+
+    #[test]
+    fn saves_password_changes() {
+        let dir = TempDir::new().unwrap();
+        let mut config = ControllerConfiguration::new();
+        config.users = vec![UserAccount::for_tests("admin", "secret", Role::Admin)];
+        config.save(dir.path().join("config/Controller.yaml")).unwrap();
+        let state = ControllerState::new(dir.path().to_path_buf(), config.clone(), Fleet::new(config));
+
+        state.save_password("admin", "a new password").unwrap();
+        assert!(state.find_user("admin").unwrap().verify_password("a new password"));
+        let saved = ControllerConfiguration::read(&state.config_path()).unwrap();
+        assert!(saved.users[0].verify_password("a new password"));
+        assert!(matches!(state.save_password("nobody", "a new password"), Err(NotFound(_))));
+    }
+}
@@ -0,0 +1,343 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::{fmt, io};
+
+use log::{info, warn};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use ureq::{Agent, AgentBuilder};
+use crate::config::controller::DeviceCredentials;
+use crate::controller::device_client::ClientError::{InvalidResponse, IoError, NoCredentials, Status, Transport};
+use crate::web_server::api::DeviceIdentity;
+use crate::web_server::chunked_upload::sha256_file;
+
+/// Pushed files are sent in chunks this big, a failed chunk is resent from where the device got to.
+const CHUNK_SIZE: u64 = 4 * 1024 * 1024;
+/// How many failed chunks in a row a push survives.
+const CHUNK_RETRIES: u32 = 3;
+
+/// Talks to one device over its web interface, logging in with the configured admin account
+/// whenever the device asks for it. Without an account only the public endpoints can be used.
+pub struct DeviceClient {
+    url: String,
+    agent: Agent,
+    credentials: Option<DeviceCredentials>,
+    token: Mutex<Option<String>>,
+}
+
+/// What is sent along with a request to the device.
+enum Body<'a> {
+    Empty,
+    Json(&'a Value),
+    Bytes(&'a [u8]),
+}
+
+#[derive(Debug, Deserialize)]
+struct LoginResult {
+    token: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct RemoteMedia {
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct RemoteUpload {
+    id: String,
+    received: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct FinalizedUpload {
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Message {
+    message: String,
+}
+
+impl DeviceClient {
+    pub fn new(url: &str, credentials: Option<DeviceCredentials>) -> DeviceClient {
+        DeviceClient {
+            url: url.trim_end_matches('/').to_owned(),
+            agent: AgentBuilder::new()
+                .timeout_connect(Duration::from_secs(5))
+                .timeout_read(Duration::from_secs(60))
+                .build(),
+            credentials,
+            token: Mutex::new(None),
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn identity(&self) -> Result<DeviceIdentity, ClientError> {
+        Ok(self.agent.get(&self.endpoint("/api/device")).call()?.into_json()?)
+    }
+
+    /// The device's `/healthz` report, which is still sent along with a 503 when the device is down.
+    pub fn health(&self) -> Result<Value, ClientError> {
+        match self.agent.get(&self.endpoint("/healthz")).call() {
+            Ok(response) | Err(ureq::Error::Status(503, response)) => Ok(response.into_json()?),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub fn media(&self) -> Result<Vec<String>, ClientError> {
+        let media = self.send_json::<Vec<RemoteMedia>>("GET", "/api/media", Body::Empty)?;
+        Ok(media.into_iter().map(|media| media.name).collect())
+    }
+
+    pub fn play(&self, name: &str) -> Result<String, ClientError> {
+        let path = format!("/api/media/{}/play", utf8_percent_encode(name, NON_ALPHANUMERIC));
+        Ok(self.send_json::<Message>("POST", &path, Body::Empty)?.message)
+    }
+
+    pub fn reboot(&self) -> Result<(), ClientError> {
+        self.send("POST", "/reboot", Body::Empty)?;
+        Ok(())
+    }
+
+    /// Uploads `path` to the device's library as `name` using its resumable upload api,
+    /// reporting the bytes the device has received after every chunk. Returns the name the device stored it as.
+    pub fn push(&self, path: &Path, name: &str, progress: &dyn Fn(u64, u64)) -> Result<String, ClientError> {
+        let mut file = File::open(path)?;
+        let size = file.metadata()?.len();
+        let sha256 = sha256_file(path)?;
+
+        let upload = self.send_json::<RemoteUpload>("POST", "/api/uploads", Body::Json(&json!({ "name": name, "size": size })))?;
+        let upload_path = format!("/api/uploads/{}", upload.id);
+        info!("Pushing {} to {} as upload {}", name, self.url, upload.id);
+
+        let result = self.send_chunks(&mut file, &upload_path, upload.received, size, progress)
+            .and_then(|_| self.send_json::<FinalizedUpload>("POST", &format!("{}/finalize", upload_path), Body::Json(&json!({ "sha256": sha256 }))));
+
+        match result {
+            Ok(finalized) => Ok(finalized.name),
+            Err(err) => {
+                // Don't leave a half received file taking up space on the device
+                if let Err(abort_err) = self.send("DELETE", &upload_path, Body::Empty) {
+                    warn!("Failed to abort upload {} on {}: {}", upload.id, self.url, abort_err);
+                }
+                Err(err)
+            }
+        }
+    }
+
+    fn send_chunks(&self, file: &mut File, upload_path: &str, mut received: u64, size: u64, progress: &dyn Fn(u64, u64)) -> Result<(), ClientError> {
+        let mut failures = 0;
+        let mut chunk = Vec::with_capacity(CHUNK_SIZE as usize);
+
+        while received < size {
+            chunk.clear();
+            file.seek(SeekFrom::Start(received))?;
+            file.by_ref().take(CHUNK_SIZE).read_to_end(&mut chunk)?;
+
+            let offset = received;
+            let sent = self.send("PUT", &format!("{}?offset={}", upload_path, offset), Body::Bytes(&chunk))
+                .and_then(|response| Ok(response.into_json::<RemoteUpload>()?));
+
+            match sent {
+                Ok(upload) => {
+                    received = upload.received;
+                    failures = 0;
+                }
+                Err(err) if failures < CHUNK_RETRIES => {
+                    failures += 1;
+                    warn!("Chunk at {} to {} failed, resuming ({}/{}): {}", offset, self.url, failures, CHUNK_RETRIES, err);
+                    // Part of the chunk may have arrived, carry on from what the device has
+                    received = self.send_json::<RemoteUpload>("GET", upload_path, Body::Empty)?.received;
+                }
+                Err(err) => return Err(err),
+            }
+            progress(received, size);
+        }
+        Ok(())
+    }
+
+    fn send_json<T: DeserializeOwned>(&self, method: &str, path: &str, body: Body) -> Result<T, ClientError> {
+        Ok(self.send(method, path, body)?.into_json()?)
+    }
+
+    /// Sends an authenticated request, logging in first when there is no token yet
+    /// and once more if the device has forgotten it, e.g. after a restart.
+    fn send(&self, method: &str, path: &str, body: Body) -> Result<ureq::Response, ClientError> {
+        for attempt in 0..2 {
+            let request = self.agent.request(method, &self.endpoint(path))
+                .set("Authorization", &format!("Bearer {}", self.token()?));
+            let result = match body {
+                Body::Empty => request.call(),
+                Body::Json(json) => request.send_json(json),
+                Body::Bytes(bytes) => request.send_bytes(bytes),
+            };
+            match result {
+                Err(ureq::Error::Status(401, _)) if attempt == 0 => {
+                    self.token.lock().unwrap().take();
+                }
+                result => return Ok(result?),
+            }
+        }
+        Err(Status(401, "Device did not accept the login".to_owned()))
+    }
+
+    fn token(&self) -> Result<String, ClientError> {
+        let mut token = self.token.lock().unwrap();
+        match token.as_ref() {
+            Some(token) => Ok(token.clone()),
+            None => Ok(token.insert(self.login()?).clone()),
+        }
+    }
+
+    fn login(&self) -> Result<String, ClientError> {
+        let credentials = self.credentials.as_ref().ok_or(NoCredentials)?;
+        let response = self.agent.post(&self.endpoint("/api/login"))
+            .send_json(json!({ "username": credentials.username, "password": credentials.password }))?;
+        Ok(response.into_json::<LoginResult>()?.token)
+    }
+
+    fn endpoint(&self, path: &str) -> String {
+        format!("{}{}", self.url, path)
+    }
+}
+
+#[derive(Debug)]
+pub enum ClientError {
+    /// The device answered with an error status and message
+    Status(u16, String),
+    Transport(String),
+    InvalidResponse(String),
+    IoError(io::Error),
+    /// No `device_credentials` are configured to log in with
+    NoCredentials,
+}
+
+impl Display for ClientError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Status(status, message) => {write!(f, "Device answered {}: {}", status, message)}
+            Transport(reason) => {write!(f, "Could not reach device: {}", reason)}
+            InvalidResponse(reason) => {write!(f, "Device sent an invalid response: {}", reason)}
+            IoError(error) => {write!(f, "Io operation failed: {}", error)}
+            NoCredentials => {write!(f, "No device_credentials are configured to log into the device with")}
+        }
+    }
+}
+
+impl From<ureq::Error> for ClientError {
+    fn from(error: ureq::Error) -> Self {
+        match error {
+            ureq::Error::Status(status, response) => {
+                let body = response.into_string().unwrap_or_default();
+                let message = serde_json::from_str::<Message>(&body).map(|message| message.message).unwrap_or(body);
+                Status(status, message)
+            }
+            ureq::Error::Transport(transport) => Transport(transport.to_string()),
+        }
+    }
+}
+
+impl From<io::Error> for ClientError {
+    fn from(error: io::Error) -> Self {
+        // into_json reports unparseable bodies as InvalidData
+        if error.kind() == io::ErrorKind::InvalidData {
+            InvalidResponse(error.to_string())
+        } else {
+            IoError(error)
+        }
+    }
+}
+
+impl Error for ClientError {}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::sync::Arc;
+    use std::thread;
+    use sha2::{Digest, Sha256};
+    use tempfile::NamedTempFile;
+    use tiny_http::{Response, Server};
+    use super::*;
+
+    /// Answers like a device's upload api, forgetting the login once to act like a restarted device.
+    fn stand_in_device(server: Arc<Server>) -> thread::JoinHandle<(Vec<String>, Vec<u8>)> {
+        thread::spawn(move || {
+            let mut requests = Vec::new();
+            let mut received = Vec::new();
+            let mut logins = 0;
+            for mut request in server.incoming_requests() {
+                let url = request.url().to_owned();
+                requests.push(format!("{} {}", request.method(), url.split('?').next().unwrap()));
+                let authorized = request.headers().iter()
+                    .any(|header| header.field.equiv("Authorization") && header.value.as_str() == format!("Bearer token-{}", logins));
+
+                let (status, body) = match (request.method().as_str(), url.as_str()) {
+                    ("POST", "/api/login") => {
+                        logins += 1;
+                        (200, json!({ "token": format!("token-{}", logins), "role": "Admin" }))
+                    }
+                    _ if !authorized || (logins == 1 && url.starts_with("/api/uploads/")) => (401, json!({ "message": "Login required" })),
+                    ("POST", "/api/uploads") => (201, json!({ "id": "upload", "received": 0 })),
+                    ("PUT", _) => {
+                        assert_eq!(url, format!("/api/uploads/upload?offset={}", received.len()));
+                        request.as_reader().read_to_end(&mut received).unwrap();
+                        (200, json!({ "id": "upload", "received": received.len() }))
+                    }
+                    ("POST", "/api/uploads/upload/finalize") => {
+                        let finalize = serde_json::from_reader::<_, Value>(request.as_reader()).unwrap();
+                        assert_eq!(finalize["sha256"], hex::encode(Sha256::digest(&received)));
+                        let response = Response::from_string(json!({ "name": "clue.mp4", "size": received.len() }).to_string());
+                        request.respond(response.with_status_code(201)).unwrap();
+                        break;
+                    }
+                    _ => (404, json!({ "message": "Not found" })),
+                };
+                request.respond(Response::from_string(body.to_string()).with_status_code(status)).unwrap();
+            }
+            (requests, received)
+        })
+    }
+
+    #[test]
+    fn pushes_files_in_chunks_logging_in_again_when_asked() {
+        let server = Arc::new(Server::http("127.0.0.1:0").unwrap());
+        let url = format!("http://{}", server.server_addr().to_ip().unwrap());
+        let device = stand_in_device(server.clone());
+
+        let file = NamedTempFile::new().unwrap();
+        let contents = (0..CHUNK_SIZE * 2 + 10).map(|i| i as u8).collect::<Vec<_>>();
+        fs::write(&file, &contents).unwrap();
+
+        let progress = Mutex::new(Vec::new());
+        let credentials = DeviceCredentials { username: "admin".to_owned(), password: "secret".to_owned() };
+        let client = DeviceClient::new(&url, Some(credentials));
+        let stored = client.push(file.path(), "clue.mp4", &|received, _| progress.lock().unwrap().push(received));
+
+        assert_eq!(stored.unwrap(), "clue.mp4");
+        let (requests, received) = device.join().unwrap();
+        assert!(received == contents);
+        assert_eq!(*progress.lock().unwrap(), vec![CHUNK_SIZE, CHUNK_SIZE * 2, CHUNK_SIZE * 2 + 10]);
+        assert_eq!(requests, vec![
+            "POST /api/login",
+            "POST /api/uploads",
+            "PUT /api/uploads/upload",
+            "POST /api/login",
+            "PUT /api/uploads/upload",
+            "PUT /api/uploads/upload",
+            "PUT /api/uploads/upload",
+            "POST /api/uploads/upload/finalize",
+        ]);
+    }
+}
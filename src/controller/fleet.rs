@@ -0,0 +1,430 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::{error, info, warn};
+use serde::Serialize;
+use serde_json::Value;
+use crate::config::controller::ControllerConfiguration;
+use crate::controller::device_client::{ClientError, DeviceClient};
+use crate::discovery::mdns::{discover, Interfaces};
+use crate::web_server::api::DeviceIdentity;
+
+/// How a device became part of the fleet.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceSource {
+    Discovered,
+    Configured,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskState {
+    Running,
+    Done,
+    Failed,
+}
+
+/// The last thing the controller asked a device to do.
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceTask {
+    pub action: String,
+    pub state: TaskState,
+    /// Fraction of a push the device has received
+    pub progress: Option<f64>,
+    pub message: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FleetDevice {
+    pub device_uuid: String,
+    pub name: String,
+    pub version: String,
+    pub url: String,
+    pub source: DeviceSource,
+    pub online: bool,
+    /// The device's own `/healthz` report
+    pub health: Option<Value>,
+    pub media: Vec<String>,
+    /// Seconds since the unix epoch the device last answered a refresh
+    pub last_seen: Option<u64>,
+    pub error: Option<String>,
+    pub task: Option<DeviceTask>,
+}
+
+/// A configured device that could not be asked for its uuid, so it can't be put on the dashboard yet.
+#[derive(Debug, Clone, Serialize)]
+pub struct UnreachableDevice {
+    pub url: String,
+    pub error: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FleetStatus {
+    pub devices: Vec<FleetDevice>,
+    pub unreachable: Vec<UnreachableDevice>,
+    pub refreshed: Option<u64>,
+}
+
+/// What a device answered during a refresh.
+struct Probe {
+    client: Arc<DeviceClient>,
+    identity: DeviceIdentity,
+    health: Result<Value, ClientError>,
+    media: Result<Vec<String>, ClientError>,
+}
+
+struct FleetEntry {
+    client: Arc<DeviceClient>,
+    device: FleetDevice,
+}
+
+/// Every device the controller knows about keyed by `device_uuid`.
+///
+/// Devices stay on the dashboard once seen, a device that stops answering is only marked offline.
+pub struct Fleet {
+    config: ControllerConfiguration,
+    devices: Mutex<HashMap<String, FleetEntry>>,
+    unreachable: Mutex<Vec<UnreachableDevice>>,
+    refreshed: Mutex<Option<u64>>,
+    refreshing: Mutex<()>,
+}
+
+impl Fleet {
+    pub fn new(config: ControllerConfiguration) -> Arc<Fleet> {
+        Arc::new(Fleet {
+            config,
+            devices: Mutex::new(HashMap::new()),
+            unreachable: Mutex::new(Vec::new()),
+            refreshed: Mutex::new(None),
+            refreshing: Mutex::new(()),
+        })
+    }
+
+    /// Refreshes the fleet now and then every `refresh_interval` seconds.
+    pub fn start_refresh_thread(self: &Arc<Fleet>) {
+        let fleet = self.clone();
+        thread::spawn(move || loop {
+            fleet.refresh();
+            thread::sleep(Duration::from_secs(fleet.config.refresh_interval.max(1)));
+        });
+    }
+
+    /// Finds the devices, asks each who it is and how it is doing and updates the dashboard.
+    /// A refresh that is asked for while one is running is skipped.
+    pub fn refresh(&self) {
+        let Ok(_refreshing) = self.refreshing.try_lock() else {
+            return;
+        };
+
+        let mut candidates = self.config.devices.iter()
+            .map(|url| (url.trim_end_matches('/').to_owned(), DeviceSource::Configured))
+            .collect::<Vec<_>>();
+        if self.config.discover {
+            match discover(Duration::from_secs(self.config.discovery_timeout), Interfaces::Network) {
+                Ok(devices) => candidates.extend(devices.into_iter()
+                    .filter_map(|device| device.url)
+                    .filter(|url| !self.config.devices.iter().any(|configured| configured.trim_end_matches('/') == url))
+                    .map(|url| (url, DeviceSource::Discovered))),
+                Err(err) => error!("Failed to discover devices: {:?}", err),
+            }
+        }
+
+        let probes = thread::scope(|scope| {
+            candidates.iter()
+                .map(|(url, source)| scope.spawn(move || (url, *source, self.probe(url))))
+                .collect::<Vec<_>>()
+                .into_iter()
+                .filter_map(|probe| probe.join().ok())
+                .collect::<Vec<_>>()
+        });
+
+        let now = now();
+        let mut devices = self.devices.lock().unwrap();
+        let mut unreachable = Vec::new();
+        let mut seen = Vec::new();
+        for (url, source, probe) in probes {
+            let Probe { client, identity, health, media } = match probe {
+                Ok(probe) => probe,
+                Err(err) => {
+                    unreachable.push(UnreachableDevice { url: url.clone(), error: err.to_string() });
+                    continue;
+                }
+            };
+
+            seen.push(identity.device_uuid.clone());
+            let task = devices.get(&identity.device_uuid).and_then(|entry| entry.device.task.clone());
+            let (media, error) = match media {
+                Ok(media) => (media, None),
+                Err(err) => (Vec::new(), Some(err.to_string())),
+            };
+            devices.insert(identity.device_uuid.clone(), FleetEntry {
+                client,
+                device: FleetDevice {
+                    device_uuid: identity.device_uuid,
+                    name: identity.name,
+                    version: identity.version,
+                    url: url.clone(),
+                    source,
+                    online: true,
+                    health: health.ok(),
+                    media,
+                    last_seen: Some(now),
+                    error,
+                    task,
+                },
+            });
+        }
+
+        for entry in devices.values_mut().filter(|entry| !seen.contains(&entry.device.device_uuid)) {
+            entry.device.online = false;
+            entry.device.error = Some("Did not answer the last refresh".to_owned());
+        }
+
+        // Configured devices that are offline are already on the dashboard under their uuid
+        unreachable.retain(|device| !devices.values().any(|entry| entry.device.url == device.url));
+        info!("Refreshed fleet, {} of {} devices online", seen.len(), devices.len());
+        *self.unreachable.lock().unwrap() = unreachable;
+        *self.refreshed.lock().unwrap() = Some(now);
+    }
+
+    fn probe(&self, url: &str) -> Result<Probe, ClientError> {
+        // Reuse the client so its login survives between refreshes
+        let existing = self.devices.lock().unwrap().values()
+            .find(|entry| entry.client.url() == url)
+            .map(|entry| entry.client.clone());
+        let client = existing.unwrap_or_else(|| Arc::new(DeviceClient::new(url, self.config.device_credentials.clone())));
+
+        let identity = client.identity()?;
+        let health = client.health();
+        let media = client.media();
+        Ok(Probe { client, identity, health, media })
+    }
+
+    pub fn status(&self) -> FleetStatus {
+        let mut devices = self.devices.lock().unwrap().values()
+            .map(|entry| entry.device.clone())
+            .collect::<Vec<_>>();
+        devices.sort_by(|a, b| a.name.cmp(&b.name).then_with(|| a.device_uuid.cmp(&b.device_uuid)));
+
+        FleetStatus {
+            devices,
+            unreachable: self.unreachable.lock().unwrap().clone(),
+            refreshed: *self.refreshed.lock().unwrap(),
+        }
+    }
+
+    /// Returns the uuids that are not part of the fleet.
+    pub fn unknown_devices(&self, uuids: &[String]) -> Vec<String> {
+        let devices = self.devices.lock().unwrap();
+        uuids.iter().filter(|uuid| !devices.contains_key(*uuid)).cloned().collect()
+    }
+
+    pub fn push(self: &Arc<Fleet>, uuids: &[String], path: PathBuf, name: String) {
+        self.run(uuids, "push", move |client, progress| {
+            let stored = client.push(&path, &name, &|received, size| progress(received as f64 / size.max(1) as f64))?;
+            Ok(format!("Stored as {}", stored))
+        });
+    }
+
+    pub fn play(self: &Arc<Fleet>, uuids: &[String], name: String) {
+        self.run(uuids, "play", move |client, _| client.play(&name));
+    }
+
+    pub fn reboot(self: &Arc<Fleet>, uuids: &[String]) {
+        self.run(uuids, "reboot", |client, _| client.reboot().map(|_| "Rebooting".to_owned()));
+    }
+
+    /// Runs `job` against every device at once in the background, recording how it went as the device's task.
+    fn run<F>(self: &Arc<Fleet>, uuids: &[String], action: &str, job: F)
+    where
+        F: Fn(&DeviceClient, &dyn Fn(f64)) -> Result<String, ClientError> + Send + Sync + 'static,
+    {
+        let job = Arc::new(job);
+        for uuid in uuids {
+            let Some(client) = self.devices.lock().unwrap().get(uuid).map(|entry| entry.client.clone()) else {
+                continue;
+            };
+
+            self.set_task(uuid, DeviceTask { action: action.to_owned(), state: TaskState::Running, progress: None, message: None });
+            let fleet = self.clone();
+            let job = job.clone();
+            let uuid = uuid.clone();
+            let action = action.to_owned();
+            thread::spawn(move || {
+                let progress = |progress: f64| fleet.update_task(&uuid, |task| task.progress = Some(progress));
+                let result = job(&client, &progress);
+                if let Err(err) = &result {
+                    warn!("Failed to {} on {}: {}", action, uuid, err);
+                }
+                fleet.update_task(&uuid, |task| match result {
+                    Ok(message) => {
+                        task.state = TaskState::Done;
+                        task.message = Some(message);
+                    }
+                    Err(err) => {
+                        task.state = TaskState::Failed;
+                        task.message = Some(err.to_string());
+                    }
+                });
+            });
+        }
+    }
+
+    fn set_task(&self, uuid: &str, task: DeviceTask) {
+        if let Some(entry) = self.devices.lock().unwrap().get_mut(uuid) {
+            entry.device.task = Some(task);
+        }
+    }
+
+    fn update_task<F: FnOnce(&mut DeviceTask)>(&self, uuid: &str, update: F) {
+        if let Some(task) = self.devices.lock().unwrap().get_mut(uuid).and_then(|entry| entry.device.task.as_mut()) {
+            update(task);
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs()).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Instant;
+    use serde_json::json;
+    use tiny_http::{Response, Server};
+    use crate::config::controller::DeviceCredentials;
+    use crate::controller::device_client::ClientError::NoCredentials;
+    use super::*;
+
+    /// Answers like a device until dropped, or with 503 to everything while `online` is unset.
+    struct StandInDevice {
+        server: Arc<Server>,
+        url: String,
+        online: Arc<AtomicBool>,
+    }
+
+    impl StandInDevice {
+        fn start(uuid: &str, name: &str) -> StandInDevice {
+            let server = Arc::new(Server::http("127.0.0.1:0").unwrap());
+            let url = format!("http://{}", server.server_addr().to_ip().unwrap());
+            let online = Arc::new(AtomicBool::new(true));
+
+            let (serving, answering) = (server.clone(), online.clone());
+            let identity = json!({ "device_uuid": uuid, "name": name, "version": "test" });
+            thread::spawn(move || {
+                for request in serving.incoming_requests() {
+                    let authorized = request.headers().iter()
+                        .any(|header| header.field.equiv("Authorization") && header.value.as_str() == "Bearer token");
+                    let (status, body) = match (request.method().as_str(), request.url()) {
+                        _ if !answering.load(Ordering::SeqCst) => (503, json!({ "message": "Down" })),
+                        ("GET", "/api/device") => (200, identity.clone()),
+                        ("GET", "/healthz") => (200, json!({ "status": "ok" })),
+                        ("POST", "/api/login") => (200, json!({ "token": "token", "role": "Admin" })),
+                        _ if !authorized => (401, json!({ "message": "Login required" })),
+                        ("GET", "/api/media") => (200, json!([{ "name": "clue.mp4" }])),
+                        ("POST", "/api/media/clue%2Emp4/play") => (200, json!({ "message": "Playing clue.mp4" })),
+                        _ => (404, json!({ "message": "Not found" })),
+                    };
+                    let _ = request.respond(Response::from_string(body.to_string()).with_status_code(status));
+                }
+            });
+            StandInDevice { server, url, online }
+        }
+    }
+
+    impl Drop for StandInDevice {
+        fn drop(&mut self) {
+            self.server.unblock();
+        }
+    }
+
+    fn fleet(devices: &[&StandInDevice], device_credentials: Option<DeviceCredentials>) -> Arc<Fleet> {
+        let mut config = ControllerConfiguration::new();
+        config.discover = false;
+        config.devices = devices.iter().map(|device| format!("{}/", device.url)).collect();
+        config.device_credentials = device_credentials;
+        Fleet::new(config)
+    }
+
+    fn credentials() -> Option<DeviceCredentials> {
+        Some(DeviceCredentials { username: "admin".to_owned(), password: "secret".to_owned() })
+    }
+
+    /// Waits for the task on the device to finish.
+    fn finished_task(fleet: &Fleet, uuid: &str) -> DeviceTask {
+        let started = Instant::now();
+        loop {
+            let device = fleet.status().devices.into_iter().find(|device| device.device_uuid == uuid).unwrap();
+            match device.task {
+                Some(task) if task.state != TaskState::Running => return task,
+                _ if started.elapsed() > Duration::from_secs(5) => panic!("Task on {} did not finish", uuid),
+                _ => thread::sleep(Duration::from_millis(10)),
+            }
+        }
+    }
+
+    #[test]
+    fn refresh_keeps_devices_that_stop_answering_as_offline() {
+        let (study, attic, cellar) = (StandInDevice::start("a", "Study"), StandInDevice::start("b", "Attic"), StandInDevice::start("c", "Cellar"));
+        cellar.online.store(false, Ordering::SeqCst);
+        let fleet = fleet(&[&study, &attic, &cellar], credentials());
+
+        fleet.refresh();
+        let status = fleet.status();
+        assert_eq!(status.devices.iter().map(|device| device.name.as_str()).collect::<Vec<_>>(), vec!["Attic", "Study"]);
+        let attic_device = &status.devices[0];
+        assert!(attic_device.online && attic_device.error.is_none());
+        assert_eq!((attic_device.url.as_str(), attic_device.source), (attic.url.as_str(), DeviceSource::Configured));
+        assert_eq!(attic_device.media, vec!["clue.mp4"]);
+        assert_eq!(attic_device.health, Some(json!({ "status": "ok" })));
+        assert_eq!(status.unreachable.iter().map(|device| device.url.as_str()).collect::<Vec<_>>(), vec![cellar.url.as_str()]);
+
+        attic.online.store(false, Ordering::SeqCst);
+        cellar.online.store(true, Ordering::SeqCst);
+        fleet.refresh();
+        let status = fleet.status();
+        assert_eq!(status.devices.iter().map(|device| (device.name.as_str(), device.online)).collect::<Vec<_>>(),
+                   vec![("Attic", false), ("Cellar", true), ("Study", true)]);
+        // The offline device keeps what was last seen of it and is not listed as unreachable as well
+        assert_eq!(status.devices[0].media, vec!["clue.mp4"]);
+        assert!(status.devices[0].last_seen.is_some() && status.devices[0].error.is_some());
+        assert!(status.unreachable.is_empty());
+    }
+
+    #[test]
+    fn tasks_record_how_actions_went_and_survive_refreshes() {
+        let (study, attic) = (StandInDevice::start("a", "Study"), StandInDevice::start("b", "Attic"));
+        let fleet = fleet(&[&study, &attic], credentials());
+        fleet.refresh();
+        assert_eq!(fleet.unknown_devices(&["a".to_owned(), "z".to_owned()]), vec!["z"]);
+
+        fleet.play(&["a".to_owned()], "clue.mp4".to_owned());
+        fleet.reboot(&["b".to_owned()]);
+        let played = finished_task(&fleet, "a");
+        assert_eq!((played.action.as_str(), played.state, played.message.as_deref()), ("play", TaskState::Done, Some("Playing clue.mp4")));
+        let rebooted = finished_task(&fleet, "b");
+        assert_eq!((rebooted.action.as_str(), rebooted.state), ("reboot", TaskState::Failed));
+
+        fleet.refresh();
+        assert_eq!(finished_task(&fleet, "a").message.as_deref(), Some("Playing clue.mp4"));
+    }
+
+    #[test]
+    fn devices_are_only_watched_without_credentials() {
+        let study = StandInDevice::start("a", "Study");
+        let fleet = fleet(&[&study], None);
+        fleet.refresh();
+
+        let device = &fleet.status().devices[0];
+        assert!(device.online && device.health.is_some() && device.media.is_empty());
+        assert_eq!(device.error.as_deref(), Some(NoCredentials.to_string().as_str()));
+
+        fleet.play(&["a".to_owned()], "clue.mp4".to_owned());
+        assert_eq!(finished_task(&fleet, "a").state, TaskState::Failed);
+    }
+}
@@ -0,0 +1,116 @@
+use std::error::Error;
+use std::fs;
+use std::thread;
+
+use serde::Deserialize;
+use tera::Context;
+use tiny_http::Request;
+use crate::config::users::Role;
+use crate::controller::controller_state::ControllerState;
+use crate::web_server::api::ApiError::{BadRequest, Conflict, NotFound};
+use crate::web_server::api::{json_response, respond, ApiError, Message};
+use crate::web_server::media_path::resolve_media_path;
+use crate::web_server::page_handler::respond_with_html;
+use crate::web_server::router::PathParams;
+use crate::web_server::upload_handler::save_multipart_upload;
+
+/// Body of the push, play and reboot requests.
+#[derive(Debug, Deserialize)]
+struct FleetAction {
+    /// Uuids of the devices to act on
+    devices: Vec<String>,
+    #[serde(default)]
+    media: Option<String>,
+}
+
+pub fn dashboard(request: Request, state: &ControllerState, _: &PathParams) -> Result<(), Box<dyn Error>> {
+    let role = state.sessions.authenticate(&request).map(|session| session.role);
+
+    let mut context = Context::new();
+    context.insert("media", &state.media.list()?);
+    context.insert("isAdmin", &(role == Some(Role::Admin)));
+
+    respond_with_html(request, state.tera.render("fleet.html", &context)?)
+}
+
+pub fn list_devices(request: Request, state: &ControllerState, _: &PathParams) -> Result<(), Box<dyn Error>> {
+    respond(request, Ok(json_response(200, &state.fleet.status())))
+}
+
+/// Starts a refresh without waiting for it, the dashboard picks up the result when it polls.
+pub fn refresh_devices(request: Request, state: &ControllerState, _: &PathParams) -> Result<(), Box<dyn Error>> {
+    let fleet = state.fleet.clone();
+    thread::spawn(move || fleet.refresh());
+    respond(request, Ok(json_response(202, &Message::new("Refreshing devices"))))
+}
+
+pub fn list_media(request: Request, state: &ControllerState, _: &PathParams) -> Result<(), Box<dyn Error>> {
+    let result = state.media.list().map(|media| json_response(200, &media)).map_err(|err| err.into());
+    respond(request, result)
+}
+
+pub fn upload_media(mut request: Request, state: &ControllerState, _: &PathParams) -> Result<(), Box<dyn Error>> {
+    let result = save_multipart_upload(&mut request, &state.files_dir(), &state.upload_policy())
+        .map(|report| json_response(report.status_code(), &report))
+        .map_err(|err| BadRequest(err.to_string()));
+    respond(request, result)
+}
+
+pub fn delete_media(request: Request, state: &ControllerState, params: &PathParams) -> Result<(), Box<dyn Error>> {
+    let name = params.get("name").unwrap_or_default();
+    let result = resolve_media_path(&state.files_dir(), name)
+        .map_err(ApiError::from)
+        .and_then(|media| Ok(fs::remove_file(media)?))
+        .map(|_| json_response(200, &Message::new(format!("Removed {}", name))));
+    respond(request, result)
+}
+
+/// Copies a file from the controller's library to every selected device.
+pub fn push_media(mut request: Request, state: &ControllerState, _: &PathParams) -> Result<(), Box<dyn Error>> {
+    let result = read_action(&mut request, state)
+        .and_then(|action| {
+            let name = action.media.ok_or(BadRequest("media is required".to_owned()))?;
+            let path = resolve_media_path(&state.files_dir(), &name)?;
+            state.fleet.push(&action.devices, path, name.clone());
+            Ok(json_response(202, &Message::new(format!("Pushing {} to {} devices", name, action.devices.len()))))
+        });
+    respond(request, result)
+}
+
+/// Plays a file that is already on every selected device.
+pub fn play_media(mut request: Request, state: &ControllerState, _: &PathParams) -> Result<(), Box<dyn Error>> {
+    let result = read_action(&mut request, state)
+        .and_then(|action| {
+            let name = action.media.ok_or(BadRequest("media is required".to_owned()))?;
+            state.fleet.play(&action.devices, name.clone());
+            Ok(json_response(202, &Message::new(format!("Playing {} on {} devices", name, action.devices.len()))))
+        });
+    respond(request, result)
+}
+
+pub fn reboot_devices(mut request: Request, state: &ControllerState, _: &PathParams) -> Result<(), Box<dyn Error>> {
+    let result = read_action(&mut request, state)
+        .map(|action| {
+            state.fleet.reboot(&action.devices);
+            json_response(202, &Message::new(format!("Rebooting {} devices", action.devices.len())))
+        });
+    respond(request, result)
+}
+
+/// Parses the action and checks every device it names is part of the fleet.
+fn read_action(request: &mut Request, state: &ControllerState) -> Result<FleetAction, ApiError> {
+    let action = serde_json::from_reader::<_, FleetAction>(request.as_reader())
+        .map_err(|err| BadRequest(err.to_string()))?;
+    if action.devices.is_empty() {
+        return Err(BadRequest("No devices selected".to_owned()));
+    }
+    if state.config.device_credentials.is_none() {
+        return Err(Conflict("Set device_credentials in Controller.yaml to act on devices".to_owned()));
+    }
+
+    let unknown = state.fleet.unknown_devices(&action.devices);
+    if !unknown.is_empty() {
+        return Err(NotFound(unknown.join(", ")));
+    }
+    Ok(action)
+}
@@ -0,0 +1,6 @@
+pub mod device_client;
+pub mod fleet;
+pub mod controller_state;
+pub mod fleet_api;
+pub mod routes;
+pub mod server;
@@ -0,0 +1,24 @@
+use crate::controller::controller_state::ControllerState;
+use crate::controller::fleet_api;
+use crate::web_server::auth;
+use crate::web_server::auth::Access::{Admin, GameMaster, Public};
+use crate::web_server::router::Router;
+
+/// Every endpoint the fleet controller serves.
+pub fn build_controller_router() -> Router<ControllerState> {
+    Router::new()
+        .get("/login", Public, auth::login_page)
+        .post("/login", Public, auth::login_form)
+        .post("/logout", Public, auth::logout)
+        .post("/api/login", Public, auth::login_api)
+        .put("/api/password", GameMaster, auth::change_password)
+        .get("/", GameMaster, fleet_api::dashboard)
+        .get("/api/devices", GameMaster, fleet_api::list_devices)
+        .post("/api/devices/refresh", GameMaster, fleet_api::refresh_devices)
+        .get("/api/media", GameMaster, fleet_api::list_media)
+        .post("/api/media", Admin, fleet_api::upload_media)
+        .delete("/api/media/{name}", Admin, fleet_api::delete_media)
+        .post("/api/push", Admin, fleet_api::push_media)
+        .post("/api/play", GameMaster, fleet_api::play_media)
+        .post("/api/reboot", Admin, fleet_api::reboot_devices)
+}
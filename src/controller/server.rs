@@ -0,0 +1,40 @@
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use log::{error, info};
+use crate::config::controller::ControllerConfiguration;
+use crate::controller::controller_state::ControllerState;
+use crate::controller::fleet::Fleet;
+use crate::controller::routes::build_controller_router;
+use crate::logging::logging_util::setup_logging;
+use crate::web_server::listener::bind_server;
+use crate::web_server::worker_pool::WorkerPool;
+
+/// Runs the binary as a fleet controller: a dashboard for every device on the network
+/// instead of a player, reader and library of its own.
+pub fn run(project_dir: PathBuf) {
+    let config = ControllerConfiguration::load(project_dir.join("config/Controller.yaml"));
+
+    setup_logging("controller").unwrap();
+    info!("Starting fleet controller!");
+
+    fs::create_dir_all(project_dir.join("files")).expect("Failed to create the controller's files dir");
+
+    let server = bind_server(&config.bind_address(), config.tls.as_ref(), &project_dir).unwrap_or_else(|e| {
+        error!("Failed to start controller on {}: {:?}", config.bind_address(), e);
+        panic!("Failed to start controller on {}: {:?}", config.bind_address(), e);
+    });
+
+    let fleet = Fleet::new(config.clone());
+    fleet.start_refresh_thread();
+
+    let http_workers = config.http_workers;
+    let state = Arc::new(ControllerState::new(project_dir, config, fleet));
+    let workers = WorkerPool::new(http_workers, Arc::new(build_controller_router()), state);
+
+    for request in server.incoming_requests() {
+        info!("Received request from {:?}: {:?}", request.remote_addr(), request);
+        workers.dispatch(request);
+    }
+}
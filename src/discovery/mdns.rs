@@ -0,0 +1,166 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use log::{error, info};
+use mdns_sd::{IfKind, ServiceDaemon, ServiceEvent, ServiceInfo};
+use serde::Serialize;
+use crate::config::setup::DeviceConfiguration;
+
+/// The DNS-SD service type every device advertises itself under.
+pub const SERVICE_TYPE: &str = "_clue-device._tcp.local.";
+
+const TXT_DEVICE_UUID: &str = "device_uuid";
+const TXT_NAME: &str = "name";
+const TXT_VERSION: &str = "version";
+const TXT_SCHEME: &str = "scheme";
+
+/// Which interfaces mDNS is sent and answered on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Interfaces {
+    /// Every non loopback interface, what devices on the network use
+    Network,
+    /// Only 127.0.0.1, keeps tests off the real network
+    LoopbackV4,
+}
+
+/// A device found on the network by [discover].
+#[derive(Debug, Clone, Serialize)]
+pub struct DiscoveredDevice {
+    pub device_uuid: String,
+    pub name: String,
+    pub version: String,
+    pub scheme: String,
+    pub addresses: Vec<IpAddr>,
+    pub port: u16,
+    /// Base url of the device's web interface, ipv4 addresses are preferred
+    pub url: Option<String>,
+}
+
+/// Keeps the device advertised for as long as it is alive.
+pub struct Advertiser {
+    daemon: ServiceDaemon,
+    fullname: String,
+}
+
+impl Advertiser {
+    /// Advertises the web interface as a `_clue-device._tcp` service named after the device uuid,
+    /// so two devices given the same friendly name never conflict.
+    pub fn start(config: &DeviceConfiguration, interfaces: Interfaces) -> Result<Advertiser, mdns_sd::Error> {
+        let daemon = new_daemon(interfaces)?;
+
+        let properties = [
+            (TXT_DEVICE_UUID, config.device_uuid.as_str()),
+            (TXT_NAME, config.device_name.as_str()),
+            (TXT_VERSION, env!("CARGO_PKG_VERSION")),
+            (TXT_SCHEME, config.scheme()),
+        ];
+        let host_name = format!("{}.local.", config.device_uuid);
+
+        // Listening on every address means advertising every address of the enabled interfaces
+        let service = if config.listen_address == "0.0.0.0" {
+            ServiceInfo::new(SERVICE_TYPE, &config.device_uuid, &host_name, "", config.port, &properties[..])?
+                .enable_addr_auto()
+        } else {
+            ServiceInfo::new(SERVICE_TYPE, &config.device_uuid, &host_name, config.listen_address.as_str(), config.port, &properties[..])?
+        };
+
+        let fullname = service.get_fullname().to_owned();
+        daemon.register(service)?;
+        info!("Advertising {} as {}", config.device_name, fullname);
+
+        Ok(Advertiser { daemon, fullname })
+    }
+}
+
+impl Drop for Advertiser {
+    fn drop(&mut self) {
+        if let Err(err) = self.daemon.unregister(&self.fullname) {
+            error!("Failed to stop advertising {}: {:?}", self.fullname, err);
+        }
+        let _ = self.daemon.shutdown();
+    }
+}
+
+/// Browses for devices for `timeout` and returns every one that answered, at most once per device uuid.
+pub fn discover(timeout: Duration, interfaces: Interfaces) -> Result<Vec<DiscoveredDevice>, mdns_sd::Error> {
+    let daemon = new_daemon(interfaces)?;
+    let receiver = daemon.browse(SERVICE_TYPE)?;
+
+    let mut devices = HashMap::new();
+    let deadline = Instant::now() + timeout;
+    while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+        match receiver.recv_timeout(remaining) {
+            Ok(ServiceEvent::ServiceResolved(service)) => {
+                if let Some(device) = to_device(&service) {
+                    devices.insert(device.device_uuid.clone(), device);
+                }
+            }
+            Ok(_) => {}
+            Err(_) => break,
+        }
+    }
+
+    let _ = daemon.stop_browse(SERVICE_TYPE);
+    let _ = daemon.shutdown();
+
+    let mut devices = devices.into_values().collect::<Vec<_>>();
+    devices.sort_by(|a, b| a.name.cmp(&b.name).then_with(|| a.device_uuid.cmp(&b.device_uuid)));
+    Ok(devices)
+}
+
+fn new_daemon(interfaces: Interfaces) -> Result<ServiceDaemon, mdns_sd::Error> {
+    let daemon = ServiceDaemon::new()?;
+    if interfaces == Interfaces::LoopbackV4 {
+        daemon.disable_interface(IfKind::All)?;
+        daemon.enable_interface(IfKind::LoopbackV4)?;
+    }
+    Ok(daemon)
+}
+
+fn to_device(service: &ServiceInfo) -> Option<DiscoveredDevice> {
+    let property = |key: &str| service.get_property_val_str(key).map(|value| value.to_owned());
+
+    let mut addresses = service.get_addresses().iter().copied().collect::<Vec<_>>();
+    addresses.sort();
+
+    let scheme = property(TXT_SCHEME).unwrap_or_else(|| "http".to_owned());
+    let port = service.get_port();
+    let url = addresses.iter().find(|address| address.is_ipv4()).or(addresses.first())
+        .map(|address| match address {
+            IpAddr::V4(address) => format!("{}://{}:{}", scheme, address, port),
+            IpAddr::V6(address) => format!("{}://[{}]:{}", scheme, address, port),
+        });
+
+    Some(DiscoveredDevice {
+        device_uuid: property(TXT_DEVICE_UUID)?,
+        name: property(TXT_NAME).unwrap_or_default(),
+        version: property(TXT_VERSION).unwrap_or_default(),
+        scheme,
+        addresses,
+        port,
+        url,
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn discovers_devices_advertised_on_loopback() {
+        let mut config = DeviceConfiguration::new();
+        config.device_name = "Study".to_owned();
+        config.port = 8123;
+
+        let _advertiser = Advertiser::start(&config, Interfaces::LoopbackV4).unwrap();
+        let devices = discover(Duration::from_secs(3), Interfaces::LoopbackV4).unwrap();
+
+        let device = devices.iter().find(|device| device.device_uuid == config.device_uuid)
+            .expect("advertised device was not discovered");
+        assert_eq!(device.name, "Study");
+        assert_eq!(device.version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(device.url.as_deref(), Some("http://127.0.0.1:8123"));
+    }
+}
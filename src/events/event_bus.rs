@@ -0,0 +1,145 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+
+use log::{debug, warn};
+use serde::Serialize;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum PlayerState {
+    Startup,
+    Idle,
+    Playing { media: String },
+    Pairing,
+}
+
+/// Something that happened on the device that other parts of the program or clients may care about.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum DeviceEvent {
+    PlayerState { player: PlayerState },
+    RfidWaiting { waiting: bool },
+    /// `media` is the first file a paired card plays, a paired card may play none
+    CardScanned { card_id: String, uid: String, paired: bool, media: Option<String> },
+    CardPaired { card_id: String, uid: String, media: String },
+    UploadCompleted { files: Vec<String> },
+    /// Published once everything has started
+    Booted { version: String },
+}
+
+impl DeviceEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            DeviceEvent::PlayerState { .. } => "player_state",
+            DeviceEvent::RfidWaiting { .. } => "rfid_waiting",
+            DeviceEvent::CardScanned { .. } => "card_scanned",
+            DeviceEvent::CardPaired { .. } => "card_paired",
+            DeviceEvent::UploadCompleted { .. } => "upload_completed",
+            DeviceEvent::Booted { .. } => "booted",
+        }
+    }
+
+    /// State events describe how the device is right now rather than something that happened,
+    /// the latest one of each is replayed to new subscribers.
+    fn is_state(&self) -> bool {
+        matches!(self, DeviceEvent::PlayerState { .. } | DeviceEvent::RfidWaiting { .. })
+    }
+}
+
+/// Events waiting per subscriber, new events are dropped for a subscriber while it is this far behind.
+const QUEUE_SIZE: usize = 64;
+
+struct Subscriber {
+    sender: SyncSender<DeviceEvent>,
+    /// Set while events are being dropped so that is only logged once.
+    lagging: bool,
+}
+
+/// Fans every published event out to all current subscribers.
+#[derive(Default)]
+pub struct EventBus {
+    subscribers: Mutex<Vec<Subscriber>>,
+    retained: Mutex<HashMap<&'static str, DeviceEvent>>,
+}
+
+impl EventBus {
+    pub fn new() -> EventBus {
+        EventBus::default()
+    }
+
+    pub fn publish(&self, event: DeviceEvent) {
+        debug!("Publishing event: {:?}", event);
+        if event.is_state() {
+            self.retained.lock().unwrap().insert(event.name(), event.clone());
+        }
+
+        // Subscribers that hung up are dropped here
+        self.subscribers.lock().unwrap().retain_mut(|subscriber| match subscriber.sender.try_send(event.clone()) {
+            Ok(()) => {
+                subscriber.lagging = false;
+                true
+            }
+            Err(TrySendError::Full(_)) => {
+                if !subscriber.lagging {
+                    warn!("Dropping events for a subscriber that is {} events behind", QUEUE_SIZE);
+                    subscriber.lagging = true;
+                }
+                true
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        });
+    }
+
+    /// Returns a receiver for every event published from now on, starting with the current state.
+    pub fn subscribe(&self) -> Receiver<DeviceEvent> {
+        let (sender, receiver) = sync_channel(QUEUE_SIZE);
+        for event in self.retained.lock().unwrap().values() {
+            let _ = sender.try_send(event.clone());
+        }
+        self.subscribers.lock().unwrap().push(Subscriber { sender, lagging: false });
+        receiver
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn booted(version: usize) -> DeviceEvent {
+        DeviceEvent::Booted { version: version.to_string() }
+    }
+
+    #[test]
+    fn new_subscribers_get_the_current_state() {
+        let events = EventBus::new();
+        events.publish(DeviceEvent::RfidWaiting { waiting: true });
+        events.publish(DeviceEvent::RfidWaiting { waiting: false });
+        events.publish(booted(1));
+
+        let receiver = events.subscribe();
+        assert_eq!(receiver.try_iter().collect::<Vec<_>>(), vec![DeviceEvent::RfidWaiting { waiting: false }]);
+    }
+
+    #[test]
+    fn slow_subscribers_miss_events_without_holding_up_others() {
+        let events = EventBus::new();
+        let slow = events.subscribe();
+        let fast = events.subscribe();
+        let gone = events.subscribe();
+        drop(gone);
+
+        for version in 0..QUEUE_SIZE + 10 {
+            events.publish(booted(version));
+            assert_eq!(fast.try_recv(), Ok(booted(version)));
+        }
+        assert_eq!(events.subscribers.lock().unwrap().len(), 2);
+        assert!(events.subscribers.lock().unwrap()[0].lagging);
+
+        // The slow subscriber keeps the oldest events and gets new ones again once it catches up
+        assert_eq!(slow.try_iter().collect::<Vec<_>>(), (0..QUEUE_SIZE).map(booted).collect::<Vec<_>>());
+        events.publish(booted(1000));
+        assert_eq!(slow.try_recv(), Ok(booted(1000)));
+        assert!(!events.subscribers.lock().unwrap()[0].lagging);
+    }
+}
@@ -0,0 +1,155 @@
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{error, info, warn};
+use crate::config::setup::ButtonConfiguration;
+use crate::gpio::pins::{open_input, InputLine};
+use crate::remote::remote_command::RemoteCommand;
+use crate::web_server::app_state::AppState;
+
+/// How often the inputs are read, well below any sensible debounce time.
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// Only reports a new level once the input has held it for the whole debounce time.
+struct Debouncer {
+    stable: bool,
+    candidate: bool,
+    changed: Instant,
+    debounce: Duration,
+}
+
+impl Debouncer {
+    fn new(level: bool, debounce: Duration, now: Instant) -> Debouncer {
+        Debouncer { stable: level, candidate: level, changed: now, debounce }
+    }
+
+    /// The new level when `level` has just become stable.
+    fn update(&mut self, level: bool, now: Instant) -> Option<bool> {
+        if level != self.candidate {
+            self.candidate = level;
+            self.changed = now;
+            return None;
+        }
+        if level != self.stable && now.duration_since(self.changed) >= self.debounce {
+            self.stable = level;
+            return Some(level);
+        }
+        None
+    }
+}
+
+/// An input that runs its command every time it is pressed.
+pub struct Button {
+    pin: u64,
+    command: RemoteCommand,
+    input: Box<dyn InputLine>,
+    debouncer: Debouncer,
+}
+
+impl Button {
+    pub fn new(config: &ButtonConfiguration, input: Box<dyn InputLine>) -> Result<Button, String> {
+        let command = RemoteCommand::parse(&config.command, config.media.as_deref())?;
+        // A switch that is already closed at boot should not fire until it is opened and closed again
+        let level = input.is_active().map_err(|err| err.to_string())?;
+        let debouncer = Debouncer::new(level, Duration::from_millis(config.debounce), Instant::now());
+        Ok(Button { pin: config.pin, command, input, debouncer })
+    }
+
+    /// Whether the button has just been pressed.
+    fn pressed(&mut self, now: Instant) -> bool {
+        match self.input.is_active() {
+            Ok(level) => self.debouncer.update(level, now) == Some(true),
+            Err(err) => {
+                error!("Failed to read gpio {}: {}", self.pin, err);
+                false
+            }
+        }
+    }
+}
+
+/// Polls every button on one thread, running the command of each press with `execute`.
+pub fn start_buttons<F>(mut buttons: Vec<Button>, execute: F)
+where F: Fn(&RemoteCommand) -> Result<String, String> + Send + 'static {
+    if buttons.is_empty() {
+        return;
+    }
+    thread::spawn(move || loop {
+        let now = Instant::now();
+        for button in buttons.iter_mut() {
+            if !button.pressed(now) {
+                continue;
+            }
+            match execute(&button.command) {
+                Ok(result) => info!("Button on gpio {} pressed: {}", button.pin, result),
+                Err(err) => warn!("Button on gpio {} pressed but failed: {}", button.pin, err),
+            }
+        }
+        thread::sleep(POLL_INTERVAL);
+    });
+}
+
+/// Opens every configured button, skipping the ones that can't be used, and runs their commands against `state`.
+pub fn start_device_buttons(configs: &[ButtonConfiguration], state: Arc<AppState>) {
+    let buttons = configs.iter()
+        .filter_map(|config| {
+            let input = open_input(config.pin, config.active_low).map_err(|err| err.to_string());
+            match input.and_then(|input| Button::new(config, input)) {
+                Ok(button) => {
+                    info!("Button on gpio {} runs {:?}", config.pin, button.command);
+                    Some(button)
+                }
+                Err(err) => {
+                    error!("Ignoring button on gpio {}: {}", config.pin, err);
+                    None
+                }
+            }
+        })
+        .collect();
+    start_buttons(buttons, move |command| command.execute(&state));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::channel;
+    use crate::gpio::pins::SimulatedInput;
+    use super::*;
+
+    #[test]
+    fn ignores_bounces_shorter_than_the_debounce_time() {
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+        let mut debouncer = Debouncer::new(false, Duration::from_millis(20), start);
+
+        assert_eq!(debouncer.update(true, at(1)), None);
+        assert_eq!(debouncer.update(false, at(5)), None);
+        assert_eq!(debouncer.update(true, at(10)), None);
+        assert_eq!(debouncer.update(true, at(25)), None);
+        assert_eq!(debouncer.update(true, at(30)), Some(true));
+        assert_eq!(debouncer.update(true, at(40)), None);
+        assert_eq!(debouncer.update(false, at(45)), None);
+        assert_eq!(debouncer.update(false, at(70)), Some(false));
+    }
+
+    #[test]
+    fn runs_the_command_of_each_press() {
+        let config = ButtonConfiguration { pin: 17, command: "play".to_owned(), media: Some("clue.mp4".to_owned()), active_low: true, debounce: 10 };
+        let input = SimulatedInput::default();
+        let (commands, executed) = channel();
+        start_buttons(vec![Button::new(&config, Box::new(input.clone())).unwrap()], move |command| {
+            commands.send(command.clone()).unwrap();
+            Ok("Playing clue.mp4".to_owned())
+        });
+
+        for _ in 0..2 {
+            input.set_active(true);
+            assert_eq!(executed.recv_timeout(Duration::from_secs(5)).unwrap(), RemoteCommand::Play("clue.mp4".to_owned()));
+            input.set_active(false);
+            thread::sleep(Duration::from_millis(50));
+        }
+        assert!(executed.try_recv().is_err());
+
+        let missing_media = ButtonConfiguration { media: None, ..config };
+        assert!(Button::new(&missing_media, Box::new(input)).is_err());
+    }
+}
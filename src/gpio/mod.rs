@@ -0,0 +1,3 @@
+pub mod pins;
+pub mod buttons;
+pub mod outputs;
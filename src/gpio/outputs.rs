@@ -0,0 +1,174 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use crate::config::setup::OutputConfiguration;
+use crate::gpio::pins::{open_output, OutputLine};
+
+/// What a card scan or the web interface does to an output.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum OutputMode {
+    /// On for `millis` and off again, like popping a maglock
+    Pulse { millis: u64 },
+    /// On until released
+    Latch,
+    Toggle,
+    /// Off, ending a latch or a pulse early
+    Release,
+}
+
+/// An output a card switches when it is scanned.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OutputAction {
+    pub output: String,
+    #[serde(flatten)]
+    pub mode: OutputMode,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OutputStatus {
+    pub name: String,
+    pub pin: u64,
+    pub active: bool,
+}
+
+struct Output {
+    pin: u64,
+    line: Box<dyn OutputLine>,
+    active: bool,
+    /// Bumped by every change so a pulse only switches off the output it switched on
+    generation: u64,
+}
+
+impl Output {
+    fn set(&mut self, active: bool) -> Result<u64, String> {
+        self.line.set_active(active).map_err(|err| err.to_string())?;
+        self.active = active;
+        self.generation += 1;
+        Ok(self.generation)
+    }
+}
+
+/// The relays and lights wired to the device, by name.
+pub struct Outputs {
+    outputs: BTreeMap<String, Arc<Mutex<Output>>>,
+}
+
+impl Outputs {
+    pub fn new(lines: Vec<(OutputConfiguration, Box<dyn OutputLine>)>) -> Outputs {
+        let outputs = lines.into_iter()
+            .map(|(config, line)| {
+                let output = Output { pin: config.pin, line, active: false, generation: 0 };
+                (config.name, Arc::new(Mutex::new(output)))
+            })
+            .collect();
+        Outputs { outputs }
+    }
+
+    /// Opens every configured output, skipping the ones that can't be used.
+    pub fn open(configs: &[OutputConfiguration]) -> Outputs {
+        let lines = configs.iter()
+            .filter_map(|config| match open_output(config.pin, config.active_low) {
+                Ok(line) => {
+                    info!("Output {} on gpio {}", config.name, config.pin);
+                    Some((config.clone(), line))
+                }
+                Err(err) => {
+                    error!("Ignoring output {} on gpio {}: {}", config.name, config.pin, err);
+                    None
+                }
+            })
+            .collect();
+        Outputs::new(lines)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.outputs.contains_key(name)
+    }
+
+    pub fn list(&self) -> Vec<OutputStatus> {
+        self.outputs.iter()
+            .map(|(name, output)| {
+                let output = output.lock().unwrap();
+                OutputStatus { name: name.clone(), pin: output.pin, active: output.active }
+            })
+            .collect()
+    }
+
+    /// Switches the output, a pulse returns as soon as the output is on.
+    pub fn run(&self, action: &OutputAction) -> Result<String, String> {
+        let output = self.outputs.get(&action.output).ok_or(format!("There is no output named {}", action.output))?;
+        let mut state = output.lock().unwrap();
+        match action.mode {
+            OutputMode::Pulse { millis } => {
+                let generation = state.set(true)?;
+                let output = output.clone();
+                let name = action.output.clone();
+                thread::spawn(move || {
+                    thread::sleep(Duration::from_millis(millis));
+                    let mut state = output.lock().unwrap();
+                    if state.generation == generation {
+                        if let Err(err) = state.set(false) {
+                            error!("Failed to end pulse of output {}: {}", name, err);
+                        }
+                    }
+                });
+                Ok(format!("Pulsed {} for {}ms", action.output, millis))
+            }
+            OutputMode::Latch => state.set(true).map(|_| format!("Latched {}", action.output)),
+            OutputMode::Toggle => {
+                let active = !state.active;
+                state.set(active).map(|_| format!("Turned {} {}", action.output, if active { "on" } else { "off" }))
+            }
+            OutputMode::Release => state.set(false).map(|_| format!("Released {}", action.output)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::gpio::pins::SimulatedOutput;
+    use super::*;
+
+    fn maglock() -> (Outputs, SimulatedOutput) {
+        let line = SimulatedOutput::default();
+        let config = OutputConfiguration { name: "maglock".to_owned(), pin: 23, active_low: false };
+        (Outputs::new(vec![(config, Box::new(line.clone()))]), line)
+    }
+
+    fn action(mode: OutputMode) -> OutputAction {
+        OutputAction { output: "maglock".to_owned(), mode }
+    }
+
+    #[test]
+    fn latches_toggles_and_releases() {
+        let (outputs, line) = maglock();
+        outputs.run(&action(OutputMode::Latch)).unwrap();
+        assert!(line.is_active());
+        outputs.run(&action(OutputMode::Toggle)).unwrap();
+        assert!(!line.is_active());
+        outputs.run(&action(OutputMode::Toggle)).unwrap();
+        assert!(outputs.list()[0].active);
+        outputs.run(&action(OutputMode::Release)).unwrap();
+        assert!(!line.is_active());
+        assert!(outputs.run(&OutputAction { output: "light".to_owned(), mode: OutputMode::Latch }).is_err());
+    }
+
+    #[test]
+    fn a_pulse_does_not_end_a_later_latch() {
+        let (outputs, line) = maglock();
+        outputs.run(&action(OutputMode::Pulse { millis: 20 })).unwrap();
+        assert!(line.is_active());
+        thread::sleep(Duration::from_millis(100));
+        assert!(!line.is_active());
+
+        outputs.run(&action(OutputMode::Pulse { millis: 20 })).unwrap();
+        outputs.run(&action(OutputMode::Latch)).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        assert!(line.is_active());
+    }
+}
@@ -0,0 +1,153 @@
+use std::error::Error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use linux_embedded_hal::sysfs_gpio;
+use linux_embedded_hal::sysfs_gpio::Direction;
+use linux_embedded_hal::Pin;
+use log::warn;
+use crate::gpio::pins::GpioError::PinError;
+use crate::rfid::rfid_manger::is_raspberry_pi;
+
+/// A gpio input, a sysfs pin on the Pi and a simulated one everywhere else.
+pub trait InputLine: Send {
+    /// Whether the input is pressed or closed, already accounting for active low wiring.
+    fn is_active(&self) -> Result<bool, GpioError>;
+}
+
+/// A gpio output, a sysfs pin on the Pi and a simulated one everywhere else.
+pub trait OutputLine: Send {
+    /// Switches whatever is wired to the output on or off, already accounting for active low wiring.
+    fn set_active(&self, active: bool) -> Result<(), GpioError>;
+}
+
+/// Opens `pin` as an input, falling back to a simulated input that is never pressed when not running on a Pi.
+pub fn open_input(pin: u64, active_low: bool) -> Result<Box<dyn InputLine>, GpioError> {
+    if !is_raspberry_pi() {
+        warn!("Not running on a Raspberry Pi, gpio {} is simulated", pin);
+        return Ok(Box::new(SimulatedInput::default()));
+    }
+    Ok(Box::new(SysfsInput::open(pin, active_low)?))
+}
+
+/// Opens `pin` as an output that starts off, falling back to a simulated output when not running on a Pi.
+pub fn open_output(pin: u64, active_low: bool) -> Result<Box<dyn OutputLine>, GpioError> {
+    if !is_raspberry_pi() {
+        warn!("Not running on a Raspberry Pi, gpio {} is simulated", pin);
+        return Ok(Box::new(SimulatedOutput::default()));
+    }
+    Ok(Box::new(SysfsOutput::open(pin, active_low)?))
+}
+
+/// Exports `number` and waits for the sysfs files to become usable.
+fn export(number: u64) -> Result<Pin, GpioError> {
+    let pin = Pin::new(number);
+    pin.export()?;
+    while !pin.is_exported() {}
+    // Same as the rfid chip select, udev needs a moment to hand the new files to the gpio group
+    thread::sleep(Duration::from_millis(25));
+    Ok(pin)
+}
+
+pub struct SysfsInput {
+    pin: Pin,
+}
+
+impl SysfsInput {
+    pub fn open(number: u64, active_low: bool) -> Result<SysfsInput, GpioError> {
+        let pin = export(number)?;
+        pin.set_direction(Direction::In)?;
+        pin.set_active_low(active_low)?;
+        Ok(SysfsInput { pin })
+    }
+}
+
+impl InputLine for SysfsInput {
+    fn is_active(&self) -> Result<bool, GpioError> {
+        Ok(self.pin.get_value()? != 0)
+    }
+}
+
+pub struct SysfsOutput {
+    pin: Pin,
+}
+
+impl SysfsOutput {
+    pub fn open(number: u64, active_low: bool) -> Result<SysfsOutput, GpioError> {
+        let pin = export(number)?;
+        pin.set_active_low(active_low)?;
+        // The initial level ignores active low, an active low relay has to start high to stay off
+        pin.set_direction(if active_low { Direction::High } else { Direction::Low })?;
+        Ok(SysfsOutput { pin })
+    }
+}
+
+impl OutputLine for SysfsOutput {
+    fn set_active(&self, active: bool) -> Result<(), GpioError> {
+        Ok(self.pin.set_value(u8::from(active))?)
+    }
+}
+
+/// An input set from code, clones share the same level.
+#[derive(Debug, Clone, Default)]
+pub struct SimulatedInput {
+    active: Arc<AtomicBool>,
+}
+
+impl SimulatedInput {
+    #[cfg(test)]
+    pub fn set_active(&self, active: bool) {
+        self.active.store(active, Ordering::SeqCst);
+    }
+}
+
+impl InputLine for SimulatedInput {
+    fn is_active(&self) -> Result<bool, GpioError> {
+        Ok(self.active.load(Ordering::SeqCst))
+    }
+}
+
+/// An output that only remembers its level, clones share the same level.
+#[derive(Debug, Clone, Default)]
+pub struct SimulatedOutput {
+    active: Arc<AtomicBool>,
+}
+
+impl SimulatedOutput {
+    #[cfg(test)]
+    pub fn is_active(&self) -> bool {
+        self.active.load(Ordering::SeqCst)
+    }
+}
+
+impl OutputLine for SimulatedOutput {
+    fn set_active(&self, active: bool) -> Result<(), GpioError> {
+        self.active.store(active, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum GpioError {
+    PinError(sysfs_gpio::Error),
+}
+
+impl Display for GpioError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            PinError(error) => {write!(f, "Gpio operation failed: {}", error)}
+        }
+    }
+}
+
+impl From<sysfs_gpio::Error> for GpioError {
+    fn from(error: sysfs_gpio::Error) -> Self {
+        PinError(error)
+    }
+}
+
+impl Error for GpioError {}
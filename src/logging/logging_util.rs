@@ -5,17 +5,15 @@ use log::{error};
 use qoollo_log4rs_logstash::config::DeserializersExt;
 use serde_json::Value;
 
-use crate::config::setup::DeviceConfiguration;
-
-pub fn setup_logging(device_config: &DeviceConfiguration) -> Result<(),  Box<dyn std::error::Error>> {
-    let device_id = device_config.device_uuid.clone();
-    let binding = include_str!("../../config/log4rs.yaml").replace("{device_id}", device_id.as_str());
+/// Logs are tagged with `device_id`, the device uuid or `controller` for the fleet controller.
+pub fn setup_logging(device_id: &str) -> Result<(),  Box<dyn std::error::Error>> {
+    let binding = include_str!("../../config/log4rs.yaml").replace("{device_id}", device_id);
     let config_str = binding.as_str();
     let config: RawConfig = serde_yaml::from_str(config_str).unwrap();
 
     let mut data:  HashMap<String, Value>  = HashMap::new();
 
-    data.insert("deviceId".to_string(), Value::String(device_config.device_uuid.clone()));
+    data.insert("deviceId".to_string(), Value::String(device_id.to_owned()));
 
     let (appenders, errors) = config.appenders_lossy(&log4rs::config::Deserializers::default().with_logstash_extra(data));
     if !errors.is_empty() {
@@ -6,182 +6,115 @@ mod rfid;
 mod web_server;
 mod config;
 mod logging;
-
-use std::{fs, io};
-use std::env::current_dir;
-use std::fs::{File};
-use std::process::Command;
-
-
-
-use multipart::server::{Multipart};
-use tera::{Context, Tera};
-use tiny_http::{Server, Method, Header, StatusCode, Response};
-use log::{debug, error, info, warn};
-use url::form_urlencoded;
+mod events;
+mod metrics;
+mod discovery;
+mod controller;
+mod backup;
+mod webhooks;
+mod remote;
+mod mqtt;
+mod osc;
+mod gpio;
+
+use std::env::{args, current_dir};
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{debug, error, info};
 use crate::config::setup::DeviceConfiguration;
+use crate::discovery::mdns::{discover, Advertiser, Interfaces};
+use crate::events::event_bus::{DeviceEvent, EventBus};
+use crate::gpio::buttons::start_device_buttons;
+use crate::gpio::outputs::Outputs;
 use crate::logging::logging_util::setup_logging;
-use crate::rfid::rfid_manger::{is_raspberry_pi, Rfid};
+use crate::metrics::device_metrics::Metrics;
+use crate::mqtt::mqtt_client::start_device_bridge;
+use crate::osc::osc_listener::start_device_listener;
+use crate::rfid::rfid_manger::Rfid;
+use crate::rfid::sequencer::Sequencer;
 
 use crate::video_handler::media_manager::VlcManager;
-use crate::web_server::file_action_handler::{route_action_form};
+use crate::web_server::api::DeviceIdentity;
+use crate::web_server::app_state::AppState;
+use crate::web_server::listener::bind_server;
+use crate::web_server::routes::build_router;
+use crate::web_server::worker_pool::WorkerPool;
+use crate::webhooks::webhook_dispatcher::{Webhooks, RETRY_DELAY};
 
 
 
 fn main() {
+    // `clue-device discover` lists the devices on the network as json instead of starting a device
+    if args().nth(1).as_deref() == Some("discover") {
+        let devices = discover(Duration::from_secs(3), Interfaces::Network).expect("Failed to browse for devices");
+        println!("{}", serde_json::to_string_pretty(&devices).unwrap());
+        return;
+    }
+
     let project_dir = current_dir().unwrap();
 
+    // `clue-device controller` manages the other devices instead of being one
+    if args().nth(1).as_deref() == Some("controller") {
+        controller::server::run(project_dir);
+        return;
+    }
+
     let dev_config = DeviceConfiguration::load(project_dir.join("config/Config.yaml"));
 
-    setup_logging(&dev_config).unwrap();
+    setup_logging(&dev_config.device_uuid).unwrap();
     info!("Starting Server!");
 
-    let server = Server::http("0.0.0.0:8000").unwrap();
+    let server = bind_server(&dev_config.bind_address(), dev_config.tls.as_ref(), &project_dir).unwrap_or_else(|e| {
+        error!("Failed to start web server on {}: {:?}", dev_config.bind_address(), e);
+        panic!("Failed to start web server on {}: {:?}", dev_config.bind_address(), e);
+    });
+
+    // Kept alive for as long as the server runs, dropping it stops the advertisement
+    let _advertiser = Advertiser::start(&dev_config, Interfaces::Network).map_err(|e| {
+        error!("Failed to advertise device over mDNS: {:?}", e);
+    }).ok();
+
+    let events = Arc::new(EventBus::new());
+
+    let metrics = Arc::new(Metrics::new());
 
-    let media_manager = VlcManager::new();
+    // Listening before anything else starts so no event is missed
+    let webhooks = Arc::new(Webhooks::new(&dev_config.webhooks, DeviceIdentity::from_config(&dev_config), metrics.clone(), RETRY_DELAY));
+    webhooks.listen(&events);
 
-    let rfid = Rfid::new(media_manager.get_command_channel(), dev_config.clone());
+    let media_manager = VlcManager::new(events.clone(), metrics.clone(), &dev_config);
 
-    let mut tera = Tera::default();
+    let outputs = Arc::new(Outputs::open(&dev_config.outputs));
 
-    for mut request in server.incoming_requests() {
+    let sequencer = Arc::new(Sequencer::new(media_manager.get_command_channel(), project_dir.join("files"), outputs.clone(), webhooks));
+
+    let rfid = Rfid::new(media_manager.get_command_channel(), dev_config.clone(), sequencer, events.clone(), metrics.clone());
+
+    let http_workers = dev_config.http_workers;
+
+    let state = Arc::new(AppState::new(project_dir, dev_config, media_manager, rfid, outputs, events, metrics));
+
+    // Kept for as long as the device runs, like the advertiser
+    let _mqtt = state.config().mqtt.clone().map(|config| start_device_bridge(&config, state.clone()));
+    let _osc = state.config().osc.clone().and_then(|config| start_device_listener(&config, state.clone()).map_err(|e| {
+        error!("Failed to listen for osc on port {}: {:?}", config.port, e);
+    }).ok());
+    start_device_buttons(&state.config().buttons, state.clone());
+
+    state.events.publish(DeviceEvent::Booted { version: env!("CARGO_PKG_VERSION").to_owned() });
+
+    let workers = WorkerPool::new(http_workers, Arc::new(build_router()), state);
+
+    for request in server.incoming_requests() {
         debug!("received request! method: {:?}, url: {:?}, headers: {:?}",
              request.method(),
              request.url(),
              request.headers()
     );
-        info!("Received request from {}: {:?}", request.remote_addr().unwrap(), request);
-
-        match request.method() {
-            Method::Get => {
-                if request.url().contains("filename") {
-                    if let Some((_, filename)) = request.url().split_once('?'){
-                        info!("{}", filename.clone());
-                        let decoded_filename = form_urlencoded::parse(filename.as_bytes())
-                            .map(|(_, value)| value.into_owned())
-                            .collect::<Vec<String>>()
-                            .pop()
-                            .unwrap_or_else(|| String::new());
-                        info!("Decoded filename: {}", decoded_filename);
-
-                        let filepath = project_dir.join("files").join(decoded_filename);
-
-                        if !filepath.is_file() {
-                            error!("Could not find file");
-                            request.respond(Response::from_string("").with_status_code(400)).unwrap_or_else(|err|{
-                                error!("Failed to send failed status code: {:?}", err);
-                            });
-                            continue;
-                        }
-
-                        if let Ok(media) = File::open(filepath.clone()){
-                            info!("Sending file to client");
-                            request.respond(Response::from_file(media)).unwrap_or_else(|err|{
-                                error!("Failed to send file: {:?}", err)
-                            });
-                            info!("Sent file to client");
-                            continue;
-                        } else {
-                            error!("Failed to find file at path: {}", filepath.display())
-                        }
-
-                    }
-
-                }
-            },
-            Method::Post => {
-                match request.url() {
-                    "/upload" => {
-                        let boundary = request
-                            .headers()
-                            .iter()
-                            .find(|h| h.field.as_str() == "Content-Type")
-                            .map(|h| {
-                                let content_type = h.value.as_str();
-                                content_type.split("boundary=").last().unwrap().to_string()
-                            });
-
-                        let boundary = match boundary {
-                            Some(boundary) => boundary,
-                            None => {"".to_owned()},
-                        };
-                        let mut multipart = Multipart::with_body(request.as_reader(), &boundary);
-
-                        while let Ok(Some(mut field)) = multipart.read_entry() {
-                            let file_name = field
-                                .headers
-                                .filename
-                                .clone()
-                                .ok_or(io::Error::new(io::ErrorKind::InvalidInput, "No filename found"))
-                                .unwrap();
-
-                            let file_path = project_dir.clone().join("files").join(file_name);
-
-                            info!("Pulling file from client saving here: {}", file_path.as_path().to_str().unwrap());
-
-                            let mut file = File::create(&file_path).unwrap();
-                            io::copy(&mut field.data, &mut file).unwrap();
-                        }
-                    },
-                    "/action" => {
-                        match route_action_form(request, &media_manager, &rfid) {
-                            Ok(action) => {
-                                info!("Media Action Form routed successfully. Action preformed : {:?}", action)
-                            }
-                            Err(error) => {
-                                error!("Routing of action form failed because: {:?}", error)
-                            }
-                        }
-                        continue
-                    }
-                    "/reboot" => {
-                        if is_raspberry_pi() {
-                            info!("Rebooting...");
-                            request.respond(Response::from_string("")).unwrap();
-                            Command::new("sudo")
-                                .arg("reboot")
-                                .arg("-f")
-                                .output()
-                                .expect("failed to execute reboot command");
-                            panic!();
-                        }else {
-                            warn!("Did not reboot because it is not on a pi");
-                        }
-                    }
-                    &_ => {}
-                }
-            }
-            _ => {}
-        }
-
-        let contents = include_str!("../pages/index.html");
-
-        tera.add_raw_template("index.html", &contents).expect("TODO: panic message");
-
-        let paths = fs::read_dir(project_dir.join("files"))
-            .expect("Should Have been a files DIR")
-            .map(|entry| entry.unwrap().path().file_name().unwrap().to_str().unwrap().to_owned())
-            .filter(|item| (!item.contains(".png") || item.eq("idle.png")))
-            .collect::<Vec<_>>();
-
-        let mut context = Context::new();
-        context.insert("items", &paths);
-        context.insert("deviceId", &dev_config.device_uuid);
-
-
-        let rendered = tera.render("index.html", &context).unwrap();
-
-
-        let response = Response::new(
-            StatusCode(200),
-            vec![Header::from_bytes(&b"Content-Type"[..], &b"text/html"[..]).unwrap()],
-            rendered.as_bytes(),
-            None,
-            None
-        );
-        request.respond(response).expect("TODO: panic message");
+        info!("Received request from {:?}: {:?}", request.remote_addr(), request);
+
+        workers.dispatch(request);
     }
 
 }
-
-
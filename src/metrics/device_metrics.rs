@@ -0,0 +1,117 @@
+use std::path::Path;
+
+use log::error;
+use prometheus::{Encoder, IntCounter, IntCounterVec, IntGauge, Opts, Registry, TextEncoder};
+
+/// Counters and gauges exposed on `/metrics` in the Prometheus text format.
+///
+/// Created once at startup and shared with every thread that has something to count.
+pub struct Metrics {
+    registry: Registry,
+    pub card_scans: IntCounterVec,
+    pub card_pairings: IntCounter,
+    pub play_commands: IntCounter,
+    pub player_recreations: IntCounter,
+    pub rfid_reader_restarts: IntCounter,
+    pub rfid_lost_communication: IntCounter,
+    pub http_requests: IntCounterVec,
+    pub upload_bytes: IntCounter,
+    pub webhook_deliveries: IntCounterVec,
+    free_disk_bytes: IntGauge,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        let registry = Registry::new_custom(Some("clue".to_owned()), None).expect("Metrics prefix should be valid");
+
+        let metrics = Metrics {
+            card_scans: IntCounterVec::new(Opts::new("card_scans_total", "Cards read by the rfid reader"), &["result"]).unwrap(),
+            card_pairings: IntCounter::new("card_pairings_total", "Cards paired with media").unwrap(),
+            play_commands: IntCounter::new("play_commands_total", "Play commands received by the player").unwrap(),
+            player_recreations: IntCounter::new("player_recreations_total", "Times the mpv player was recreated after failing to load media").unwrap(),
+            rfid_reader_restarts: IntCounter::new("rfid_reader_restarts_total", "Times the rfid reader was restarted").unwrap(),
+            rfid_lost_communication: IntCounter::new("rfid_lost_communication_total", "Times communication with the rfid reader was lost").unwrap(),
+            http_requests: IntCounterVec::new(Opts::new("http_requests_total", "Http requests by route and response status"), &["route", "status"]).unwrap(),
+            upload_bytes: IntCounter::new("upload_bytes_total", "Bytes of media received through uploads").unwrap(),
+            webhook_deliveries: IntCounterVec::new(Opts::new("webhook_deliveries_total", "Webhook events by whether they were delivered, failed or dropped from a full queue"), &["result"]).unwrap(),
+            free_disk_bytes: IntGauge::new("free_disk_bytes", "Free space on the disk holding the media library").unwrap(),
+            registry,
+        };
+
+        let collectors: [Box<dyn prometheus::core::Collector>; 10] = [
+            Box::new(metrics.card_scans.clone()),
+            Box::new(metrics.card_pairings.clone()),
+            Box::new(metrics.play_commands.clone()),
+            Box::new(metrics.player_recreations.clone()),
+            Box::new(metrics.rfid_reader_restarts.clone()),
+            Box::new(metrics.rfid_lost_communication.clone()),
+            Box::new(metrics.http_requests.clone()),
+            Box::new(metrics.upload_bytes.clone()),
+            Box::new(metrics.webhook_deliveries.clone()),
+            Box::new(metrics.free_disk_bytes.clone()),
+        ];
+        for collector in collectors {
+            metrics.registry.register(collector).expect("Metrics should only be registered once");
+        }
+        // Export both scan results from the start so rates work before the first unknown card
+        for result in ["known", "unknown"] {
+            metrics.card_scans.with_label_values(&[result]);
+        }
+        metrics
+    }
+
+    pub fn card_scanned(&self, known: bool) {
+        self.card_scans.with_label_values(&[if known { "known" } else { "unknown" }]).inc();
+    }
+
+    pub fn webhook_delivery(&self, result: &str) {
+        self.webhook_deliveries.with_label_values(&[result]).inc();
+    }
+
+    pub fn http_request(&self, route: &str, status: u16) {
+        self.http_requests.with_label_values(&[route, &status.to_string()]).inc();
+    }
+
+    /// Renders every metric, measuring the free space of `files_dir` first.
+    pub fn render(&self, files_dir: &Path) -> String {
+        match fs2::available_space(files_dir) {
+            Ok(free) => self.free_disk_bytes.set(free as i64),
+            Err(err) => error!("Failed to read free disk space: {:?}", err),
+        }
+
+        let mut buffer = Vec::new();
+        if let Err(err) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            error!("Failed to encode metrics: {:?}", err);
+        }
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+    use super::*;
+
+    #[test]
+    fn renders_labelled_counters() {
+        let metrics = Metrics::new();
+        metrics.card_scanned(true);
+        metrics.card_scanned(true);
+        metrics.http_request("/api/media/{name}", 200);
+        metrics.http_request("unmatched", 404);
+
+        let dir = TempDir::new().unwrap();
+        let rendered = metrics.render(dir.path());
+        let lines = rendered.lines().collect::<Vec<_>>();
+        for line in [
+            "# TYPE clue_card_scans_total counter",
+            "clue_card_scans_total{result=\"known\"} 2",
+            "clue_card_scans_total{result=\"unknown\"} 0",
+            "clue_http_requests_total{route=\"/api/media/{name}\",status=\"200\"} 1",
+            "clue_http_requests_total{route=\"unmatched\",status=\"404\"} 1",
+        ] {
+            assert!(lines.contains(&line), "{} is missing from\n{}", line, rendered);
+        }
+        assert!(lines.iter().any(|line| line.starts_with("clue_free_disk_bytes ") && !line.ends_with(" 0")));
+    }
+}
@@ -0,0 +1 @@
+pub mod mqtt_client;
@@ -0,0 +1,273 @@
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use log::{debug, error, info, warn};
+use rumqttc::{Client, Event, LastWill, MqttOptions, Packet, QoS};
+use serde::Serialize;
+use serde_json::json;
+use crate::config::setup::MqttConfiguration;
+use crate::events::event_bus::{DeviceEvent, EventBus};
+use crate::remote::remote_command::RemoteCommand;
+use crate::web_server::app_state::AppState;
+use crate::web_server::health::check_health;
+
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+/// Wait before connecting again after the broker went away.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// Messages waiting to be sent, publishing fails instead of blocking once this many are waiting.
+const QUEUE_SIZE: usize = 64;
+
+/// The topics of one device, all under `<topic_prefix>/<device_uuid>`.
+#[derive(Debug, Clone)]
+struct Topics {
+    base: String,
+}
+
+impl Topics {
+    fn new(prefix: &str, device_uuid: &str) -> Topics {
+        Topics { base: format!("{}/{}", prefix.trim_end_matches('/'), device_uuid) }
+    }
+
+    /// Retained `online`, the broker replaces it with `offline` when the device disappears
+    fn status(&self) -> String {
+        format!("{}/status", self.base)
+    }
+
+    fn scan(&self) -> String {
+        format!("{}/scan", self.base)
+    }
+
+    fn player(&self) -> String {
+        format!("{}/player", self.base)
+    }
+
+    fn health(&self) -> String {
+        format!("{}/health", self.base)
+    }
+
+    /// Where the outcome of every command is published
+    fn result(&self) -> String {
+        format!("{}/result", self.base)
+    }
+
+    fn commands(&self) -> String {
+        format!("{}/command/+", self.base)
+    }
+
+    /// Name of the command `topic` is for, if it is one of ours.
+    fn command<'a>(&self, topic: &'a str) -> Option<&'a str> {
+        topic.strip_prefix(&self.base)?.strip_prefix("/command/")
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct CommandResult<'a> {
+    command: &'a str,
+    ok: bool,
+    message: String,
+}
+
+/// Publishes the device's scans, player state and health to an mqtt broker and runs the commands it receives.
+#[derive(Clone)]
+pub struct MqttBridge {
+    client: Client,
+    topics: Topics,
+}
+
+impl MqttBridge {
+    /// Connects in the background, reconnecting whenever the broker goes away. Every command published to
+    /// `<topic_prefix>/<device_uuid>/command/<play|idle|pair>` is run with `execute`, the payload naming the media.
+    pub fn start<F>(config: &MqttConfiguration, device_uuid: &str, execute: F) -> MqttBridge
+    where F: Fn(&RemoteCommand) -> Result<String, String> + Send + 'static {
+        let topics = Topics::new(&config.topic_prefix, device_uuid);
+        let mut options = MqttOptions::new(format!("clue-device-{}", device_uuid), &config.host, config.port);
+        options.set_keep_alive(KEEP_ALIVE);
+        options.set_last_will(LastWill::new(topics.status(), "offline", QoS::AtLeastOnce, true));
+        if let Some(username) = &config.username {
+            options.set_credentials(username, config.password.clone().unwrap_or_default());
+        }
+
+        let (client, mut connection) = Client::new(options, QUEUE_SIZE);
+        let bridge = MqttBridge { client, topics };
+        let connected = bridge.clone();
+        let broker = format!("{}:{}", config.host, config.port);
+        thread::spawn(move || {
+            // The connection has to keep being polled, so only the non blocking client calls are used in here
+            for notification in connection.iter() {
+                match notification {
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                        info!("Connected to mqtt broker {}", broker);
+                        if let Err(err) = connected.client.try_subscribe(connected.topics.commands(), QoS::AtLeastOnce) {
+                            error!("Failed to subscribe to mqtt commands: {:?}", err);
+                        }
+                        connected.publish(connected.topics.status(), true, "online");
+                    }
+                    Ok(Event::Incoming(Packet::Publish(publish))) => {
+                        if let Some(name) = connected.topics.command(&publish.topic) {
+                            let media = String::from_utf8_lossy(&publish.payload);
+                            connected.run_command(name, &media, &execute);
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(err) => {
+                        warn!("Mqtt connection to {} failed, reconnecting in {:?}: {}", broker, RECONNECT_DELAY, err);
+                        thread::sleep(RECONNECT_DELAY);
+                    }
+                }
+            }
+        });
+        bridge
+    }
+
+    /// Publishes scans and player changes as they happen from now on.
+    pub fn listen(&self, events: &EventBus) {
+        let receiver = events.subscribe();
+        let bridge = self.clone();
+        thread::spawn(move || {
+            for event in receiver {
+                bridge.publish_event(&event);
+            }
+        });
+    }
+
+    /// Publishes whatever `health` returns every `interval`.
+    pub fn start_health_thread<T: Serialize, F: Fn() -> T + Send + 'static>(&self, interval: Duration, health: F) {
+        let bridge = self.clone();
+        thread::spawn(move || loop {
+            bridge.publish_json(bridge.topics.health(), true, &health());
+            thread::sleep(interval);
+        });
+    }
+
+    fn publish_event(&self, event: &DeviceEvent) {
+        match event {
+            DeviceEvent::CardScanned { card_id, uid, paired, media } => {
+                self.publish_json(self.topics.scan(), false, &json!({ "card_id": card_id, "uid": uid, "paired": paired, "media": media }));
+            }
+            // Retained so anything that subscribes later still learns what is on screen
+            DeviceEvent::PlayerState { player } => self.publish_json(self.topics.player(), true, player),
+            _ => {}
+        }
+    }
+
+    fn run_command<F: Fn(&RemoteCommand) -> Result<String, String>>(&self, name: &str, media: &str, execute: &F) {
+        let result = RemoteCommand::parse(name, Some(media)).and_then(|command| execute(&command));
+        match &result {
+            Ok(message) => info!("Ran mqtt command {}: {}", name, message),
+            Err(message) => warn!("Mqtt command {} failed: {}", name, message),
+        }
+        let ok = result.is_ok();
+        let message = result.unwrap_or_else(|message| message);
+        self.publish_json(self.topics.result(), false, &CommandResult { command: name, ok, message });
+    }
+
+    fn publish_json<T: Serialize + ?Sized>(&self, topic: String, retain: bool, value: &T) {
+        match serde_json::to_vec(value) {
+            Ok(payload) => self.publish(topic, retain, payload),
+            Err(err) => error!("Failed to serialize mqtt message for {}: {:?}", topic, err),
+        }
+    }
+
+    fn publish<P: Into<Vec<u8>>>(&self, topic: String, retain: bool, payload: P) {
+        debug!("Publishing to {}", topic);
+        if let Err(err) = self.client.try_publish(&topic, QoS::AtLeastOnce, retain, payload) {
+            warn!("Dropped mqtt message for {}: {:?}", topic, err);
+        }
+    }
+}
+
+/// Connects the device to the broker in `config`, commands are run against `state`.
+pub fn start_device_bridge(config: &MqttConfiguration, state: Arc<AppState>) -> MqttBridge {
+    let device_uuid = state.config().device_uuid.clone();
+    let commands = state.clone();
+    let bridge = MqttBridge::start(config, &device_uuid, move |command| command.execute(&commands));
+    bridge.listen(&state.events);
+    bridge.start_health_thread(Duration::from_secs(config.health_interval), move || check_health(&state));
+    bridge
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc::channel;
+    use bytes::BytesMut;
+    use rumqttc::{ConnAck, ConnectReturnCode, PubAck, Publish, SubAck, SubscribeReasonCode};
+    use crate::events::event_bus::PlayerState;
+    use super::*;
+
+    /// Just enough of a broker to accept one client, send it a play command once it subscribes
+    /// and report everything it publishes.
+    fn start_broker() -> (u16, std::sync::mpsc::Receiver<(String, String)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (published, received) = channel();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buffer = BytesMut::new();
+            let mut chunk = [0; 4096];
+            loop {
+                let packet = match rumqttc::mqttbytes::v4::read(&mut buffer, 1024 * 1024) {
+                    Ok(packet) => packet,
+                    Err(_) => match stream.read(&mut chunk) {
+                        Ok(0) | Err(_) => return,
+                        Ok(read) => {
+                            buffer.extend_from_slice(&chunk[..read]);
+                            continue;
+                        }
+                    },
+                };
+
+                let mut reply = BytesMut::new();
+                match packet {
+                    Packet::Connect(_) => { ConnAck::new(ConnectReturnCode::Success, false).write(&mut reply).unwrap(); }
+                    Packet::Subscribe(subscribe) => {
+                        published.send(("subscribe".to_owned(), subscribe.filters[0].path.clone())).unwrap();
+                        SubAck::new(subscribe.pkid, vec![SubscribeReasonCode::Success(QoS::AtLeastOnce)]).write(&mut reply).unwrap();
+                        Publish::new("clue/device/command/play", QoS::AtMostOnce, "clue.mp4").write(&mut reply).unwrap();
+                    }
+                    Packet::Publish(publish) => {
+                        published.send((publish.topic.clone(), String::from_utf8_lossy(&publish.payload).into_owned())).unwrap();
+                        PubAck::new(publish.pkid).write(&mut reply).unwrap();
+                    }
+                    _ => {}
+                }
+                stream.write_all(&reply).unwrap();
+            }
+        });
+        (port, received)
+    }
+
+    #[test]
+    fn runs_commands_and_publishes_under_the_device_topic() {
+        let (port, published) = start_broker();
+        let config = MqttConfiguration {
+            host: "127.0.0.1".to_owned(),
+            port,
+            username: None,
+            password: None,
+            topic_prefix: "clue/".to_owned(),
+            health_interval: 30,
+        };
+        let (commands, executed) = channel();
+        let bridge = MqttBridge::start(&config, "device", move |command| {
+            commands.send(command.clone()).unwrap();
+            Ok("Playing clue.mp4".to_owned())
+        });
+
+        let next = || published.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(next(), ("subscribe".to_owned(), "clue/device/command/+".to_owned()));
+        assert_eq!(next(), ("clue/device/status".to_owned(), "online".to_owned()));
+        assert_eq!(executed.recv_timeout(Duration::from_secs(5)).unwrap(), RemoteCommand::Play("clue.mp4".to_owned()));
+        let (topic, result) = next();
+        assert_eq!(topic, "clue/device/result");
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&result).unwrap()["ok"], true);
+
+        bridge.publish_event(&DeviceEvent::RfidWaiting { waiting: true });
+        bridge.publish_event(&DeviceEvent::PlayerState { player: PlayerState::Playing { media: "clue.mp4".to_owned() } });
+        let (topic, player) = next();
+        assert_eq!(topic, "clue/device/player");
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&player).unwrap()["media"], "clue.mp4");
+    }
+}
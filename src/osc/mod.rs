@@ -0,0 +1,2 @@
+pub mod osc_message;
+pub mod osc_listener;
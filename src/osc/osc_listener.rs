@@ -0,0 +1,147 @@
+use std::io;
+use std::net::UdpSocket;
+use std::sync::Arc;
+use std::thread;
+
+use log::{debug, error, info, warn};
+use crate::config::setup::OscConfiguration;
+use crate::events::event_bus::{DeviceEvent, EventBus};
+use crate::osc::osc_message::{decode, OscArg, OscMessage};
+use crate::remote::remote_command::RemoteCommand;
+use crate::web_server::app_state::AppState;
+
+/// Largest osc packet that is read, anything longer is cut off and fails to decode.
+const MAX_PACKET_SIZE: usize = 64 * 1024;
+
+/// Runs `<address_prefix>/play|idle|pair` osc messages from show control software and
+/// optionally tells it about card scans.
+pub struct OscListener {
+    socket: UdpSocket,
+    prefix: String,
+}
+
+impl OscListener {
+    /// Binds the udp port and runs every command that arrives on it with `execute`,
+    /// the first argument of a message naming the media.
+    pub fn start<F>(listen_address: &str, config: &OscConfiguration, execute: F) -> io::Result<OscListener>
+    where F: Fn(&RemoteCommand) -> Result<String, String> + Send + 'static {
+        let socket = UdpSocket::bind(format!("{}:{}", listen_address, config.port))?;
+        info!("Listening for osc on {}", socket.local_addr()?);
+        let listener = OscListener { socket, prefix: config.address_prefix.trim_end_matches('/').to_owned() };
+
+        let receiving = listener.try_clone()?;
+        thread::spawn(move || {
+            let mut packet = vec![0; MAX_PACKET_SIZE];
+            loop {
+                match receiving.socket.recv_from(&mut packet) {
+                    Ok((size, sender)) => match decode(&packet[..size]) {
+                        Ok(messages) => messages.iter().for_each(|message| receiving.run_command(message, &execute)),
+                        Err(err) => warn!("Ignoring osc packet from {}: {}", sender, err),
+                    },
+                    Err(err) => {
+                        error!("Failed to receive osc packet, no longer listening: {:?}", err);
+                        return;
+                    }
+                }
+            }
+        });
+        Ok(listener)
+    }
+
+    /// Sends `<address_prefix>/scan <uid> <media>` for every paired card, leaving out the media of a card that
+    /// plays none, and `<address_prefix>/unknown <uid>` for every other card scanned from now on to `target`.
+    pub fn send_scans(&self, events: &EventBus, target: String) -> io::Result<()> {
+        let receiver = events.subscribe();
+        let sender = self.try_clone()?;
+        info!("Sending card scans over osc to {}", target);
+        thread::spawn(move || {
+            for event in receiver {
+                if let Some(message) = sender.scan_message(&event) {
+                    if let Err(err) = sender.socket.send_to(&message.encode(), &target) {
+                        warn!("Failed to send osc {} to {}: {:?}", message.address, target, err);
+                    }
+                }
+            }
+        });
+        Ok(())
+    }
+
+    fn try_clone(&self) -> io::Result<OscListener> {
+        Ok(OscListener { socket: self.socket.try_clone()?, prefix: self.prefix.clone() })
+    }
+
+    fn run_command<F: Fn(&RemoteCommand) -> Result<String, String>>(&self, message: &OscMessage, execute: &F) {
+        let name = match message.address.strip_prefix(&self.prefix).and_then(|name| name.strip_prefix('/')) {
+            Some(name) => name,
+            None => {
+                debug!("Ignoring osc message for {}", message.address);
+                return;
+            }
+        };
+
+        match RemoteCommand::parse(name, message.first_text().as_deref()).and_then(|command| execute(&command)) {
+            Ok(result) => info!("Ran osc command {}: {}", message.address, result),
+            Err(err) => warn!("Osc command {} failed: {}", message.address, err),
+        }
+    }
+
+    fn scan_message(&self, event: &DeviceEvent) -> Option<OscMessage> {
+        match event {
+            DeviceEvent::CardScanned { uid, paired: true, media, .. } => Some(OscMessage::new(
+                &format!("{}/scan", self.prefix),
+                std::iter::once(uid).chain(media).map(|arg| OscArg::Str(arg.clone())).collect(),
+            )),
+            DeviceEvent::CardScanned { uid, paired: false, .. } => Some(OscMessage::new(
+                &format!("{}/unknown", self.prefix),
+                vec![OscArg::Str(uid.clone())],
+            )),
+            _ => None,
+        }
+    }
+}
+
+/// Listens for osc next to the web interface, commands are run against `state`.
+pub fn start_device_listener(config: &OscConfiguration, state: Arc<AppState>) -> io::Result<OscListener> {
+    let listen_address = state.config().listen_address.clone();
+    let commands = state.clone();
+    let listener = OscListener::start(&listen_address, config, move |command| command.execute(&commands))?;
+    if let Some(target) = &config.send_scans_to {
+        listener.send_scans(&state.events, target.clone())?;
+    }
+    Ok(listener)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::channel;
+    use std::time::Duration;
+    use super::*;
+
+    #[test]
+    fn runs_commands_and_sends_scans() {
+        let config = OscConfiguration { port: 0, address_prefix: "/clue/".to_owned(), send_scans_to: None };
+        let (commands, executed) = channel();
+        let listener = OscListener::start("127.0.0.1", &config, move |command| {
+            commands.send(command.clone()).unwrap();
+            Ok("Playing clue.mp4".to_owned())
+        }).unwrap();
+
+        let show_control = UdpSocket::bind("127.0.0.1:0").unwrap();
+        show_control.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let device = listener.socket.local_addr().unwrap();
+        show_control.send_to(&OscMessage::new("/other/play", vec![OscArg::Str("a.mp4".to_owned())]).encode(), device).unwrap();
+        show_control.send_to(&OscMessage::new("/clue/play", vec![OscArg::Str("clue.mp4".to_owned())]).encode(), device).unwrap();
+        assert_eq!(executed.recv_timeout(Duration::from_secs(5)).unwrap(), RemoteCommand::Play("clue.mp4".to_owned()));
+
+        let events = EventBus::new();
+        listener.send_scans(&events, show_control.local_addr().unwrap().to_string()).unwrap();
+        events.publish(DeviceEvent::CardScanned { card_id: "card".to_owned(), uid: "0a0b".to_owned(), paired: false, media: None });
+        let mut packet = [0; 512];
+        let (size, _) = show_control.recv_from(&mut packet).unwrap();
+        assert_eq!(decode(&packet[..size]).unwrap(), vec![OscMessage::new("/clue/unknown", vec![OscArg::Str("0a0b".to_owned())])]);
+
+        events.publish(DeviceEvent::CardScanned { card_id: "card".to_owned(), uid: "0a0b".to_owned(), paired: true, media: None });
+        let (size, _) = show_control.recv_from(&mut packet).unwrap();
+        assert_eq!(decode(&packet[..size]).unwrap(), vec![OscMessage::new("/clue/scan", vec![OscArg::Str("0a0b".to_owned())])]);
+    }
+}
@@ -0,0 +1,171 @@
+use std::error::Error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+
+use crate::osc::osc_message::OscError::{Truncated, UnsupportedType};
+
+const BUNDLE_TAG: &str = "#bundle";
+
+/// An argument of an osc message, only the types show control software actually sends are understood.
+#[derive(Debug, Clone, PartialEq)]
+pub enum OscArg {
+    Int(i32),
+    Float(f32),
+    Str(String),
+}
+
+/// A single osc message, bundles are flattened into the messages they carry.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OscMessage {
+    pub address: String,
+    pub args: Vec<OscArg>,
+}
+
+impl OscMessage {
+    pub fn new(address: &str, args: Vec<OscArg>) -> OscMessage {
+        OscMessage { address: address.to_owned(), args }
+    }
+
+    /// The first argument as text, numbers are accepted so a cue can name media `3` without quoting it.
+    pub fn first_text(&self) -> Option<String> {
+        self.args.first().map(|arg| match arg {
+            OscArg::Int(value) => value.to_string(),
+            OscArg::Float(value) => value.to_string(),
+            OscArg::Str(value) => value.clone(),
+        })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut packet = Vec::new();
+        write_string(&mut packet, &self.address);
+        let tags = self.args.iter()
+            .map(|arg| match arg {
+                OscArg::Int(_) => 'i',
+                OscArg::Float(_) => 'f',
+                OscArg::Str(_) => 's',
+            })
+            .collect::<String>();
+        write_string(&mut packet, &format!(",{}", tags));
+        for arg in &self.args {
+            match arg {
+                OscArg::Int(value) => packet.extend_from_slice(&value.to_be_bytes()),
+                OscArg::Float(value) => packet.extend_from_slice(&value.to_be_bytes()),
+                OscArg::Str(value) => write_string(&mut packet, value),
+            }
+        }
+        packet
+    }
+}
+
+/// Reads every message in a udp packet, which is either one message or a bundle of them.
+pub fn decode(packet: &[u8]) -> Result<Vec<OscMessage>, OscError> {
+    let mut reader = Reader { packet, position: 0 };
+    let address = reader.string()?;
+    if address != BUNDLE_TAG {
+        return Ok(vec![reader.message(address)?]);
+    }
+
+    // Cues are run as soon as they arrive, so the time tag is skipped
+    reader.take(8)?;
+    let mut messages = Vec::new();
+    while reader.position < packet.len() {
+        let size = reader.int()?;
+        let element = reader.take(usize::try_from(size).map_err(|_| Truncated)?)?;
+        messages.extend(decode(element)?);
+    }
+    Ok(messages)
+}
+
+fn write_string(packet: &mut Vec<u8>, value: &str) {
+    packet.extend_from_slice(value.as_bytes());
+    // Always at least one nul, then padded to a multiple of four
+    let padding = 4 - value.len() % 4;
+    packet.extend(std::iter::repeat_n(0, padding));
+}
+
+struct Reader<'a> {
+    packet: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, size: usize) -> Result<&'a [u8], OscError> {
+        let end = self.position.checked_add(size).filter(|end| *end <= self.packet.len()).ok_or(Truncated)?;
+        let bytes = &self.packet[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn string(&mut self) -> Result<String, OscError> {
+        let rest = &self.packet[self.position..];
+        let length = rest.iter().position(|byte| *byte == 0).ok_or(Truncated)?;
+        let value = String::from_utf8_lossy(&rest[..length]).into_owned();
+        self.take((length / 4 + 1) * 4)?;
+        Ok(value)
+    }
+
+    fn int(&mut self) -> Result<i32, OscError> {
+        Ok(i32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn message(&mut self, address: String) -> Result<OscMessage, OscError> {
+        // Very old senders leave out the type tags, those messages can only be read without arguments
+        if self.position == self.packet.len() {
+            return Ok(OscMessage { address, args: Vec::new() });
+        }
+
+        let tags = self.string()?;
+        let mut args = Vec::new();
+        for tag in tags.chars().skip_while(|tag| *tag == ',') {
+            args.push(match tag {
+                'i' => OscArg::Int(self.int()?),
+                'f' => OscArg::Float(f32::from_be_bytes(self.take(4)?.try_into().unwrap())),
+                's' => OscArg::Str(self.string()?),
+                tag => return Err(UnsupportedType(tag)),
+            });
+        }
+        Ok(OscMessage { address, args })
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum OscError {
+    Truncated,
+    UnsupportedType(char),
+}
+
+impl Display for OscError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Truncated => {write!(f, "Osc packet is truncated")}
+            UnsupportedType(tag) => {write!(f, "Osc argument type {} is not supported", tag)}
+        }
+    }
+}
+
+impl Error for OscError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_messages_and_bundles() {
+        let play = OscMessage::new("/clue/play", vec![OscArg::Str("clue.mp4".to_owned()), OscArg::Int(3), OscArg::Float(0.5)]);
+        let idle = OscMessage::new("/clue/idle", Vec::new());
+        assert_eq!(decode(&play.encode()).unwrap(), vec![play.clone()]);
+
+        let mut bundle = Vec::new();
+        write_string(&mut bundle, BUNDLE_TAG);
+        bundle.extend_from_slice(&1u64.to_be_bytes());
+        for message in [&play, &idle] {
+            let encoded = message.encode();
+            bundle.extend_from_slice(&(encoded.len() as i32).to_be_bytes());
+            bundle.extend_from_slice(&encoded);
+        }
+        assert_eq!(decode(&bundle).unwrap(), vec![play.clone(), idle]);
+
+        let encoded = play.encode();
+        assert_eq!(decode(&encoded[..encoded.len() - 4]), Err(Truncated));
+    }
+}
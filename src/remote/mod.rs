@@ -0,0 +1 @@
+pub mod remote_command;
@@ -0,0 +1,48 @@
+use crate::video_handler::media_manager::Command;
+use crate::web_server::app_state::AppState;
+use crate::web_server::media_path::resolve_media_path;
+
+/// A player command received from a room automation system rather than the web interface.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RemoteCommand {
+    Play(String),
+    Idle,
+    Pair(String),
+}
+
+impl RemoteCommand {
+    /// Builds a command from its name and the media it names, if it takes one.
+    pub fn parse(name: &str, media: Option<&str>) -> Result<RemoteCommand, String> {
+        let media = || media.map(str::trim).filter(|media| !media.is_empty()).map(str::to_owned)
+            .ok_or(format!("{} needs the name of a media file", name));
+        match name {
+            "play" => Ok(RemoteCommand::Play(media()?)),
+            "idle" => Ok(RemoteCommand::Idle),
+            "pair" => Ok(RemoteCommand::Pair(media()?)),
+            _ => Err(format!("Unknown command {}, expected play, idle or pair", name)),
+        }
+    }
+
+    /// Hands the command to the player the same way the web interface does, describing what happened.
+    pub fn execute(&self, state: &AppState) -> Result<String, String> {
+        match self {
+            RemoteCommand::Play(name) => {
+                let media = resolve_media_path(&state.files_dir(), name).map_err(|err| err.to_string())?;
+                state.media_manager.send_command(Command::PlayMedia(media)).map_err(|_| "Media player is not running".to_owned())?;
+                Ok(format!("Playing {}", name))
+            }
+            RemoteCommand::Idle => {
+                state.media_manager.send_command(Command::Idle).map_err(|_| "Media player is not running".to_owned())?;
+                Ok("Showing the idle screen".to_owned())
+            }
+            RemoteCommand::Pair(name) => {
+                let media = resolve_media_path(&state.files_dir(), name).map_err(|err| err.to_string())?;
+                if state.rfid.is_waiting() {
+                    return Err("Rfid reader is still waiting on video to complete".to_owned());
+                }
+                state.rfid.pair_card(&media);
+                Ok(format!("Waiting for card to pair with {}", name))
+            }
+        }
+    }
+}
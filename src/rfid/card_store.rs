@@ -0,0 +1,274 @@
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::error;
+use serde::Serialize;
+use sled::{Db, IVec, Tree};
+use crate::gpio::outputs::OutputAction;
+use crate::rfid::sequencer::CardAction;
+use crate::web_server::media_path::resolve_media_path;
+
+const LAST_SCANNED_TREE: &str = "last_scanned";
+const OUTPUTS_TREE: &str = "outputs";
+
+/// The cards kept in the `card_database` and what they do when scanned.
+///
+/// Each paired card is stored in the default tree as card id → json list of its actions, media
+/// are named by their file name in the library. Cards paired before there were actions are stored
+/// as a bare media path and read as a card that only plays that file. The time a card was last
+/// scanned and the outputs it switches are kept next to it in their own trees.
+#[derive(Clone)]
+pub struct CardStore {
+    pairings: Db,
+    last_scanned: Tree,
+    outputs: Tree,
+    library: PathBuf,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PairedCard {
+    pub card_id: String,
+    /// The first file the card plays, empty when it plays none
+    pub media: String,
+    /// Where the first file is in the library, empty when it is missing
+    pub path: String,
+    /// Whether every file the card plays is still in the library
+    pub file_exists: bool,
+    /// Seconds since the unix epoch
+    pub last_scanned: Option<u64>,
+    pub outputs: Vec<OutputAction>,
+    pub actions: Vec<CardAction>,
+}
+
+impl CardStore {
+    /// Opens the database at `path` for the media library in `library`.
+    pub fn open(path: &Path, library: &Path) -> sled::Result<CardStore> {
+        let pairings = sled::open(path)?;
+        let last_scanned = pairings.open_tree(LAST_SCANNED_TREE)?;
+        let outputs = pairings.open_tree(OUTPUTS_TREE)?;
+        Ok(CardStore { pairings, last_scanned, outputs, library: library.to_path_buf() })
+    }
+
+    /// Pairs the card with a file in the library, replacing anything else it did.
+    pub fn pair(&self, card_id: &str, media: &Path) -> sled::Result<()> {
+        self.set_actions(card_id, &[CardAction::PlayMedia { media: media_name(media) }])
+    }
+
+    /// Points the first file the card plays at `media`, keeping the rest of its actions.
+    pub fn reassign(&self, card_id: &str, media: &Path) -> sled::Result<()> {
+        let mut actions = self.actions(card_id)?.unwrap_or_default();
+        let media = media_name(media);
+        match actions.iter_mut().find_map(|action| match action {
+            CardAction::PlayMedia { media } => Some(media),
+            _ => None,
+        }) {
+            Some(first) => *first = media,
+            None => actions.insert(0, CardAction::PlayMedia { media }),
+        }
+        self.set_actions(card_id, &actions)
+    }
+
+    /// The actions of a paired card, in order.
+    pub fn actions(&self, card_id: &str) -> sled::Result<Option<Vec<CardAction>>> {
+        Ok(self.pairings.get(card_id)?.map(|value| to_actions(card_id, &value)))
+    }
+
+    /// Replaces the actions of the card, pairing it if it wasn't.
+    pub fn set_actions(&self, card_id: &str, actions: &[CardAction]) -> sled::Result<()> {
+        // Serializing a list of plain enums and strings can't fail
+        self.pairings.insert(card_id, serde_json::to_vec(actions).unwrap())?;
+        Ok(())
+    }
+
+    pub fn is_paired(&self, card_id: &str) -> sled::Result<bool> {
+        self.pairings.contains_key(card_id)
+    }
+    pub fn record_scan(&self, card_id: &str) -> sled::Result<()> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        self.last_scanned.insert(card_id, &now.to_be_bytes())?;
+        Ok(())
+    }
+
+    /// The outputs the card switches when it is scanned, in order.
+    pub fn outputs(&self, card_id: &str) -> sled::Result<Vec<OutputAction>> {
+        Ok(self.outputs.get(card_id)?.map(|value| to_outputs(card_id, &value)).unwrap_or_default())
+    }
+
+    /// Replaces the outputs the card switches, none removes them.
+    pub fn set_outputs(&self, card_id: &str, outputs: &[OutputAction]) -> sled::Result<()> {
+        if outputs.is_empty() {
+            self.outputs.remove(card_id)?;
+        } else {
+            // Serializing a list of plain enums and strings can't fail
+            self.outputs.insert(card_id, serde_json::to_vec(outputs).unwrap())?;
+        }
+        Ok(())
+    }
+
+    /// Returns false when the card was not paired.
+    pub fn unpair(&self, card_id: &str) -> sled::Result<bool> {
+        self.last_scanned.remove(card_id)?;
+        self.outputs.remove(card_id)?;
+        Ok(self.pairings.remove(card_id)?.is_some())
+    }
+
+    /// Removes every pairing `filter` accepts, returning the ids that were removed.
+    pub fn clear<F: Fn(&PairedCard) -> bool>(&self, filter: F) -> sled::Result<Vec<String>> {
+        let mut removed = Vec::new();
+        for card in self.list()?.iter().filter(|card| filter(card)) {
+            if self.unpair(&card.card_id)? {
+                removed.push(card.card_id.clone());
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Number of paired cards, reading it also checks the database can still be used.
+    pub fn health(&self) -> sled::Result<usize> {
+        self.pairings.size_on_disk()?;
+        self.last_scanned.first()?;
+        Ok(self.pairings.len())
+    }
+
+    /// Every paired card ordered by card id.
+    pub fn list(&self) -> sled::Result<Vec<PairedCard>> {
+        self.pairings
+            .iter()
+            .map(|entry| {
+                let (key, value) = entry?;
+                let card_id = String::from_utf8_lossy(&key).into_owned();
+                let actions = to_actions(&card_id, &value);
+                let media = first_media(&actions).unwrap_or_default();
+                let last_scanned = self.last_scanned.get(&key)?
+                    .and_then(|bytes| <[u8; 8]>::try_from(bytes.as_ref()).ok())
+                    .map(u64::from_be_bytes);
+                let outputs = self.outputs.get(&key)?.map(|value| to_outputs(&card_id, &value)).unwrap_or_default();
+
+                Ok(PairedCard {
+                    path: resolve_media_path(&self.library, &media).map(|path| path.display().to_string()).unwrap_or_default(),
+                    file_exists: actions.iter().all(|action| match action {
+                        CardAction::PlayMedia { media } => resolve_media_path(&self.library, media).is_ok(),
+                        _ => true,
+                    }),
+                    media,
+                    card_id,
+                    last_scanned,
+                    outputs,
+                    actions,
+                })
+            })
+            .collect()
+    }
+}
+
+fn to_outputs(card_id: &str, value: &IVec) -> Vec<OutputAction> {
+    serde_json::from_slice(value).unwrap_or_else(|err| {
+        error!("Ignoring unreadable outputs of card {}: {:?}", card_id, err);
+        Vec::new()
+    })
+}
+
+fn to_actions(card_id: &str, value: &IVec) -> Vec<CardAction> {
+    if let Ok(actions) = serde_json::from_slice(value) {
+        return actions;
+    }
+    match std::str::from_utf8(value) {
+        Ok(path) => vec![CardAction::PlayMedia { media: media_name(Path::new(path)) }],
+        Err(_) => {
+            error!("Ignoring unreadable actions of card {}", card_id);
+            Vec::new()
+        }
+    }
+}
+
+/// The first file `actions` play.
+pub fn first_media(actions: &[CardAction]) -> Option<String> {
+    actions.iter().find_map(|action| match action {
+        CardAction::PlayMedia { media } => Some(media.clone()),
+        _ => None,
+    })
+}
+
+fn media_name(path: &Path) -> String {
+    path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use tempfile::TempDir;
+    use crate::gpio::outputs::OutputMode;
+    use super::*;
+
+    struct TestStore {
+        dir: PathBuf,
+        store: CardStore,
+        _temp: TempDir,
+    }
+
+    impl TestStore {
+        /// Opens a database in a temporary `card_database` next to an existing `clue.mp4`
+        fn new() -> TestStore {
+            let temp = TempDir::new().unwrap();
+            let dir = temp.path().to_path_buf();
+            fs::write(dir.join("clue.mp4"), b"clue").unwrap();
+            let store = CardStore::open(&dir.join("card_database"), &dir).unwrap();
+            TestStore { dir, store, _temp: temp }
+        }
+    }
+
+    #[test]
+    fn lists_pairings_with_scan_time_and_file_state() {
+        let test = TestStore::new();
+        test.store.pair("a", &test.dir.join("clue.mp4")).unwrap();
+        test.store.pair("b", &test.dir.join("gone.mp4")).unwrap();
+        test.store.record_scan("a").unwrap();
+
+        let cards = test.store.list().unwrap();
+        assert_eq!(cards.len(), 2);
+        assert_eq!(cards[0].media, "clue.mp4");
+        assert_eq!(cards[0].path, test.dir.canonicalize().unwrap().join("clue.mp4").display().to_string());
+        assert!(cards[0].file_exists && cards[0].last_scanned.is_some());
+        assert!(!cards[1].file_exists && cards[1].last_scanned.is_none());
+    }
+
+    #[test]
+    fn unpair_removes_pairing_scan_time_and_outputs() {
+        let test = TestStore::new();
+        test.store.pair("a", &test.dir.join("clue.mp4")).unwrap();
+        test.store.record_scan("a").unwrap();
+        let outputs = vec![OutputAction { output: "maglock".to_owned(), mode: OutputMode::Pulse { millis: 500 } }];
+        test.store.set_outputs("a", &outputs).unwrap();
+        assert_eq!(test.store.list().unwrap()[0].outputs, outputs);
+
+        assert!(test.store.unpair("a").unwrap());
+        assert!(!test.store.unpair("a").unwrap());
+        assert!(test.store.actions("a").unwrap().is_none());
+        assert!(test.store.last_scanned.is_empty());
+        assert!(test.store.outputs("a").unwrap().is_empty());
+    }
+
+    #[test]
+    fn reads_bare_paths_and_reassign_keeps_the_other_actions() {
+        let test = TestStore::new();
+        test.store.pairings.insert("a", test.dir.join("clue.mp4").display().to_string().as_str()).unwrap();
+        assert_eq!(test.store.actions("a").unwrap(), Some(vec![CardAction::PlayMedia { media: "clue.mp4".to_owned() }]));
+
+        test.store.set_actions("a", &[CardAction::Wait { seconds: 2 }, CardAction::PlayMedia { media: "clue.mp4".to_owned() }, CardAction::ReturnToIdle]).unwrap();
+        test.store.reassign("a", &test.dir.join("other.mp4")).unwrap();
+        assert_eq!(test.store.actions("a").unwrap().unwrap()[1], CardAction::PlayMedia { media: "other.mp4".to_owned() });
+        assert!(test.store.list().unwrap()[0].path.is_empty());
+        assert!(!test.store.list().unwrap()[0].file_exists);
+    }
+
+    #[test]
+    fn clear_only_removes_filtered_cards() {
+        let test = TestStore::new();
+        test.store.pair("a", &test.dir.join("clue.mp4")).unwrap();
+        test.store.pair("b", &test.dir.join("gone.mp4")).unwrap();
+
+        assert_eq!(test.store.clear(|card| !card.file_exists).unwrap(), vec!["b".to_owned()]);
+        assert_eq!(test.store.clear(|_| true).unwrap(), vec!["a".to_owned()]);
+        assert!(test.store.list().unwrap().is_empty());
+    }
+}
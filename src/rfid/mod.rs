@@ -1 +1,3 @@
-pub mod rfid_manger;
\ No newline at end of file
+pub mod rfid_manger;
+pub mod card_store;
+pub mod sequencer;
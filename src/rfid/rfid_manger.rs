@@ -1,12 +1,11 @@
 use std::env::current_dir;
 use std::{fs, thread};
-use std::fs::File;
-use std::io::BufReader;
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 
 use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::thread::JoinHandle;
 use std::time::Duration;
 use linux_embedded_hal::{Pin, Spidev};
 use linux_embedded_hal::spidev::{SpidevOptions, SpiModeFlags};
@@ -14,23 +13,47 @@ use linux_embedded_hal::sysfs_gpio::Direction;
 use log::{error, info};
 use mfrc522::Mfrc522;
 use mfrc522::error::Error;
-use sled::{Db};
+use serde::Serialize;
 use uuid::{Bytes, Uuid};
 use crate::config::setup::DeviceConfiguration;
+use crate::events::event_bus::{DeviceEvent, EventBus};
+use crate::metrics::device_metrics::Metrics;
+use crate::rfid::card_store::{first_media, CardStore};
+use crate::rfid::sequencer::Sequencer;
 use crate::video_handler::media_manager::Command;
-use crate::video_handler::media_manager::Command::{Idle, PlayMedia};
+use crate::video_handler::media_manager::Command::Idle;
 
 #[derive(Debug, Clone)]
 enum RfidCommands {
     PairCard(PathBuf)
 }
 
+/// What the rfid reader thread is doing, reported by the health check.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReaderState {
+    Starting,
+    Running,
+    /// Opening the reader failed or communication was lost, it will be tried again
+    Retrying,
+    /// Every retry failed or the reader thread died, cards can't be read until a restart
+    Absent,
+    NotRaspberryPi,
+}
+
 pub struct Rfid {
     vlc_command_channel: Sender<Command>,
-    sled_database: Db,
+    cards: CardStore,
     device_configuration: DeviceConfiguration,
     command_channel: Sender<RfidCommands>,
-    is_waiting: Arc<AtomicBool>
+    is_waiting: Arc<AtomicBool>,
+    /// Shared with the reader thread so a new timeout applies from the next card on
+    clue_timeout: Arc<AtomicU64>,
+    reader_state: Arc<Mutex<ReaderState>>,
+    reader_thread: Option<JoinHandle<()>>,
+    sequencer: Arc<Sequencer>,
+    events: Arc<EventBus>,
+    metrics: Arc<Metrics>,
 }
 
 impl Rfid {
@@ -59,7 +82,7 @@ impl Rfid {
 }
 
 impl Rfid {
-    pub fn new(vlc_command_channel: Sender<Command>, device_configuration: DeviceConfiguration) -> Rfid {
+    pub fn new(vlc_command_channel: Sender<Command>, device_configuration: DeviceConfiguration, sequencer: Arc<Sequencer>, events: Arc<EventBus>, metrics: Arc<Metrics>) -> Rfid {
         let database_dir = current_dir().unwrap().join("data");
 
         if !database_dir.is_dir() {
@@ -70,42 +93,74 @@ impl Rfid {
             });
         }
 
-        let sled_database = sled::open(database_dir.join("card_database")).unwrap_or_else(|e|{
+        let cards = CardStore::open(&database_dir.join("card_database"), &current_dir().unwrap().join("files")).unwrap_or_else(|e|{
             error!("Failed to open database: {:?}", e);
             panic!("Failed to open database: {:?}", e);
         });
 
 
         let commands = channel();
-        let rfid = Rfid {
+        let clue_timeout = Arc::new(AtomicU64::new(device_configuration.clue_timeout));
+        let mut rfid = Rfid {
             vlc_command_channel,
-            sled_database,
+            cards,
             device_configuration,
             command_channel: commands.0,
-            is_waiting: Arc::new(AtomicBool::new(false))
+            is_waiting: Arc::new(AtomicBool::new(false)),
+            clue_timeout,
+            reader_state: Arc::new(Mutex::new(ReaderState::Starting)),
+            reader_thread: None,
+            sequencer,
+            events,
+            metrics,
         };
 
-        rfid.start_rfid_thread(commands.1);
+        rfid.reader_thread = rfid.start_rfid_thread(commands.1);
         rfid
     }
 
+    pub fn cards(&self) -> &CardStore {
+        &self.cards
+    }
+
+    pub fn reader_state(&self) -> ReaderState {
+        let state = *self.reader_state.lock().unwrap();
+        // The thread panics on errors it can't recover from, without getting to update the state
+        match &self.reader_thread {
+            Some(thread) if thread.is_finished() => ReaderState::Absent,
+            _ => state,
+        }
+    }
+
     pub fn is_waiting(&self) -> bool {
         self.is_waiting.load(Ordering::SeqCst)
     }
 
-    fn start_rfid_thread(&self,commands_rx: Receiver<RfidCommands>) {
+    pub fn set_clue_timeout(&self, seconds: u64) {
+        self.clue_timeout.store(seconds, Ordering::SeqCst);
+    }
+
+    fn start_rfid_thread(&self,commands_rx: Receiver<RfidCommands>) -> Option<JoinHandle<()>> {
         if is_raspberry_pi() {
-            let clue_timeout = self.device_configuration.clue_timeout;
+            let clue_timeout = self.clue_timeout.clone();
             let tx = self.vlc_command_channel.clone();
-            let database = self.sled_database.clone();
+            let cards = self.cards.clone();
             let retry = self.device_configuration.rfid_retrys;
             let is_waiting = self.is_waiting.clone();
-            thread::spawn(move || {
+            let sequencer = self.sequencer.clone();
+            let events = self.events.clone();
+            let metrics = self.metrics.clone();
+            let reader_state = self.reader_state.clone();
+            Some(thread::spawn(move || {
                 for i in 0..retry {
                     info!("Starting rfid reader ({} of {})", i, retry-1);
+                    if i > 0 {
+                        metrics.rfid_reader_restarts.inc();
+                    }
                     let spi = Spidev::open("/dev/spidev0.0");
                     if spi.is_err() {
                         error!("Failed to open spi device waiting 3 seconds then retrying");
+                        *reader_state.lock().unwrap() = ReaderState::Retrying;
                         thread::sleep(Duration::from_secs(3));
                         continue;
                     }
@@ -144,61 +199,63 @@ impl Rfid {
                     info!("Mfrc522 VERSION: 0x{:x}", vers);
 
                     assert!(vers == 0x91 || vers == 0x92);
+                    *reader_state.lock().unwrap() = ReaderState::Running;
 
                     loop {
                         match mfrc522.reqa() {
                             Ok(atqa) =>{
                                 if let Ok(uid) = mfrc522.select(&atqa) {
                                     info!("UID: {:?}", uid.as_bytes());
+                                    let card_id = slice_to_uuid(uid.as_bytes()).to_string();
+                                    let uid_hex = hex::encode(uid.as_bytes());
 
                                     match commands_rx.try_recv() {
                                         Ok(message) => {
                                             match message { RfidCommands::PairCard(path) => {
                                                 while let Ok(value) = commands_rx.try_recv() {
-                                                    println!("received {:?}", value);
+                                                    info!("Skipping queued rfid command {:?}", value);
                                                 }
 
-                                                if let Ok(_) = database.insert(slice_to_uuid(uid.as_bytes()).to_string(), path.display().to_string().as_str()) {
-                                                    info!("Card written waiting {}S",clue_timeout);
-                                                    tx.send(Idle).unwrap_or_else(|_err|{
-                                                        error!("Failed send idle screen");
-                                                    });
-                                                    is_waiting.store(true, Ordering::SeqCst);
-                                                    thread::sleep(Duration::from_secs(clue_timeout));
-                                                    is_waiting.store(false, Ordering::SeqCst);
+                                                match cards.pair(&card_id, &path) {
+                                                    Ok(_) => {
+                                                        let clue_timeout = clue_timeout.load(Ordering::SeqCst);
+                                                        info!("Card written waiting {}S",clue_timeout);
+                                                        metrics.card_pairings.inc();
+                                                        events.publish(DeviceEvent::CardPaired { card_id, uid: uid_hex, media: media_name(&path) });
+                                                        tx.send(Idle).unwrap_or_else(|_err|{
+                                                            error!("Failed send idle screen");
+                                                        });
+                                                        set_waiting(&is_waiting, &events, true);
+                                                        thread::sleep(Duration::from_secs(clue_timeout));
+                                                        set_waiting(&is_waiting, &events, false);
+                                                    }
+                                                    Err(err) => error!("Failed to pair card {} with {}: {:?}", card_id, path.display(), err),
                                                 }
                                             }}
                                         },
                                         Err(TryRecvError::Empty) => {
-                                            if let Ok(Some(data)) = database.get(slice_to_uuid(uid.as_bytes()).to_string()) {
-                                                let bytes: &[u8] = data.as_ref();
-                                                if let Ok(path) = std::str::from_utf8(bytes){
-                                                    let media = PathBuf::from(path);
-                                                    if let Ok(f) = File::open(&media) {
-                                                        let size = f.metadata().unwrap().len();
-                                                        let reader = BufReader::new(f);
-
-                                                        let mp4 = mp4::Mp4Reader::read_header(reader, size).unwrap();
-
-                                                        tx.send(PlayMedia(media)).unwrap();
-                                                        let wait = mp4.duration().as_secs() + clue_timeout;
-                                                        info!("Card read waiting {}S",wait);
-                                                        is_waiting.store(true, Ordering::SeqCst);
-                                                        thread::sleep(Duration::from_secs(wait));
-                                                        is_waiting.store(false, Ordering::SeqCst);
-                                                        info!("Finished waiting");
-                                                    } else {
-                                                        info!("File is no longer valid removing from database");
-                                                        let key = slice_to_uuid(uid.as_bytes()).to_string();
-                                                        if let Err(err) = database.remove(&key) {
-                                                            error!("Failed tp remove db entry for {} err: {:?}", key, err);
-                                                        }
-                                                        info!("Removed {} with id {} from database", path, key);
-                                                    }
-
+                                            if let Ok(Some(actions)) = cards.actions(&card_id) {
+                                                metrics.card_scanned(true);
+                                                if let Err(err) = cards.record_scan(&card_id) {
+                                                    error!("Failed to record scan of {}: {:?}", card_id, err);
                                                 }
+                                                events.publish(DeviceEvent::CardScanned { card_id: card_id.clone(), uid: uid_hex, paired: true, media: first_media(&actions) });
+                                                let outputs = cards.outputs(&card_id).unwrap_or_else(|err| {
+                                                    error!("Failed to read outputs of card {}: {:?}", card_id, err);
+                                                    Vec::new()
+                                                });
+
+                                                set_waiting(&is_waiting, &events, true);
+                                                sequencer.run(&card_id, &outputs, &actions);
+                                                let wait = clue_timeout.load(Ordering::SeqCst);
+                                                info!("Card read waiting {}S",wait);
+                                                thread::sleep(Duration::from_secs(wait));
+                                                set_waiting(&is_waiting, &events, false);
+                                                info!("Finished waiting");
                                             } else {
                                                 info!("No database entry found for card: {:?}", uid.as_bytes());
+                                                metrics.card_scanned(false);
+                                                events.publish(DeviceEvent::CardScanned { card_id, uid: uid_hex, paired: false, media: None });
                                             }
                                         },
                                         Err(TryRecvError::Disconnected) => error!("Channel disconnected"),
@@ -206,6 +263,8 @@ impl Rfid {
                                 }
                             }
                             Err(Error::LostCommunication) => {
+                                metrics.rfid_lost_communication.inc();
+                                *reader_state.lock().unwrap() = ReaderState::Retrying;
                                 break;
                             }
                             _ => {}
@@ -223,13 +282,25 @@ impl Rfid {
                     thread::sleep(Duration::from_secs(5));
                 }
                 error!("Rfid reader not found.");
-            });
+                *reader_state.lock().unwrap() = ReaderState::Absent;
+            }))
         } else {
             error!("Not a raspberry pi not starting rfid reader");
+            *self.reader_state.lock().unwrap() = ReaderState::NotRaspberryPi;
+            None
         }
     }
 }
 
+fn set_waiting(is_waiting: &AtomicBool, events: &EventBus, waiting: bool) {
+    is_waiting.store(waiting, Ordering::SeqCst);
+    events.publish(DeviceEvent::RfidWaiting { waiting });
+}
+
+fn media_name(path: &Path) -> String {
+    path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default()
+}
+
 fn slice_to_uuid(data: &[u8]) -> Uuid {
     let array: [u8; 16] = data
         .iter()
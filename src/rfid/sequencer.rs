@@ -0,0 +1,166 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use crate::gpio::outputs::{OutputAction, OutputMode, Outputs};
+use crate::video_handler::media_manager::Command;
+use crate::web_server::media_path::resolve_media_path;
+use crate::webhooks::webhook_dispatcher::{WebhookEvent, Webhooks};
+
+/// One step of what a card does when it is scanned.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum CardAction {
+    /// Plays a file from the library, the next step runs once a clip has finished
+    PlayMedia { media: String },
+    /// Shows text on top of whatever is on screen for `seconds`
+    ShowText { text: String, seconds: u64 },
+    Wait { seconds: u64 },
+    /// Posts a `card_action` event named `name` to the webhooks
+    FireWebhook { name: String },
+    SetOutput {
+        output: String,
+        #[serde(flatten)]
+        mode: OutputMode,
+    },
+    ReturnToIdle,
+}
+
+/// Runs the actions of scanned cards.
+pub struct Sequencer {
+    player: Sender<Command>,
+    library: PathBuf,
+    outputs: Arc<Outputs>,
+    webhooks: Arc<Webhooks>,
+}
+
+impl Sequencer {
+    pub fn new(player: Sender<Command>, library: PathBuf, outputs: Arc<Outputs>, webhooks: Arc<Webhooks>) -> Sequencer {
+        Sequencer { player, library, outputs, webhooks }
+    }
+
+    /// Switches the card's outputs and runs its actions in order, returning once the last one is done.
+    /// A step that fails is logged and skipped so the rest of the sequence still runs.
+    pub fn run(&self, card_id: &str, outputs: &[OutputAction], actions: &[CardAction]) {
+        for output in outputs {
+            match self.outputs.run(output) {
+                Ok(result) => info!("Card {}: {}", card_id, result),
+                Err(err) => error!("Failed to switch output of card {}: {}", card_id, err),
+            }
+        }
+        for (step, action) in actions.iter().enumerate() {
+            match self.step(card_id, action) {
+                Ok(result) => info!("Card {} step {}: {}", card_id, step + 1, result),
+                Err(err) => error!("Card {} step {} failed: {}", card_id, step + 1, err),
+            }
+        }
+    }
+
+    fn step(&self, card_id: &str, action: &CardAction) -> Result<String, String> {
+        match action {
+            CardAction::PlayMedia { media } => {
+                let path = resolve_media_path(&self.library, media).map_err(|err| err.to_string())?;
+                let duration = media_duration(&path);
+                self.send(Command::PlayMedia(path))?;
+                thread::sleep(duration);
+                Ok(format!("Played {}", media))
+            }
+            CardAction::ShowText { text, seconds } => {
+                self.send(Command::ShowText { text: text.clone(), duration: Duration::from_secs(*seconds) })?;
+                Ok(format!("Showing text for {}s", seconds))
+            }
+            CardAction::Wait { seconds } => {
+                thread::sleep(Duration::from_secs(*seconds));
+                Ok(format!("Waited {}s", seconds))
+            }
+            CardAction::FireWebhook { name } => {
+                self.webhooks.send(&WebhookEvent::CardAction { card_id: card_id.to_owned(), name: name.clone() });
+                Ok(format!("Fired webhook {}", name))
+            }
+            CardAction::SetOutput { output, mode } => self.outputs.run(&OutputAction { output: output.clone(), mode: *mode }),
+            CardAction::ReturnToIdle => {
+                self.send(Command::Idle)?;
+                Ok("Returned to the idle screen".to_owned())
+            }
+        }
+    }
+
+    fn send(&self, command: Command) -> Result<(), String> {
+        self.player.send(command).map_err(|_| "Media player is not running".to_owned())
+    }
+}
+
+/// How long a clip plays for, nothing for images and files whose length can't be read.
+fn media_duration(path: &Path) -> Duration {
+    if !path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("mp4")) {
+        return Duration::ZERO;
+    }
+    let Ok(file) = File::open(path) else {
+        return Duration::ZERO;
+    };
+    let size = file.metadata().map(|metadata| metadata.len()).unwrap_or_default();
+    match mp4::Mp4Reader::read_header(BufReader::new(file), size) {
+        Ok(mp4) => mp4.duration(),
+        Err(err) => {
+            error!("Failed to read mp4 header of {}: {:?}", path.display(), err);
+            Duration::ZERO
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::sync::mpsc::channel;
+    use tempfile::TempDir;
+    use crate::config::setup::OutputConfiguration;
+    use crate::gpio::pins::SimulatedOutput;
+    use crate::metrics::device_metrics::Metrics;
+    use crate::web_server::api::DeviceIdentity;
+    use super::*;
+
+    #[test]
+    fn reads_actions_as_tagged_records() {
+        let json = r#"[{"action":"play_media","media":"clue.mp4"},{"action":"set_output","output":"maglock","mode":"pulse","millis":500},{"action":"return_to_idle"}]"#;
+        let actions = serde_json::from_str::<Vec<CardAction>>(json).unwrap();
+        assert_eq!(actions[1], CardAction::SetOutput { output: "maglock".to_owned(), mode: OutputMode::Pulse { millis: 500 } });
+        assert_eq!(serde_json::to_value(&actions).unwrap(), serde_json::from_str::<serde_json::Value>(json).unwrap());
+    }
+
+    #[test]
+    fn runs_every_step_in_order_skipping_failed_ones() {
+        let library = TempDir::new().unwrap();
+        fs::write(library.path().join("clue.png"), b"png").unwrap();
+
+        let (player, commands) = channel();
+        let light = SimulatedOutput::default();
+        let config = OutputConfiguration { name: "light".to_owned(), pin: 24, active_low: false };
+        let outputs = Arc::new(Outputs::new(vec![(config, Box::new(light.clone()))]));
+        let identity = DeviceIdentity { device_uuid: "device".to_owned(), name: "Study".to_owned(), version: "test".to_owned() };
+        let webhooks = Arc::new(Webhooks::new(&[], identity, Arc::new(Metrics::new()), Duration::ZERO));
+        let sequencer = Sequencer::new(player, library.path().to_path_buf(), outputs, webhooks);
+
+        sequencer.run("card", &[], &[
+            CardAction::PlayMedia { media: "gone.mp4".to_owned() },
+            CardAction::PlayMedia { media: "../clue.png".to_owned() },
+            CardAction::PlayMedia { media: "clue.png".to_owned() },
+            CardAction::ShowText { text: "Look up".to_owned(), seconds: 5 },
+            CardAction::SetOutput { output: "light".to_owned(), mode: OutputMode::Latch },
+            CardAction::FireWebhook { name: "solved".to_owned() },
+            CardAction::ReturnToIdle,
+        ]);
+
+        let sent = commands.try_iter().map(|command| format!("{:?}", command)).collect::<Vec<_>>();
+        assert_eq!(sent.len(), 3);
+        assert!(sent[0].starts_with("PlayMedia") && sent[0].contains("clue.png"));
+        assert!(sent[1].starts_with("ShowText"));
+        assert_eq!(sent[2], "Idle");
+        assert!(light.is_active());
+    }
+}
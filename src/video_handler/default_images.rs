@@ -1,12 +1,56 @@
 use std::env::current_dir;
-use std::fs;
-use std::path::{PathBuf};
+use std::{fs, io};
+use std::path::{Path, PathBuf};
 
 use local_ip_address::local_ip;
 use log::{error, info};
 use crate::video_handler::image_generation::generate_image_with_text;
 
-pub fn create_startup_file() -> PathBuf {
+/// Screens the player generates for itself, they are kept out of the media listing.
+const GENERATED_SCREENS: [&str; 2] = ["startup.png", "paircard.png"];
+
+/// Shown when no clue is playing, admins replace it by uploading their own.
+pub const IDLE_SCREEN: &str = "idle.png";
+
+/// Marks the idle image as generated by us, holding the address it shows.
+const GENERATED_IDLE_MARKER: &str = ".idle.generated";
+
+pub fn is_generated_screen(name: &str) -> bool {
+    GENERATED_SCREENS.contains(&name)
+}
+
+/// True when `idle.png` was uploaded rather than generated by us.
+pub fn is_custom_idle(files_dir: &Path) -> bool {
+    let idle_image_path = files_dir.join(IDLE_SCREEN);
+    let marker_path = files_dir.join(GENERATED_IDLE_MARKER);
+    if !idle_image_path.is_file() {
+        return false;
+    }
+    let modified = |path: &Path| fs::metadata(path).and_then(|metadata| metadata.modified()).ok();
+    // Without a marker nothing was ever generated here
+    modified(&marker_path).is_none() || modified(&idle_image_path) > modified(&marker_path)
+}
+
+/// Forgets that the idle image was generated, so a restored one is never replaced.
+pub fn mark_idle_custom(files_dir: &Path) -> io::Result<()> {
+    match fs::remove_file(files_dir.join(GENERATED_IDLE_MARKER)) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+/// The address the web interface can be reached at from the local network.
+pub fn web_interface_url(scheme: &str, port: u16) -> String {
+    let my_local_ip = local_ip().unwrap_or_else(|e|{
+        let error = format!("Failed to find current IP address are you connected to the internet? : {:?}", e);
+        error!("{}", error);
+        panic!("{}", error);
+    });
+
+    format!("{}://{}:{}", scheme, my_local_ip, port)
+}
+
+pub fn create_startup_file(web_url: &str) -> PathBuf {
     let files_dir = current_dir().unwrap().join("files");
 
 
@@ -21,13 +65,7 @@ pub fn create_startup_file() -> PathBuf {
     }
 
     if !startup_image_location.is_file() {
-        let my_local_ip = local_ip().unwrap_or_else(|e|{
-            let error = format!("Failed to find current IP address are you connected to the internet? : {:?}", e);
-            error!("{}", error);
-            panic!("{}", error);
-        });
-
-        match generate_image_with_text(format!("{}:8000",my_local_ip).as_str(), &startup_image_location) {
+        match generate_image_with_text(web_url, &startup_image_location) {
             Ok(_) => {
                 info!("Created startup image");
             }
@@ -41,21 +79,20 @@ pub fn create_startup_file() -> PathBuf {
     startup_image_location.as_path().to_path_buf()
 }
 
-pub fn create_idle_image() -> PathBuf {
-    let idle_image_path = current_dir().unwrap().join("files").join("idle.png");
-
-    if !idle_image_path.is_file() {
-        let my_local_ip = local_ip().unwrap_or_else(|e|{
-            let error = format!("Failed to find current IP address are you connected to the internet? : {:?}", e);
-            error!("{}", error);
-            panic!("{}", error);
-        });
+pub fn create_idle_image(web_url: &str) -> PathBuf {
+    let files_dir = current_dir().unwrap().join("files");
+    let idle_image_path = files_dir.join(IDLE_SCREEN);
+    let marker_path = files_dir.join(GENERATED_IDLE_MARKER);
 
+    if !idle_image_path.is_file() || is_outdated_default_idle(&idle_image_path, &marker_path, web_url) {
         match generate_image_with_text(
-            format!("This is the default Idle screen to add your own upload one to {}:8000 with the name idle.png", my_local_ip).as_str(),
+            format!("This is the default Idle screen to add your own upload one to {} with the name idle.png", web_url).as_str(),
                                        &idle_image_path) {
             Ok(_) => {
                 info!("Created default idle image ");
+                fs::write(&marker_path, web_url).unwrap_or_else(|e|{
+                    error!("Failed to mark idle image as generated: {:?}", e);
+                });
             }
             Err(error) => {
                 error!("{:?}", error);
@@ -67,6 +104,17 @@ pub fn create_idle_image() -> PathBuf {
     idle_image_path
 }
 
+/// True when the idle image is still the generated default but shows a different address,
+/// an uploaded idle.png is newer than the marker and is never replaced.
+fn is_outdated_default_idle(idle_image_path: &Path, marker_path: &Path, web_url: &str) -> bool {
+    let Ok(generated_for) = fs::read_to_string(marker_path) else {
+        return false;
+    };
+    let modified = |path: &Path| fs::metadata(path).and_then(|metadata| metadata.modified()).ok();
+
+    generated_for != web_url && modified(idle_image_path) <= modified(marker_path)
+}
+
 pub fn create_paircard_image() -> PathBuf {
     let pair_card_image = current_dir().unwrap().join("files").join("paircard.png");
 
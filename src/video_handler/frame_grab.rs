@@ -0,0 +1,47 @@
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use libmpv::events::Event;
+use libmpv::{FileState, Mpv};
+
+/// Longest a single video may take to decode a frame before giving up.
+const FRAME_GRAB_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Decodes one frame from a tenth of the way into `video` with mpv's image output
+/// and returns the png it wrote into `output_dir`.
+pub fn grab_frame(video: &Path, output_dir: &Path) -> Result<PathBuf, Box<dyn Error>> {
+    fs::create_dir_all(output_dir)?;
+    let output = output_dir.display().to_string();
+
+    let mpv = Mpv::with_initializer(|init| {
+        init.set_property("vo", "image")?;
+        init.set_property("vo-image-format", "png")?;
+        init.set_property("vo-image-outdir", output.as_str())?;
+        init.set_property("ao", "null")?;
+        init.set_property("aid", "no")?;
+        init.set_property("start", "10%")?;
+        init.set_property("frames", 1)?;
+        Ok(())
+    })?;
+    let mut events = mpv.create_event_context();
+    events.disable_deprecated_events()?;
+
+    mpv.playlist_load_files(&[(video.display().to_string().as_str(), FileState::Replace, None)])?;
+
+    let deadline = Instant::now() + FRAME_GRAB_TIMEOUT;
+    while Instant::now() < deadline {
+        match events.wait_event(1.0) {
+            Some(Ok(Event::EndFile(_))) | Some(Ok(Event::Shutdown)) => break,
+            Some(Err(err)) => return Err(Box::new(err)),
+            _ => {}
+        }
+    }
+
+    fs::read_dir(output_dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .find(|path| path.extension().is_some_and(|ext| ext == "png"))
+        .ok_or_else(|| format!("mpv did not write a frame for {}", video.display()).into())
+}
@@ -1,9 +1,15 @@
+use crate::config::setup::DeviceConfiguration;
+use crate::events::event_bus::EventBus;
+use crate::metrics::device_metrics::Metrics;
+use crate::video_handler::default_images::web_interface_url;
 use crate::video_handler::player::{Player};
 use std::sync::mpsc::{channel, Sender, SendError};
 use std::{thread};
 use std::path::PathBuf;
+use std::sync::Arc;
 
 use std::thread::JoinHandle;
+use std::time::Duration;
 
 
 
@@ -12,21 +18,25 @@ pub enum Command {
     Idle,
     PlayMedia(PathBuf),
     PairCard,
+    /// Text drawn on top of whatever is on screen
+    ShowText { text: String, duration: Duration },
 }
 
 pub struct VlcManager {
     command_channel: Sender<Command>,
-    _player_thread_handle: JoinHandle<()>
+    player_thread_handle: JoinHandle<()>
 }
 
 impl VlcManager {
-    pub fn new() -> VlcManager{
+    pub fn new(events: Arc<EventBus>, metrics: Arc<Metrics>, device_configuration: &DeviceConfiguration) -> VlcManager{
         let (command_tx, command_rx) = channel::<Command>();
+        let scheme = device_configuration.scheme();
+        let port = device_configuration.port;
 
         VlcManager {
             command_channel: command_tx.clone(),
-            _player_thread_handle: thread::spawn(move || {
-                Player::new((command_tx, command_rx))
+            player_thread_handle: thread::spawn(move || {
+                Player::new((command_tx, command_rx), events, metrics, web_interface_url(scheme, port))
                     .expect("FIXME: this should be changed")
                     .thread();
             })
@@ -43,4 +53,9 @@ impl VlcManager {
     pub fn get_command_channel(&self) -> Sender<Command> {
         self.command_channel.clone()
     }
+
+    /// False once the player thread has exited, usually because one of its mpv calls panicked.
+    pub fn is_running(&self) -> bool {
+        !self.player_thread_handle.is_finished()
+    }
 }
\ No newline at end of file
@@ -1,4 +1,5 @@
 mod player;
 pub mod media_manager;
-mod default_images;
+pub mod default_images;
 pub mod image_generation;
+pub mod frame_grab;
@@ -2,7 +2,7 @@
 
 use std::sync::{Arc};
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::mpsc::{Receiver, Sender};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
 use std::{fs, thread};
 use std::env::current_dir;
 
@@ -13,9 +13,11 @@ use std::time::Duration;
 use libmpv::{FileState, Mpv};
 
 use log::{error, info, warn};
+use crate::events::event_bus::{DeviceEvent, EventBus, PlayerState};
+use crate::metrics::device_metrics::Metrics;
 use crate::video_handler::default_images::{create_idle_image, create_paircard_image, create_startup_file};
 use crate::video_handler::media_manager::{Command};
-use crate::video_handler::media_manager::Command::{Idle, PairCard, PlayMedia};
+use crate::video_handler::media_manager::Command::{Idle, PairCard, PlayMedia, ShowText};
 
 
 pub struct Player{
@@ -23,11 +25,18 @@ pub struct Player{
     idle_media: PathBuf,
     pair_card_media: PathBuf,
     command_channel: (Sender<Command>, Receiver<Command>),
+    events: Arc<EventBus>,
+    metrics: Arc<Metrics>,
+    /// Name of the clip being played, until mpv moves on to the idle screen queued after it
+    playing: Option<String>,
 }
 
+/// How often mpv is asked whether the clip that was playing has finished.
+const PLAYBACK_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
 impl Player {
     //FIXME use proper error here
-    pub fn new(command_channel: (Sender<Command>, Receiver<Command>)) -> Result<Player, libmpv::Error> {
+    pub fn new(command_channel: (Sender<Command>, Receiver<Command>), events: Arc<EventBus>, metrics: Arc<Metrics>, web_url: String) -> Result<Player, libmpv::Error> {
         if let Ok(media_player) = Mpv::new() {
             media_player.set_property("volume", 100)?;
             media_player.set_property("keep-open", "yes")?;
@@ -43,13 +52,14 @@ impl Player {
                     panic!("Could not create dir to store files: {:?}", e);
                 });
             }
-            let idle_media = create_idle_image();
+            let idle_media = create_idle_image(&web_url);
 
             let pair_card_media = create_paircard_image();
 
-            let startup_media = create_startup_file();
+            let startup_media = create_startup_file(&web_url);
 
             media_player.playlist_load_files(&[(startup_media.as_path().display().to_string().as_str(), FileState::Replace, None)]).unwrap();
+            events.publish(DeviceEvent::PlayerState { player: PlayerState::Startup });
 
             let tx = command_channel.0.clone();
             thread::spawn(move ||{
@@ -65,6 +75,9 @@ impl Player {
                 idle_media,
                 pair_card_media,
                 command_channel,
+                events,
+                metrics,
+                playing: None,
             });
 
         }
@@ -79,20 +92,32 @@ impl Player {
 
 
     pub fn thread(&mut self) {
-        while let Ok(command) = self.command_channel.1.recv() {
+        loop {
+            let command = match self.command_channel.1.recv_timeout(PLAYBACK_POLL_INTERVAL) {
+                Ok(command) => command,
+                Err(RecvTimeoutError::Timeout) => {
+                    self.check_playback_finished();
+                    continue;
+                }
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
             info!("Media Player Received Command: {:?}", command);
             match command {
                 Idle => {
                     //FIXME: need to not crash here
                     self.media_player.playlist_load_files(&[(self.idle_media.as_path().display().to_string().as_str(), FileState::Replace, None)])
                         .unwrap();
+                    self.playing = None;
+                    self.events.publish(DeviceEvent::PlayerState { player: PlayerState::Idle });
                 }
                 PlayMedia(path) => {
+                    self.metrics.play_commands.inc();
                     if is_playable_by_mpv(path.as_path()) {
                         info!("Playing: {}", path.display());
                         self.media_player.playlist_load_files(&[(path.as_path().display().to_string().as_str(), FileState::Replace, None)])
                             .unwrap_or_else(|_| {
                                 warn!("Failed to changed video recreating the mpv player");
+                                self.metrics.player_recreations.inc();
                                 self.media_player = Mpv::new().unwrap();
                                 self.media_player.set_property("volume", 100).unwrap();
                                 self.media_player.set_property("keep-open", "yes").unwrap();
@@ -103,6 +128,9 @@ impl Player {
                         self.media_player.unpause().unwrap();
                         self.media_player.playlist_load_files(&[(self.idle_media.as_path().display().to_string().as_str(), FileState::AppendPlay, None)])
                             .unwrap();
+                        let media = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+                        self.playing = Some(media.clone());
+                        self.events.publish(DeviceEvent::PlayerState { player: PlayerState::Playing { media } });
                     }else {
                         error!("File \"{}\" is not playable", path.display())
                     }
@@ -127,10 +155,41 @@ impl Player {
                     self.media_player.playlist_load_files(&[(self.pair_card_media.as_path().display().to_string().as_str(), FileState::Replace, None)])
                         .unwrap();
                     no_input.store(false, Ordering::SeqCst);
+                    self.playing = None;
+                    self.events.publish(DeviceEvent::PlayerState { player: PlayerState::Pairing });
+                }
+                ShowText { text, duration } => {
+                    let millis = duration.as_millis().to_string();
+                    if let Err(err) = self.media_player.command("show-text", &[&quote_text(&text), &millis]) {
+                        error!("Failed to show text: {:?}", err);
+                    }
                 }
             }
         }
     }
+
+    /// Clips are followed by the idle screen in the playlist, once mpv reaches it the clip is over.
+    fn check_playback_finished(&mut self) {
+        if self.playing.is_none() {
+            return;
+        }
+        let Ok(current) = self.media_player.get_property::<String>("path") else {
+            return;
+        };
+        if Path::new(&current) == self.idle_media.as_path() {
+            info!("Finished playing {}", self.playing.take().unwrap_or_default());
+            self.events.publish(DeviceEvent::PlayerState { player: PlayerState::Idle });
+        }
+    }
+}
+
+/// Quotes text for an mpv command string, `$` would otherwise start a property expansion.
+fn quote_text(text: &str) -> String {
+    let escaped = text.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+        .replace('$', "$$");
+    format!("\"{}\"", escaped)
 }
 
 fn is_playable_by_mpv(file: &Path) -> bool {
@@ -0,0 +1,242 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::{fmt, fs, io};
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+use log::error;
+use serde::{Deserialize, Serialize};
+use tiny_http::{Header, Request, Response};
+use crate::backup::archive::BackupError;
+use crate::config::setup::DeviceConfiguration;
+use crate::events::event_bus::DeviceEvent;
+use crate::video_handler::media_manager::Command::PlayMedia;
+use crate::web_server::api::ApiError::{BadRequest, Conflict, DatabaseError, IoError, NotFound, PlayerUnavailable};
+use crate::web_server::app_state::AppState;
+use crate::web_server::file_server::serve_file;
+use crate::web_server::media_path::{resolve_media_path, MediaPathError};
+use crate::web_server::router::{PathParams, SendResponse};
+use crate::web_server::thumbnails::ThumbnailError;
+use crate::web_server::upload_handler::{save_multipart_upload, UploadPolicy};
+
+/// Who a device is, so the fleet controller can tell devices apart by uuid.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct DeviceIdentity {
+    pub device_uuid: String,
+    pub name: String,
+    pub version: String,
+}
+
+impl DeviceIdentity {
+    pub fn from_config(config: &DeviceConfiguration) -> DeviceIdentity {
+        DeviceIdentity {
+            device_uuid: config.device_uuid.clone(),
+            name: config.device_name.clone(),
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+        }
+    }
+}
+
+pub fn device_info(request: Request, state: &AppState, _: &PathParams) -> Result<(), Box<dyn Error>> {
+    let identity = DeviceIdentity::from_config(&state.config());
+    request.send_response(json_response(200, &identity))?;
+    Ok(())
+}
+
+pub fn get_media(request: Request, state: &AppState, _: &PathParams) -> Result<(), Box<dyn Error>> {
+    let result = state.media.list().map(|media| json_response(200, &media)).map_err(|err| err.into());
+    respond(request, result)
+}
+
+pub fn download_media(request: Request, state: &AppState, params: &PathParams) -> Result<(), Box<dyn Error>> {
+    let name = params.get("name").unwrap_or_default();
+    match media_path(state, name) {
+        Ok(media) => Ok(serve_file(request, &media, Some(name))?),
+        Err(err) => respond(request, Err(err)),
+    }
+}
+
+pub fn post_media(mut request: Request, state: &AppState, _: &PathParams) -> Result<(), Box<dyn Error>> {
+    let policy = UploadPolicy::from_config(&state.config());
+    let result = save_multipart_upload(&mut request, &state.files_dir(), &policy)
+        .map(|report| {
+            if !report.accepted.is_empty() {
+                state.events.publish(DeviceEvent::UploadCompleted { files: report.accepted_names() });
+                state.thumbnails.generate_in_background(report.accepted_names());
+            }
+            state.metrics.upload_bytes.inc_by(report.accepted_bytes());
+            json_response(report.status_code(), &report)
+        })
+        .map_err(|err| BadRequest(err.to_string()));
+    respond(request, result)
+}
+
+pub fn delete_media(request: Request, state: &AppState, params: &PathParams) -> Result<(), Box<dyn Error>> {
+    let name = params.get("name").unwrap_or_default();
+    let result = media_path(state, name)
+        .and_then(|media| Ok(fs::remove_file(media)?))
+        .map(|_| state.thumbnails.remove(name))
+        .map(|_| json_response(200, &Message::new(format!("Removed {}", name))));
+    respond(request, result)
+}
+
+pub fn media_thumbnail(request: Request, state: &AppState, params: &PathParams) -> Result<(), Box<dyn Error>> {
+    let name = params.get("name").unwrap_or_default();
+    match state.thumbnails.get(name) {
+        Ok(thumbnail) => Ok(serve_file(request, &thumbnail, None)?),
+        Err(err) => respond(request, Err(err.into())),
+    }
+}
+
+pub fn play_media(request: Request, state: &AppState, params: &PathParams) -> Result<(), Box<dyn Error>> {
+    let name = params.get("name").unwrap_or_default();
+    let result = media_path(state, name)
+        .and_then(|media| state.media_manager.send_command(PlayMedia(media)).map_err(|_| PlayerUnavailable))
+        .map(|_| json_response(202, &Message::new(format!("Playing {}", name))));
+    respond(request, result)
+}
+
+pub fn pair_media(request: Request, state: &AppState, params: &PathParams) -> Result<(), Box<dyn Error>> {
+    let name = params.get("name").unwrap_or_default();
+    let result = media_path(state, name)
+        .and_then(|media| {
+            if state.rfid.is_waiting() {
+                return Err(Conflict("Rfid reader is still waiting on video to complete".to_owned()));
+            }
+            state.rfid.pair_card(media.as_path());
+            Ok(())
+        })
+        .map(|_| json_response(202, &Message::new(format!("Waiting for card to pair with {}", name))));
+    respond(request, result)
+}
+
+/// Sends either the successful response or the error as a JSON body.
+/// The error is handed back so the router can log it.
+pub fn respond(request: Request, result: Result<Response<Cursor<Vec<u8>>>, ApiError>) -> Result<(), Box<dyn Error>> {
+    match result {
+        Ok(response) => {
+            request.send_response(response)?;
+            Ok(())
+        }
+        Err(err) => {
+            request.send_response(json_response(err.status_code(), &Message::new(err.to_string())))?;
+            Err(Box::new(err))
+        }
+    }
+}
+
+/// Serializes `body` into a response with a `Content-Type: application/json` header.
+pub fn json_response<T: Serialize>(status: u16, body: &T) -> Response<Cursor<Vec<u8>>> {
+    let body = serde_json::to_vec(body).unwrap_or_else(|err| {
+        error!("Failed to serialize json response: {:?}", err);
+        b"{}".to_vec()
+    });
+
+    Response::from_data(body)
+        .with_status_code(status)
+        .with_header("Content-Type: application/json".parse::<Header>().unwrap())
+}
+
+pub fn media_path(state: &AppState, name: &str) -> Result<PathBuf, ApiError> {
+    Ok(resolve_media_path(&state.files_dir(), name)?)
+}
+
+/// Best effort mime type based on the file extension.
+pub fn media_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|ext| ext.to_str()).map(|ext| ext.to_lowercase()).as_deref() {
+        Some("mp4") => "video/mp4",
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        _ => "application/octet-stream",
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Message {
+    pub message: String,
+}
+
+impl Message {
+    pub fn new<S: Into<String>>(message: S) -> Message {
+        Message { message: message.into() }
+    }
+}
+
+#[derive(Debug)]
+pub enum ApiError {
+    NotFound(String),
+    BadRequest(String),
+    Conflict(String),
+    PlayerUnavailable,
+    IoError(io::Error),
+    DatabaseError(sled::Error),
+}
+
+impl ApiError {
+    pub fn status_code(&self) -> u16 {
+        match self {
+            NotFound(_) => 404,
+            BadRequest(_) => 400,
+            Conflict(_) => 409,
+            PlayerUnavailable => 503,
+            IoError(_) => 500,
+            DatabaseError(_) => 500,
+        }
+    }
+}
+
+impl Display for ApiError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            NotFound(item) => {write!(f, "Not found: {}", item)}
+            BadRequest(reason) => {write!(f, "Bad request: {}", reason)}
+            Conflict(reason) => {write!(f, "{}", reason)}
+            PlayerUnavailable => {write!(f, "Media player is not running")}
+            IoError(error) => {write!(f, "Io operation failed: {}", error)}
+            DatabaseError(error) => {write!(f, "Database operation failed: {}", error)}
+        }
+    }
+}
+
+impl From<io::Error> for ApiError {
+    fn from(error: io::Error) -> Self {
+        IoError(error)
+    }
+}
+
+impl From<sled::Error> for ApiError {
+    fn from(error: sled::Error) -> Self {
+        DatabaseError(error)
+    }
+}
+
+impl From<ThumbnailError> for ApiError {
+    fn from(error: ThumbnailError) -> Self {
+        match error {
+            ThumbnailError::IoError(error) => IoError(error),
+            error => NotFound(error.to_string()),
+        }
+    }
+}
+
+impl From<BackupError> for ApiError {
+    fn from(error: BackupError) -> Self {
+        match error {
+            error @ BackupError::InvalidArchive(_) => BadRequest(error.to_string()),
+            BackupError::IoError(error) => IoError(error),
+            BackupError::DatabaseError(error) => DatabaseError(error),
+        }
+    }
+}
+
+impl From<MediaPathError> for ApiError {
+    fn from(error: MediaPathError) -> Self {
+        match error {
+            MediaPathError::NotFound(name) => NotFound(name),
+            MediaPathError::IoError(error) => IoError(error),
+            error => BadRequest(error.to_string()),
+        }
+    }
+}
+
+impl Error for ApiError {}
@@ -0,0 +1,111 @@
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock, RwLockReadGuard};
+use std::time::Duration;
+
+use tera::Tera;
+use crate::config::setup::DeviceConfiguration;
+use crate::config::users::UserAccount;
+use crate::events::event_bus::EventBus;
+use crate::gpio::outputs::Outputs;
+use crate::metrics::device_metrics::Metrics;
+use crate::rfid::rfid_manger::Rfid;
+use crate::video_handler::media_manager::VlcManager;
+use crate::web_server::api::ApiError;
+use crate::web_server::api::ApiError::NotFound;
+use crate::web_server::auth::SessionStore;
+use crate::web_server::chunked_upload::UploadSessions;
+use crate::web_server::media_library::MediaLibrary;
+use crate::web_server::router::ServerState;
+use crate::web_server::thumbnails::Thumbnails;
+use crate::web_server::upload_handler::clear_staging_dir;
+
+/// Everything a route handler needs, created once at startup and shared by every request.
+pub struct AppState {
+    pub project_dir: PathBuf,
+    /// The running configuration, settings edited from the web interface that apply live are updated in place
+    pub device_config: RwLock<DeviceConfiguration>,
+    pub media_manager: VlcManager,
+    pub rfid: Rfid,
+    pub outputs: Arc<Outputs>,
+    pub sessions: SessionStore,
+    pub uploads: UploadSessions,
+    pub media: MediaLibrary,
+    pub thumbnails: Thumbnails,
+    pub events: Arc<EventBus>,
+    pub metrics: Arc<Metrics>,
+    pub tera: Tera,
+}
+
+impl AppState {
+    pub fn new(project_dir: PathBuf, device_config: DeviceConfiguration, media_manager: VlcManager, rfid: Rfid, outputs: Arc<Outputs>, events: Arc<EventBus>, metrics: Arc<Metrics>) -> AppState {
+        let mut tera = Tera::default();
+        tera.add_raw_template("index.html", include_str!("../../pages/index.html"))
+            .expect("Index page template should be valid");
+        tera.add_raw_template("cards.html", include_str!("../../pages/cards.html"))
+            .expect("Cards page template should be valid");
+        tera.add_raw_template("settings.html", include_str!("../../pages/settings.html"))
+            .expect("Settings page template should be valid");
+
+        clear_staging_dir(&project_dir.join("files"));
+        let media = MediaLibrary::new(project_dir.join("files"));
+        let thumbnails = Thumbnails::new(project_dir.join("files"));
+        let uploads = UploadSessions::new(&project_dir.join("files"));
+        uploads.start_cleanup_thread(Duration::from_secs(device_config.upload_session_timeout * 60 * 60));
+
+        AppState {
+            project_dir,
+            device_config: RwLock::new(device_config),
+            media_manager,
+            rfid,
+            outputs,
+            sessions: SessionStore::new(),
+            uploads,
+            media,
+            thumbnails,
+            events,
+            metrics,
+            tera,
+        }
+    }
+
+    pub fn files_dir(&self) -> PathBuf {
+        self.project_dir.join("files")
+    }
+
+    pub fn config(&self) -> RwLockReadGuard<'_, DeviceConfiguration> {
+        self.device_config.read().unwrap()
+    }
+
+    pub fn config_path(&self) -> PathBuf {
+        self.project_dir.join("config/Config.yaml")
+    }
+}
+
+impl ServerState for AppState {
+    fn sessions(&self) -> &SessionStore {
+        &self.sessions
+    }
+
+    fn find_user(&self, username: &str) -> Option<UserAccount> {
+        self.config().users.iter().find(|user| user.username == username).cloned()
+    }
+
+    fn save_password(&self, username: &str, password: &str) -> Result<(), ApiError> {
+        let mut running = self.device_config.write().unwrap();
+
+        let mut saved = DeviceConfiguration::read(&self.config_path())?;
+        let user = saved.users.iter_mut().find(|user| user.username == username).ok_or(NotFound(username.to_owned()))?;
+        user.set_password(password);
+        let password_hash = user.password_hash.clone();
+        saved.save(self.config_path())?;
+
+        if let Some(user) = running.users.iter_mut().find(|user| user.username == username) {
+            user.password_hash = password_hash;
+        }
+        Ok(())
+    }
+
+    fn http_request(&self, route: &str, status: u16) {
+        self.metrics.http_request(route, status);
+    }
+}
@@ -0,0 +1,383 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tiny_http::{Header, Request, Response};
+use uuid::Uuid;
+use crate::config::users::{check_new_password, Role, UserAccount};
+use crate::web_server::api::ApiError::{BadRequest, NotFound};
+use crate::web_server::api::{json_response, respond, Message};
+use crate::web_server::auth::LoginError::{InvalidCredentials, TooManyAttempts};
+use crate::web_server::file_server::request_header;
+use crate::web_server::router::{PathParams, SendResponse, ServerState};
+
+const SESSION_COOKIE: &str = "session";
+const SESSION_LIFETIME: Duration = Duration::from_secs(12 * 60 * 60);
+/// Failed logins a client or username gets before it has to wait between attempts
+const FREE_LOGIN_ATTEMPTS: u32 = 5;
+const MAX_LOGIN_BACKOFF: Duration = Duration::from_secs(5 * 60);
+/// Failures older than this are forgotten
+const FORGET_FAILURES_AFTER: Duration = Duration::from_secs(60 * 60);
+
+/// Who is allowed to use a route.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Public,
+    GameMaster,
+    Admin,
+}
+
+impl Access {
+    pub fn allows(&self, role: Option<Role>) -> bool {
+        match self {
+            Access::Public => true,
+            Access::GameMaster => role.is_some_and(|role| role >= Role::GameMaster),
+            Access::Admin => role.is_some_and(|role| role >= Role::Admin),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub username: String,
+    pub role: Role,
+    expires: Instant,
+}
+
+/// In memory sessions, everyone has to log in again after a restart.
+pub struct SessionStore {
+    sessions: Mutex<HashMap<String, Session>>,
+    lifetime: Duration,
+    failures: Mutex<HashMap<String, Failures>>,
+}
+
+/// Failed logins of one client address or username.
+struct Failures {
+    count: u32,
+    last: Instant,
+}
+
+impl Failures {
+    /// Every failure past the free ones doubles the wait, so guessing passwords stays slow
+    /// and the hashing each attempt costs can't tie up the device.
+    fn blocked_until(&self) -> Instant {
+        if self.count < FREE_LOGIN_ATTEMPTS {
+            return self.last;
+        }
+        let backoff = Duration::from_secs(1 << (self.count - FREE_LOGIN_ATTEMPTS).min(16));
+        self.last + backoff.min(MAX_LOGIN_BACKOFF)
+    }
+}
+
+impl SessionStore {
+    pub fn new() -> SessionStore {
+        SessionStore {
+            sessions: Mutex::new(HashMap::new()),
+            lifetime: SESSION_LIFETIME,
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Checks the password against the user's and returns a new session token.
+    pub fn login(&self, username: &str, user: Option<&UserAccount>, password: &str, client: Option<IpAddr>) -> Result<(String, Role), LoginError> {
+        let user = self.check_password(username, user, password, client)?;
+        let token = format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+
+        let mut sessions = self.sessions.lock().unwrap();
+        let now = Instant::now();
+        sessions.retain(|_, session| session.expires > now);
+        sessions.insert(token.clone(), Session {
+            username: user.username.clone(),
+            role: user.role,
+            expires: now + self.lifetime,
+        });
+
+        Ok((token, user.role))
+    }
+
+    /// Checks the password of `username`, who is `user` when the account exists. Clients and usernames
+    /// that failed too often are turned away before the password is hashed.
+    pub fn check_password<'a>(&self, username: &str, user: Option<&'a UserAccount>, password: &str, client: Option<IpAddr>) -> Result<&'a UserAccount, LoginError> {
+        let keys = [Some(format!("user:{}", username)), client.map(|client| format!("client:{}", client))];
+        let keys = keys.into_iter().flatten().collect::<Vec<_>>();
+
+        let now = Instant::now();
+        {
+            let mut failures = self.failures.lock().unwrap();
+            failures.retain(|_, failure| now.duration_since(failure.last) < FORGET_FAILURES_AFTER);
+            let blocked_until = keys.iter().filter_map(|key| failures.get(key)).map(Failures::blocked_until).max();
+            if let Some(wait) = blocked_until.and_then(|until| until.checked_duration_since(now)).filter(|wait| !wait.is_zero()) {
+                return Err(TooManyAttempts(wait));
+            }
+        }
+
+        if let Some(user) = user.filter(|user| user.verify_password(password)) {
+            let mut failures = self.failures.lock().unwrap();
+            for key in &keys {
+                failures.remove(key);
+            }
+            return Ok(user);
+        }
+
+        let mut failures = self.failures.lock().unwrap();
+        for key in keys {
+            let failure = failures.entry(key).or_insert(Failures { count: 0, last: now });
+            failure.count += 1;
+            failure.last = now;
+        }
+        Err(InvalidCredentials)
+    }
+
+    pub fn logout(&self, token: &str) {
+        self.sessions.lock().unwrap().remove(token);
+    }
+
+    /// Ends every session of `username` other than `keep`, like after a password change.
+    pub fn end_sessions_of(&self, username: &str, keep: &str) {
+        self.sessions.lock().unwrap().retain(|token, session| session.username != username || token == keep);
+    }
+
+    /// Finds the session for the token sent as a bearer token or a session cookie.
+    pub fn authenticate(&self, request: &Request) -> Option<Session> {
+        self.session(&session_token(request)?)
+    }
+
+    fn session(&self, token: &str) -> Option<Session> {
+        let mut sessions = self.sessions.lock().unwrap();
+        match sessions.get(token) {
+            Some(session) if session.expires > Instant::now() => Some(session.clone()),
+            Some(_) => {
+                sessions.remove(token);
+                None
+            }
+            None => None,
+        }
+    }
+}
+
+pub fn session_token(request: &Request) -> Option<String> {
+    if let Some(token) = request_header(request, "Authorization").and_then(|value| value.strip_prefix("Bearer ").map(str::to_owned)) {
+        return Some(token.trim().to_owned());
+    }
+
+    request_header(request, "Cookie")?
+        .split(';')
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(name, _)| *name == SESSION_COOKIE)
+        .map(|(_, value)| value.to_owned())
+}
+
+#[derive(Debug, Deserialize)]
+struct Credentials {
+    username: String,
+    password: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct PasswordChange {
+    current_password: String,
+    new_password: String,
+}
+
+#[derive(Debug, Serialize)]
+struct LoginResult {
+    token: String,
+    role: Role,
+}
+
+pub fn login_page<S>(request: Request, _: &S, _: &PathParams) -> Result<(), Box<dyn Error>> {
+    let response = Response::from_string(include_str!("../../pages/login.html"))
+        .with_header("Content-Type: text/html".parse::<Header>().unwrap());
+    request.send_response(response)?;
+    Ok(())
+}
+
+/// Form login from the login page, sets the session cookie and sends the browser to the index.
+pub fn login_form<S: ServerState>(mut request: Request, state: &S, _: &PathParams) -> Result<(), Box<dyn Error>> {
+    let mut body = String::new();
+    request.as_reader().read_to_string(&mut body)?;
+    let credentials = serde_urlencoded::from_str::<Credentials>(&body).ok();
+
+    let result = credentials.ok_or(InvalidCredentials)
+        .and_then(|credentials| login(state, &credentials, client_address(&request)));
+    let response = match result {
+        Ok((token, _)) => Response::empty(303)
+            .with_header(Header::from_bytes(&b"Location"[..], &b"/"[..]).unwrap())
+            .with_header(session_cookie(&token, SESSION_LIFETIME.as_secs())),
+        Err(InvalidCredentials) => Response::empty(303)
+            .with_header(Header::from_bytes(&b"Location"[..], &b"/login?failed=true"[..]).unwrap()),
+        Err(TooManyAttempts(_)) => Response::empty(303)
+            .with_header(Header::from_bytes(&b"Location"[..], &b"/login?failed=throttled"[..]).unwrap()),
+    };
+    request.send_response(response)?;
+    Ok(())
+}
+
+/// Json login for other software, the returned token is sent back as `Authorization: Bearer <token>`.
+pub fn login_api<S: ServerState>(mut request: Request, state: &S, _: &PathParams) -> Result<(), Box<dyn Error>> {
+    let credentials = serde_json::from_reader::<_, Credentials>(request.as_reader());
+
+    let response = match credentials {
+        Ok(credentials) => match login(state, &credentials, client_address(&request)) {
+            Ok((token, role)) => json_response(200, &LoginResult { token, role }),
+            Err(err) => json_response(err.status_code(), &Message::new(err.to_string())),
+        },
+        Err(err) => json_response(400, &Message::new(format!("Bad request: {}", err))),
+    };
+    request.send_response(response)?;
+    Ok(())
+}
+
+pub fn logout<S: ServerState>(request: Request, state: &S, _: &PathParams) -> Result<(), Box<dyn Error>> {
+    if let Some(token) = session_token(&request) {
+        state.sessions().logout(&token);
+    }
+    let response = Response::empty(303)
+        .with_header(Header::from_bytes(&b"Location"[..], &b"/login"[..]).unwrap())
+        .with_header(session_cookie("", 0));
+    request.send_response(response)?;
+    Ok(())
+}
+
+/// Changes the password of whoever is logged in, ending their other sessions.
+pub fn change_password<S: ServerState>(mut request: Request, state: &S, _: &PathParams) -> Result<(), Box<dyn Error>> {
+    let change = match serde_json::from_reader::<_, PasswordChange>(request.as_reader()) {
+        Ok(change) => change,
+        Err(err) => return respond(request, Err(BadRequest(err.to_string()))),
+    };
+    // The router only lets logged in requests through
+    let (Some(token), Some(session)) = (session_token(&request), state.sessions().authenticate(&request)) else {
+        return respond(request, Err(NotFound("session".to_owned())));
+    };
+
+    let user = state.find_user(&session.username);
+    if let Err(err) = state.sessions().check_password(&session.username, user.as_ref(), &change.current_password, client_address(&request)) {
+        warn!("Password change for {} refused: {}", session.username, err);
+        request.send_response(json_response(err.status_code(), &Message::new(err.to_string())))?;
+        return Ok(());
+    }
+
+    let result = check_new_password(&change.new_password)
+        .map_err(BadRequest)
+        .and_then(|_| state.save_password(&session.username, &change.new_password))
+        .map(|_| {
+            state.sessions().end_sessions_of(&session.username, &token);
+            info!("Changed password of {}", session.username);
+            json_response(200, &Message::new("Password changed"))
+        });
+    respond(request, result)
+}
+
+fn login<S: ServerState>(state: &S, credentials: &Credentials, client: Option<IpAddr>) -> Result<(String, Role), LoginError> {
+    let user = state.find_user(&credentials.username);
+    let session = state.sessions().login(&credentials.username, user.as_ref(), &credentials.password, client);
+    match &session {
+        Ok((_, role)) => info!("{} logged in as {:?}", credentials.username, role),
+        Err(err) => warn!("Failed login attempt for {} from {:?}: {}", credentials.username, client, err),
+    }
+    session
+}
+
+pub fn client_address(request: &Request) -> Option<IpAddr> {
+    request.remote_addr().map(|address| address.ip())
+}
+
+fn session_cookie(token: &str, max_age: u64) -> Header {
+    let cookie = format!("{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Strict", SESSION_COOKIE, token, max_age);
+    Header::from_bytes(&b"Set-Cookie"[..], cookie.as_bytes()).unwrap()
+}
+
+#[derive(Debug, PartialEq)]
+pub enum LoginError {
+    InvalidCredentials,
+    /// How long until the next attempt is allowed
+    TooManyAttempts(Duration),
+}
+
+impl LoginError {
+    pub fn status_code(&self) -> u16 {
+        match self {
+            InvalidCredentials => 401,
+            TooManyAttempts(_) => 429,
+        }
+    }
+}
+
+impl Display for LoginError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            InvalidCredentials => {write!(f, "Invalid username or password")}
+            TooManyAttempts(wait) => {write!(f, "Too many failed logins, try again in {} seconds", wait.as_secs().max(1))}
+        }
+    }
+}
+
+impl Error for LoginError {}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use super::*;
+
+    const CLIENT: Option<IpAddr> = Some(IpAddr::V4(Ipv4Addr::LOCALHOST));
+
+    fn login(sessions: &SessionStore, user: &UserAccount, password: &str, client: Option<IpAddr>) -> Result<(String, Role), LoginError> {
+        sessions.login(&user.username, Some(user), password, client)
+    }
+
+    #[test]
+    fn access_follows_the_role_order() {
+        assert!(Access::Public.allows(None));
+        assert!(!Access::GameMaster.allows(None));
+        assert!(Access::GameMaster.allows(Some(Role::GameMaster)));
+        assert!(Access::GameMaster.allows(Some(Role::Admin)));
+        assert!(!Access::Admin.allows(Some(Role::GameMaster)));
+        assert!(Access::Admin.allows(Some(Role::Admin)));
+    }
+
+    #[test]
+    fn sessions_end_on_expiry_logout_and_password_change() {
+        let user = UserAccount::for_tests("gamemaster", "secret", Role::GameMaster);
+        let sessions = SessionStore::new();
+        let (token, role) = login(&sessions, &user, "secret", CLIENT).unwrap();
+        assert_eq!(role, Role::GameMaster);
+        assert_eq!(sessions.session(&token).unwrap().username, "gamemaster");
+        sessions.logout(&token);
+        assert!(sessions.session(&token).is_none());
+
+        let (first, _) = login(&sessions, &user, "secret", CLIENT).unwrap();
+        let (second, _) = login(&sessions, &user, "secret", CLIENT).unwrap();
+        sessions.end_sessions_of("gamemaster", &second);
+        assert!(sessions.session(&first).is_none());
+        assert!(sessions.session(&second).is_some());
+
+        let expired = SessionStore { lifetime: Duration::ZERO, ..SessionStore::new() };
+        let (token, _) = login(&expired, &user, "secret", CLIENT).unwrap();
+        assert!(expired.session(&token).is_none());
+        assert!(expired.sessions.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn repeated_failures_have_to_wait() {
+        let user = UserAccount::for_tests("admin", "secret", Role::Admin);
+        let sessions = SessionStore::new();
+        for _ in 0..FREE_LOGIN_ATTEMPTS {
+            assert_eq!(login(&sessions, &user, "guess", CLIENT).unwrap_err(), InvalidCredentials);
+        }
+        // Even the right password has to wait, and so does the same user from elsewhere
+        assert!(matches!(login(&sessions, &user, "secret", CLIENT), Err(TooManyAttempts(_))));
+        assert!(matches!(login(&sessions, &user, "secret", None), Err(TooManyAttempts(_))));
+        assert_eq!(sessions.login("nobody", None, "guess", Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)))).unwrap_err(), InvalidCredentials);
+
+        // The first wait is a second
+        std::thread::sleep(Duration::from_millis(1100));
+        assert!(login(&sessions, &user, "secret", CLIENT).is_ok());
+        assert!(sessions.failures.lock().unwrap().keys().all(|key| key.contains("10.0.0.2") || key == "user:nobody"));
+    }
+}
@@ -0,0 +1,87 @@
+use std::error::Error;
+use std::io;
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{error, info};
+use tiny_http::{Request, Response, StatusCode};
+use crate::backup::archive::{export, read_archive, DeviceFiles, RestoreOptions, RestoreReport};
+use crate::config::settings::Settings;
+use crate::config::setup::DeviceConfiguration;
+use crate::events::event_bus::DeviceEvent;
+use crate::web_server::api::{json_response, respond, ApiError, DeviceIdentity};
+use crate::web_server::app_state::AppState;
+use crate::web_server::file_server::header;
+use crate::web_server::router::{query_param, PathParams, SendResponse};
+
+/// Streams a tar archive of the whole device, it is written while it is sent so it never touches the sd card.
+pub fn export_backup(request: Request, state: &AppState, _: &PathParams) -> Result<(), Box<dyn Error>> {
+    let (reader, writer) = io::pipe()?;
+    let identity = DeviceIdentity::from_config(&state.config());
+    let created = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let file_name = format!("clue-backup-{}-{}.tar", identity.device_uuid, created);
+
+    let files_dir = state.files_dir();
+    let config_path = state.config_path();
+    let cards = state.rfid.cards().clone();
+    thread::spawn(move || {
+        let device = DeviceFiles { files_dir: &files_dir, config_path: &config_path, cards: &cards };
+        // A client that stops reading closes the pipe and ends the export here
+        if let Err(err) = export(writer, &device, identity) {
+            error!("Backup export stopped: {}", err);
+        }
+    });
+
+    let headers = vec![
+        header("Content-Type", "application/x-tar"),
+        header("Content-Disposition", &format!("attachment; filename=\"{}\"", file_name)),
+    ];
+    request.send_response(Response::new(StatusCode(200), headers, reader, None, None))?;
+    info!("Sent backup {}", file_name);
+    Ok(())
+}
+
+/// Restores an archive sent as the request body, `?dry_run=true` only reports what would change.
+/// Media and pairings in the archive are added to what is on the device, nothing is removed.
+/// The device keeps its `device_uuid` unless `?take_identity=true` asks to replace the backed up device.
+pub fn import_backup(mut request: Request, state: &AppState, _: &PathParams) -> Result<(), Box<dyn Error>> {
+    let flag = |name| query_param(request.url(), name).is_some_and(|value| value != "false");
+    let options = RestoreOptions { dry_run: flag("dry_run"), take_identity: flag("take_identity") };
+    let result = restore(&mut request, state, options).map(|report| json_response(200, &report));
+    respond(request, result)
+}
+
+fn restore(request: &mut Request, state: &AppState, options: RestoreOptions) -> Result<RestoreReport, ApiError> {
+    let files_dir = state.files_dir();
+    let config_path = state.config_path();
+    let device = DeviceFiles { files_dir: &files_dir, config_path: &config_path, cards: state.rfid.cards() };
+
+    let running = state.config().clone();
+    let restore = read_archive(request.as_reader(), &device, &running, options)?;
+    let config = restore.config().cloned();
+    let report = restore.apply(&device)?;
+    if options.dry_run {
+        return Ok(report);
+    }
+
+    if let Some(config) = config {
+        restore_config(state, config)?;
+    }
+    let files = report.media.iter().chain(&report.screens).map(|file| file.name.clone()).collect::<Vec<_>>();
+    if !files.is_empty() {
+        state.events.publish(DeviceEvent::UploadCompleted { files });
+    }
+    Ok(report)
+}
+
+/// Saves the restored configuration and applies what can change while running, including the accounts.
+fn restore_config(state: &AppState, restored: DeviceConfiguration) -> Result<(), ApiError> {
+    let mut running = state.device_config.write().unwrap();
+    restored.save(state.config_path())?;
+
+    Settings::from_config(&restored).apply_live_to(&mut running);
+    running.users = restored.users;
+    state.rfid.set_clue_timeout(running.clue_timeout);
+    info!("Restored configuration");
+    Ok(())
+}
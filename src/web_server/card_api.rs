@@ -0,0 +1,138 @@
+use std::error::Error;
+
+use log::info;
+use serde::{Deserialize, Serialize};
+use tiny_http::Request;
+use crate::gpio::outputs::{OutputAction, OutputMode};
+use crate::rfid::sequencer::CardAction;
+use crate::web_server::api::{json_response, media_path, respond, Message};
+use crate::web_server::api::ApiError;
+use crate::web_server::api::ApiError::{BadRequest, NotFound};
+use crate::web_server::app_state::AppState;
+use crate::web_server::router::{query_param, PathParams};
+
+/// Longest a single wait or text can take, the whole sequence runs on the rfid thread and no card can be scanned
+/// until it is done
+const MAX_STEP_SECONDS: u64 = 600;
+
+#[derive(Debug, Deserialize)]
+struct ReassignCard {
+    media: String,
+}
+
+#[derive(Debug, Serialize)]
+struct ClearedCards {
+    removed: Vec<String>,
+}
+
+pub fn list_cards(request: Request, state: &AppState, _: &PathParams) -> Result<(), Box<dyn Error>> {
+    let result = state.rfid.cards().list()
+        .map(|cards| json_response(200, &cards))
+        .map_err(|err| err.into());
+    respond(request, result)
+}
+
+/// Points an already paired card at a different file in the library.
+pub fn reassign_card(mut request: Request, state: &AppState, params: &PathParams) -> Result<(), Box<dyn Error>> {
+    let card_id = params.get("id").unwrap_or_default().to_owned();
+    let reassign = serde_json::from_reader::<_, ReassignCard>(request.as_reader());
+
+    let result = reassign
+        .map_err(|err| BadRequest(err.to_string()))
+        .and_then(|reassign| {
+            let media = media_path(state, &reassign.media)?;
+            let cards = state.rfid.cards();
+            if !cards.is_paired(&card_id)? {
+                return Err(NotFound(card_id.clone()));
+            }
+            cards.reassign(&card_id, &media)?;
+            info!("Card {} reassigned to {}", card_id, media.display());
+            Ok(json_response(200, &Message::new(format!("Card {} now plays {}", card_id, reassign.media))))
+        });
+    respond(request, result)
+}
+
+/// Replaces what a paired card does when it is scanned.
+pub fn set_card_actions(mut request: Request, state: &AppState, params: &PathParams) -> Result<(), Box<dyn Error>> {
+    let card_id = params.get("id").unwrap_or_default().to_owned();
+    let actions = serde_json::from_reader::<_, Vec<CardAction>>(request.as_reader());
+
+    let result = actions
+        .map_err(|err| BadRequest(err.to_string()))
+        .and_then(|actions| {
+            for action in &actions {
+                validate_action(state, action)?;
+            }
+            let cards = state.rfid.cards();
+            if !cards.is_paired(&card_id)? {
+                return Err(NotFound(card_id.clone()));
+            }
+            cards.set_actions(&card_id, &actions)?;
+            info!("Card {} now runs {} actions", card_id, actions.len());
+            Ok(json_response(200, &Message::new(format!("Card {} now runs {} actions", card_id, actions.len()))))
+        });
+    respond(request, result)
+}
+
+fn validate_action(state: &AppState, action: &CardAction) -> Result<(), ApiError> {
+    match action {
+        CardAction::PlayMedia { media } => media_path(state, media).map(|_| ()),
+        CardAction::ShowText { text, .. } if text.trim().is_empty() => Err(BadRequest("Text to show can't be empty".to_owned())),
+        CardAction::ShowText { seconds, .. } | CardAction::Wait { seconds } if *seconds > MAX_STEP_SECONDS => {
+            Err(BadRequest(format!("A step can last at most {} seconds", MAX_STEP_SECONDS)))
+        }
+        CardAction::FireWebhook { name } if name.trim().is_empty() => Err(BadRequest("A webhook action needs a name".to_owned())),
+        CardAction::SetOutput { output, .. } if !state.outputs.contains(output) => Err(BadRequest(format!("There is no output named {}", output))),
+        CardAction::SetOutput { mode: OutputMode::Pulse { millis: 0 }, .. } => Err(BadRequest("A pulse has to last at least 1ms".to_owned())),
+        _ => Ok(()),
+    }
+}
+
+/// Replaces the outputs a paired card switches when it is scanned.
+pub fn set_card_outputs(mut request: Request, state: &AppState, params: &PathParams) -> Result<(), Box<dyn Error>> {
+    let card_id = params.get("id").unwrap_or_default().to_owned();
+    let outputs = serde_json::from_reader::<_, Vec<OutputAction>>(request.as_reader());
+
+    let result = outputs
+        .map_err(|err| BadRequest(err.to_string()))
+        .and_then(|outputs| {
+            if let Some(unknown) = outputs.iter().find(|action| !state.outputs.contains(&action.output)) {
+                return Err(BadRequest(format!("There is no output named {}", unknown.output)));
+            }
+            if outputs.iter().any(|action| action.mode == OutputMode::Pulse { millis: 0 }) {
+                return Err(BadRequest("A pulse has to last at least 1ms".to_owned()));
+            }
+            let cards = state.rfid.cards();
+            if !cards.is_paired(&card_id)? {
+                return Err(NotFound(card_id.clone()));
+            }
+            cards.set_outputs(&card_id, &outputs)?;
+            info!("Card {} now switches {} outputs", card_id, outputs.len());
+            Ok(json_response(200, &Message::new(format!("Card {} now switches {} outputs", card_id, outputs.len()))))
+        });
+    respond(request, result)
+}
+
+pub fn unpair_card(request: Request, state: &AppState, params: &PathParams) -> Result<(), Box<dyn Error>> {
+    let card_id = params.get("id").unwrap_or_default();
+    let result = state.rfid.cards().unpair(card_id)
+        .map_err(|err| err.into())
+        .and_then(|removed| if removed { Ok(()) } else { Err(NotFound(card_id.to_owned())) })
+        .map(|_| {
+            info!("Card {} unpaired", card_id);
+            json_response(200, &Message::new(format!("Card {} unpaired", card_id)))
+        });
+    respond(request, result)
+}
+
+/// Unpairs every card, or with `?missing=true` only cards whose file is no longer in the library.
+pub fn clear_cards(request: Request, state: &AppState, _: &PathParams) -> Result<(), Box<dyn Error>> {
+    let only_missing = query_param(request.url(), "missing").is_some_and(|missing| missing == "true");
+    let result = state.rfid.cards().clear(|card| !only_missing || !card.file_exists)
+        .map(|removed| {
+            info!("Cleared {} card pairings", removed.len());
+            json_response(200, &ClearedCards { removed })
+        })
+        .map_err(|err| err.into());
+    respond(request, result)
+}
@@ -0,0 +1,413 @@
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tiny_http::Request;
+use uuid::Uuid;
+use crate::config::setup::DuplicatePolicy;
+use crate::events::event_bus::DeviceEvent;
+use crate::metrics::device_metrics::Metrics;
+use crate::web_server::api::{json_response, respond, ApiError, Message};
+use crate::web_server::api::ApiError::{BadRequest, Conflict, NotFound};
+use crate::web_server::app_state::AppState;
+use crate::web_server::router::{query_param, PathParams};
+use crate::web_server::upload_handler::{check_extension, move_into_library, sanitize_file_name, staging_dir, validate_media, UploadPolicy};
+
+const SESSIONS_DIR: &str = "sessions";
+const SESSION_FILE: &str = "session.json";
+const DATA_FILE: &str = "data.part";
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Resumable uploads for files too large to send in one request over an unreliable network.
+///
+/// A client creates a session, `PUT`s chunks at the offset the device reports as received,
+/// and finalizes with the file's sha256. Sessions live on disk so they survive a restart.
+pub struct UploadSessions {
+    root: PathBuf,
+    busy: Arc<Mutex<HashSet<String>>>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct UploadSession {
+    id: String,
+    name: String,
+    size: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct UploadStatus {
+    id: String,
+    name: String,
+    size: u64,
+    received: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct CreateUpload {
+    name: String,
+    size: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct FinalizeUpload {
+    sha256: String,
+}
+
+#[derive(Debug, Serialize)]
+struct FinalizedUpload {
+    name: String,
+    size: u64,
+}
+
+/// Marks a session as having a request in flight, so two chunks can't be appended at once
+/// and the cleanup thread leaves it alone.
+struct BusyGuard<'a> {
+    sessions: &'a UploadSessions,
+    id: String,
+}
+
+impl Drop for BusyGuard<'_> {
+    fn drop(&mut self) {
+        self.sessions.busy.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl UploadSessions {
+    pub fn new(files_dir: &Path) -> UploadSessions {
+        UploadSessions {
+            root: staging_dir(files_dir).join(SESSIONS_DIR),
+            busy: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+
+    /// Removes sessions that have not received data for `timeout` now and then every hour.
+    pub fn start_cleanup_thread(&self, timeout: Duration) {
+        let root = self.root.clone();
+        let busy = self.busy.clone();
+        thread::spawn(move || loop {
+            remove_stale_sessions(&root, &busy, timeout);
+            thread::sleep(CLEANUP_INTERVAL);
+        });
+    }
+
+    fn create(&self, create: &CreateUpload, policy: &UploadPolicy) -> Result<UploadStatus, ApiError> {
+        let name = sanitize_file_name(&create.name).ok_or(BadRequest(format!("Invalid file name: {}", create.name)))?;
+        check_extension(&name, policy).map_err(BadRequest)?;
+        if create.size > policy.max_size {
+            return Err(BadRequest(format!("File is larger than {} bytes", policy.max_size)));
+        }
+
+        let session = UploadSession { id: Uuid::new_v4().to_string(), name, size: create.size };
+        let dir = self.root.join(&session.id);
+        fs::create_dir_all(&dir)?;
+        File::create(dir.join(DATA_FILE))?;
+        fs::write(dir.join(SESSION_FILE), serde_json::to_vec(&session).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?)?;
+
+        info!("Created upload session {} for {} ({} bytes)", session.id, session.name, session.size);
+        UploadSessions::status(&session, &dir)
+    }
+
+    /// Appends `body` to the session, `offset` has to match what has been received so far.
+    fn append<R: Read>(&self, id: &str, offset: Option<u64>, body: R, metrics: &Metrics) -> Result<UploadStatus, ApiError> {
+        let _guard = self.lock(id)?;
+        let (session, dir) = self.load(id)?;
+        let received = received(&dir)?;
+        match offset {
+            None => return Err(BadRequest("offset query parameter is required".to_owned())),
+            Some(offset) if offset != received => return Err(Conflict(format!("Expected offset {}", received))),
+            Some(_) => {}
+        }
+
+        let remaining = session.size - received;
+        let mut file = OpenOptions::new().append(true).open(dir.join(DATA_FILE))?;
+        // Read one byte past the end so a body that is too long can be detected
+        let written = io::copy(&mut body.take(remaining + 1), &mut file)?;
+        metrics.upload_bytes.inc_by(written.min(remaining));
+        if written > remaining {
+            file.set_len(session.size)?;
+            return Err(BadRequest(format!("Chunk goes past the end of the {} byte file", session.size)));
+        }
+        file.flush()?;
+
+        UploadSessions::status(&session, &dir)
+    }
+
+    /// Checks the file is complete and matches the checksum, then moves it into `files_dir`.
+    fn finalize(&self, id: &str, finalize: &FinalizeUpload, files_dir: &Path, duplicates: DuplicatePolicy) -> Result<FinalizedUpload, ApiError> {
+        let _guard = self.lock(id)?;
+        let (session, dir) = self.load(id)?;
+        let data = dir.join(DATA_FILE);
+
+        let received = received(&dir)?;
+        if received != session.size {
+            return Err(Conflict(format!("Only {} of {} bytes received", received, session.size)));
+        }
+
+        let checksum = sha256_file(&data)?;
+        if !checksum.eq_ignore_ascii_case(finalize.sha256.trim()) {
+            return Err(BadRequest(format!("Checksum mismatch, received file has sha256 {}", checksum)));
+        }
+
+        let name = validate_media(&data, &session.name)
+            .and_then(|_| move_into_library(&data, files_dir, &session.name, duplicates))
+            .map_err(BadRequest)?;
+        fs::remove_dir_all(&dir)?;
+
+        info!("Finished upload session {} as {}", id, name);
+        Ok(FinalizedUpload { name, size: session.size })
+    }
+
+    fn abort(&self, id: &str) -> Result<(), ApiError> {
+        let _guard = self.lock(id)?;
+        fs::remove_dir_all(self.session_dir(id)?)?;
+        info!("Aborted upload session {}", id);
+        Ok(())
+    }
+
+    fn session_dir(&self, id: &str) -> Result<PathBuf, ApiError> {
+        // Ids are generated by us, anything else can't be a session
+        if Uuid::try_parse(id).is_err() {
+            return Err(NotFound(id.to_owned()));
+        }
+        let dir = self.root.join(id);
+        if !dir.join(SESSION_FILE).is_file() {
+            return Err(NotFound(id.to_owned()));
+        }
+        Ok(dir)
+    }
+
+    fn load(&self, id: &str) -> Result<(UploadSession, PathBuf), ApiError> {
+        let dir = self.session_dir(id)?;
+        let session = serde_json::from_slice::<UploadSession>(&fs::read(dir.join(SESSION_FILE))?)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        Ok((session, dir))
+    }
+
+    fn lock(&self, id: &str) -> Result<BusyGuard<'_>, ApiError> {
+        if !self.busy.lock().unwrap().insert(id.to_owned()) {
+            return Err(Conflict(format!("Upload {} is already receiving data", id)));
+        }
+        Ok(BusyGuard { sessions: self, id: id.to_owned() })
+    }
+
+    fn status(session: &UploadSession, dir: &Path) -> Result<UploadStatus, ApiError> {
+        Ok(UploadStatus {
+            id: session.id.clone(),
+            name: session.name.clone(),
+            size: session.size,
+            received: received(dir)?,
+        })
+    }
+}
+
+pub fn create_upload(mut request: Request, state: &AppState, _: &PathParams) -> Result<(), Box<dyn Error>> {
+    let result = serde_json::from_reader::<_, CreateUpload>(request.as_reader())
+        .map_err(|err| BadRequest(err.to_string()))
+        .and_then(|create| state.uploads.create(&create, &UploadPolicy::from_config(&state.config())))
+        .map(|status| json_response(201, &status));
+    respond(request, result)
+}
+
+pub fn upload_status(request: Request, state: &AppState, params: &PathParams) -> Result<(), Box<dyn Error>> {
+    let id = params.get("id").unwrap_or_default();
+    let result = state.uploads.load(id)
+        .and_then(|(session, dir)| UploadSessions::status(&session, &dir))
+        .map(|status| json_response(200, &status));
+    respond(request, result)
+}
+
+/// Appends the request body at `?offset=`, which has to match what has been received so far.
+/// A chunk cut off part way is kept, the client resumes from the offset the device reports.
+pub fn upload_chunk(mut request: Request, state: &AppState, params: &PathParams) -> Result<(), Box<dyn Error>> {
+    let id = params.get("id").unwrap_or_default().to_owned();
+    let offset = query_param(request.url(), "offset").and_then(|offset| offset.parse::<u64>().ok());
+
+    let result = state.uploads.append(&id, offset, request.as_reader(), &state.metrics)
+        .map(|status| json_response(200, &status));
+    respond(request, result)
+}
+
+/// Checks the file is complete and matches the checksum, then moves it into the library.
+pub fn finalize_upload(mut request: Request, state: &AppState, params: &PathParams) -> Result<(), Box<dyn Error>> {
+    let id = params.get("id").unwrap_or_default().to_owned();
+    let finalize = serde_json::from_reader::<_, FinalizeUpload>(request.as_reader());
+
+    let result = finalize
+        .map_err(|err| BadRequest(err.to_string()))
+        .and_then(|finalize| {
+            let duplicates = state.config().duplicate_uploads;
+            state.uploads.finalize(&id, &finalize, &state.files_dir(), duplicates)
+        })
+        .map(|finalized| {
+            state.events.publish(DeviceEvent::UploadCompleted { files: vec![finalized.name.clone()] });
+            state.thumbnails.generate_in_background(vec![finalized.name.clone()]);
+            json_response(201, &finalized)
+        });
+    respond(request, result)
+}
+
+pub fn abort_upload(request: Request, state: &AppState, params: &PathParams) -> Result<(), Box<dyn Error>> {
+    let id = params.get("id").unwrap_or_default().to_owned();
+    let result = state.uploads.abort(&id)
+        .map(|_| json_response(200, &Message::new(format!("Removed upload {}", id))));
+    respond(request, result)
+}
+
+fn received(dir: &Path) -> io::Result<u64> {
+    Ok(fs::metadata(dir.join(DATA_FILE))?.len())
+}
+
+pub fn sha256_file(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Removes sessions without activity for `timeout`, skipping the ones a request is working on.
+fn remove_stale_sessions(root: &Path, busy: &Mutex<HashSet<String>>, timeout: Duration) {
+    let Ok(entries) = fs::read_dir(root) else {
+        return;
+    };
+
+    for dir in entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()) {
+        // The data file is touched by every chunk so its mtime is the last activity
+        let last_activity = fs::metadata(dir.join(DATA_FILE))
+            .or_else(|_| fs::metadata(&dir))
+            .and_then(|metadata| metadata.modified())
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        if last_activity.elapsed().unwrap_or_default() <= timeout {
+            continue;
+        }
+
+        // Held while removing so a request can't pick the session up half way
+        let busy = busy.lock().unwrap();
+        if dir.file_name().is_some_and(|id| busy.contains(id.to_string_lossy().as_ref())) {
+            continue;
+        }
+        warn!("Removing stale upload session {}", dir.display());
+        if let Err(err) = fs::remove_dir_all(&dir) {
+            error!("Failed to remove stale upload session {}: {:?}", dir.display(), err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sha2::{Digest, Sha256};
+    use tempfile::TempDir;
+    use super::*;
+
+    struct TestUploads {
+        files_dir: TempDir,
+        uploads: UploadSessions,
+        metrics: Metrics,
+    }
+
+    impl TestUploads {
+        fn new() -> TestUploads {
+            let files_dir = TempDir::new().unwrap();
+            let uploads = UploadSessions::new(files_dir.path());
+            TestUploads { files_dir, uploads, metrics: Metrics::new() }
+        }
+
+        /// Starts uploading a `size` byte `clue.png`
+        fn create(&self, size: u64) -> String {
+            let policy = UploadPolicy { max_size: 100, allowed_extensions: vec!["png".to_owned()], duplicates: DuplicatePolicy::Reject };
+            self.uploads.create(&CreateUpload { name: "clue.png".to_owned(), size }, &policy).unwrap().id
+        }
+
+        fn append(&self, id: &str, offset: Option<u64>, chunk: &[u8]) -> Result<UploadStatus, ApiError> {
+            self.uploads.append(id, offset, chunk, &self.metrics)
+        }
+
+        fn finalize(&self, id: &str, sha256: &str) -> Result<FinalizedUpload, ApiError> {
+            self.uploads.finalize(id, &FinalizeUpload { sha256: sha256.to_owned() }, self.files_dir.path(), DuplicatePolicy::Reject)
+        }
+    }
+
+    #[test]
+    fn create_checks_name_extension_and_size() {
+        let test = TestUploads::new();
+        let policy = UploadPolicy { max_size: 100, allowed_extensions: vec!["png".to_owned()], duplicates: DuplicatePolicy::Reject };
+        for (name, size) in [("..", 10), ("notes.txt", 10), ("clue.png", 101)] {
+            let result = test.uploads.create(&CreateUpload { name: name.to_owned(), size }, &policy);
+            assert_eq!(result.map(|_| ()).unwrap_err().status_code(), 400, "{} was accepted", name);
+        }
+    }
+
+    #[test]
+    fn chunks_have_to_continue_at_the_received_offset() {
+        let test = TestUploads::new();
+        let id = test.create(6);
+        assert_eq!(test.append(&id, None, b"abc").unwrap_err().status_code(), 400);
+        assert_eq!(test.append(&id, Some(0), b"abc").unwrap().received, 3);
+        assert_eq!(test.append(&id, Some(0), b"abc").unwrap_err().status_code(), 409);
+        assert_eq!(test.append(&id, Some(5), b"def").unwrap_err().status_code(), 409);
+        assert_eq!(test.append(&id, Some(3), b"def").unwrap().received, 6);
+        assert_eq!(test.append(&Uuid::new_v4().to_string(), Some(0), b"abc").unwrap_err().status_code(), 404);
+        assert_eq!(test.metrics.upload_bytes.get(), 6);
+    }
+
+    #[test]
+    fn a_chunk_past_the_end_is_cut_off() {
+        let test = TestUploads::new();
+        let id = test.create(4);
+        assert_eq!(test.append(&id, Some(0), b"abcdef").unwrap_err().status_code(), 400);
+        let (session, dir) = test.uploads.load(&id).unwrap();
+        assert_eq!(UploadSessions::status(&session, &dir).unwrap().received, 4);
+    }
+
+    #[test]
+    fn finalize_needs_the_whole_file_with_its_checksum() {
+        let test = TestUploads::new();
+        let id = test.create(6);
+        let sha256 = hex::encode(Sha256::digest(b"abcdef"));
+        test.append(&id, Some(0), b"abc").unwrap();
+        assert_eq!(test.finalize(&id, &sha256).unwrap_err().status_code(), 409);
+
+        test.append(&id, Some(3), b"def").unwrap();
+        assert_eq!(test.finalize(&id, &hex::encode(Sha256::digest(b"other"))).unwrap_err().status_code(), 400);
+        assert!(!test.files_dir.path().join("clue.png").exists());
+
+        assert_eq!(test.finalize(&id, &sha256.to_uppercase()).unwrap().name, "clue.png");
+        assert_eq!(fs::read(test.files_dir.path().join("clue.png")).unwrap(), b"abcdef");
+        assert_eq!(test.finalize(&id, &sha256).unwrap_err().status_code(), 404);
+    }
+
+    #[test]
+    fn abort_removes_the_session() {
+        let test = TestUploads::new();
+        let id = test.create(6);
+        test.append(&id, Some(0), b"abc").unwrap();
+        test.uploads.abort(&id).unwrap();
+        assert_eq!(test.append(&id, Some(3), b"def").unwrap_err().status_code(), 404);
+        assert_eq!(test.uploads.abort(&id).unwrap_err().status_code(), 404);
+    }
+
+    #[test]
+    fn cleanup_skips_sessions_with_a_request_in_flight() {
+        let test = TestUploads::new();
+        let busy = test.create(6);
+        let idle = test.create(6);
+        let _guard = test.uploads.lock(&busy).unwrap();
+        let hour_ago = SystemTime::now() - Duration::from_secs(60 * 60);
+        for id in [&busy, &idle] {
+            File::options().write(true).open(test.uploads.root.join(id).join(DATA_FILE)).unwrap().set_modified(hour_ago).unwrap();
+        }
+
+        remove_stale_sessions(&test.uploads.root, &test.uploads.busy, Duration::from_secs(60));
+        assert!(test.uploads.session_dir(&busy).is_ok());
+        assert_eq!(test.uploads.session_dir(&idle).unwrap_err().status_code(), 404);
+    }
+}
@@ -0,0 +1,58 @@
+use std::error::Error;
+use std::io::Write;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+
+use log::{error, info};
+use tiny_http::Request;
+use crate::events::event_bus::DeviceEvent;
+use crate::web_server::app_state::AppState;
+use crate::web_server::router::{record_response_status, PathParams};
+
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Server-Sent Events stream of every `DeviceEvent`.
+///
+/// The stream lives on its own thread so an open dashboard does not tie up an http worker.
+pub fn events(request: Request, state: &AppState, _: &PathParams) -> Result<(), Box<dyn Error>> {
+    let events = state.events.subscribe();
+    let remote = request.remote_addr().map(|addr| addr.to_string()).unwrap_or_default();
+
+    record_response_status(200);
+    let mut writer = request.into_writer();
+    writer.write_all(b"HTTP/1.1 200 OK\r\n\
+        Content-Type: text/event-stream\r\n\
+        Cache-Control: no-cache\r\n\
+        Connection: close\r\n\r\n")?;
+    writer.write_all(format!("retry: {}\n\n", KEEP_ALIVE_INTERVAL.as_millis()).as_bytes())?;
+    writer.flush()?;
+
+    info!("Event stream opened for {}", remote);
+    thread::Builder::new()
+        .name("event-stream".to_owned())
+        .spawn(move || {
+            if let Err(err) = stream_events(writer, events) {
+                info!("Event stream for {} closed: {}", remote, err);
+            }
+        })?;
+    Ok(())
+}
+
+fn stream_events(mut writer: Box<dyn Write + Send>, events: Receiver<DeviceEvent>) -> Result<(), Box<dyn Error>> {
+    loop {
+        match events.recv_timeout(KEEP_ALIVE_INTERVAL) {
+            Ok(event) => {
+                let data = serde_json::to_string(&event)?;
+                writer.write_all(format!("event: {}\ndata: {}\n\n", event.name(), data).as_bytes())?;
+            }
+            // Comments keep proxies and the browser from timing out an idle stream
+            Err(RecvTimeoutError::Timeout) => writer.write_all(b": keep-alive\n\n")?,
+            Err(RecvTimeoutError::Disconnected) => {
+                error!("Event bus closed");
+                return Ok(());
+            }
+        }
+        writer.flush()?;
+    }
+}
@@ -1,71 +1,74 @@
 use std::error::Error;
 use std::fmt::{Display, Formatter};
 use std::{fmt, fs};
-use std::env::current_dir;
-use std::fs::File;
-use std::io::Read;
 
 use log::{debug, error};
-use tiny_http::{Header, Request, Response};
+use tiny_http::{Request, Response};
 use serde::Deserialize;
-use crate::rfid::rfid_manger::Rfid;
+use crate::config::users::Role;
 use crate::video_handler::media_manager::Command::PlayMedia;
-use crate::video_handler::media_manager::VlcManager;
-use crate::web_server::file_action_handler::ActionFormError::{FailedToDecodeForm, FailedToDelete, IoError, RfidReaderStillWaiting};
+use crate::web_server::app_state::AppState;
+use crate::web_server::file_action_handler::ActionFormError::{FailedToDecodeForm, FailedToDelete, InvalidMediaPath, IoError, NotAllowed, RfidReaderStillWaiting};
+use crate::web_server::file_server::serve_file;
+use crate::web_server::router::SendResponse;
+use crate::web_server::media_path::{resolve_media_path, MediaPathError};
 use crate::web_server::file_action_handler::Actions::{Delete, Download, PairToCard, Play};
 
-pub fn route_action_form(mut request: Request, media_manager: &VlcManager, rfid_manger: &Rfid) -> Result<Actions, ActionFormError> {
+pub fn route_action_form(mut request: Request, state: &AppState, role: Role) -> Result<Actions, ActionFormError> {
     // Read form data
     let mut raw_form_data = String::new();
     request.as_reader().read_to_string(&mut raw_form_data).unwrap();
     debug!("Raw form data: {:?}", raw_form_data);
-    let project_dir = current_dir()?;
 
     match serde_urlencoded::from_str::<FormData>(&raw_form_data) {
         Ok(form_data) =>{
             debug!("Parsed form data: {:?}", form_data);
-            let media_dir = project_dir.join("files").join(form_data.info.clone());
+            let media_dir = match resolve_media_path(&state.files_dir(), &form_data.info) {
+                Ok(media_dir) => media_dir,
+                Err(error) => {
+                    request.send_response(Response::from_string("Invalid file").with_status_code(400))?;
+                    error!("Rejected file from form: {}", error);
+                    return Err(InvalidMediaPath(error));
+                }
+            };
+
+            if role < form_data.action.required_role() {
+                request.send_response(Response::from_string("not allowed").with_status_code(403))?;
+                error!("{:?} is not allowed to preform {:?}", role, form_data.action);
+                return Err(NotAllowed);
+            }
 
             match form_data.action {
                 PairToCard => {
-                    if !rfid_manger.is_waiting() {
-                        rfid_manger.pair_card(media_dir.as_path());
-                        request.respond(Response::from_string("paired card"))?;
+                    if !state.rfid.is_waiting() {
+                        state.rfid.pair_card(media_dir.as_path());
+                        request.send_response(Response::from_string("paired card"))?;
                         Ok(PairToCard)
                     } else {
-                        request.respond(Response::from_string("still waiting").with_status_code(401))?;
+                        request.send_response(Response::from_string("still waiting").with_status_code(401))?;
                         error!("Will not send idle command as the reader is still waiting for video to complete");
                         Err(RfidReaderStillWaiting)
                     }
                 }
                 Play => {
-                    media_manager.send_command(PlayMedia(media_dir)).unwrap_or_else(|error|{
+                    state.media_manager.send_command(PlayMedia(media_dir)).unwrap_or_else(|error|{
                         error!("Failed to send play command to media manager: {:?}", error);
                     });
-                    request.respond(Response::from_string("played video"))?;
+                    request.send_response(Response::from_string("played video"))?;
                     Ok(Play)
                 }
                 Download => {
-                    let mut file = File::open(media_dir)?;
-                    let mut file_content = Vec::new();
-                    file.read_to_end(&mut file_content)?;
-
-                    // Create a response with the file's content
-                    let response = Response::from_data(file_content)
-                        .with_header("Content-Type: application/octet-stream".parse::<Header>().unwrap())
-                        .with_header(format!("Content-Disposition: attachment; filename={}", form_data.info).parse::<Header>().unwrap());
-
-                    // Send the response
-                    request.respond(response)?;
+                    serve_file(request, &media_dir, Some(&form_data.info))?;
                     Ok(Download)
                 }
                 Delete => {
                     if let Err(error) = fs::remove_file(media_dir.clone()) {
                         error!("Failed to remove file: {}", error);
-                        request.respond(Response::from_string("").with_status_code(400))?;
-                        return Err(FailedToDelete(media_dir.display().to_string()));
+                        request.send_response(Response::from_string("").with_status_code(400))?;
+                        Err(FailedToDelete(media_dir.display().to_string()))
                     } else {
-                        request.respond(Response::from_string("Removed File")).unwrap();
+                        state.thumbnails.remove(&form_data.info);
+                        request.send_response(Response::from_string("Removed File")).unwrap();
                         Ok(Delete)
                     }
                 }
@@ -73,7 +76,7 @@ pub fn route_action_form(mut request: Request, media_manager: &VlcManager, rfid_
         }
         Err(error) => {
             error!("Failed to parse string from form: {:?}", error);
-            request.respond(Response::from_string("Invalid form").with_status_code(400)).unwrap_or_else(|error|{
+            request.send_response(Response::from_string("Invalid form").with_status_code(400)).unwrap_or_else(|error|{
                 error!("Failed to send response to client: {:?}", error);
             });
             Err(FailedToDecodeForm)
@@ -88,7 +91,9 @@ pub enum ActionFormError {
     FailedToDelete(String),
     IoError(std::io::Error),
     FailedToDecodeForm,
-    RfidReaderStillWaiting
+    RfidReaderStillWaiting,
+    NotAllowed,
+    InvalidMediaPath(MediaPathError)
 }
 impl Display for ActionFormError {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
@@ -97,6 +102,8 @@ impl Display for ActionFormError {
             FailedToDecodeForm => {write!(f, "Failed to decode form data")}
             IoError(error) => {write!(f, "Io operation failed: {}", error)}
             RfidReaderStillWaiting => {write!(f, "Tried to command rfid reader wile still waiting on video to complete")}
+            NotAllowed => {write!(f, "Account is not allowed to preform this action")}
+            InvalidMediaPath(error) => {write!(f, "{}", error)}
         }
     }
 }
@@ -122,4 +129,13 @@ pub enum Actions {
     Play,
     Download,
     Delete
-}
\ No newline at end of file
+}
+
+impl Actions {
+    fn required_role(&self) -> Role {
+        match self {
+            Play => Role::GameMaster,
+            PairToCard | Download | Delete => Role::Admin,
+        }
+    }
+}
@@ -0,0 +1,224 @@
+use std::fs::File;
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::info;
+use tiny_http::{Header, Request, Response, StatusCode};
+use crate::web_server::api::media_type;
+use crate::web_server::router::SendResponse;
+
+/// Streams a file to the client without buffering it in memory.
+///
+/// Honours `Range` (single range only), `If-Range`, `If-None-Match` and `If-Modified-Since`,
+/// and always advertises `ETag`, `Last-Modified` and `Accept-Ranges`.
+/// When `attachment_name` is set the browser is told to save the file under that name.
+pub fn serve_file(request: Request, path: &Path, attachment_name: Option<&str>) -> io::Result<()> {
+    let mut file = File::open(path)?;
+    let metadata = file.metadata()?;
+    let length = metadata.len();
+    let modified = metadata.modified().unwrap_or(UNIX_EPOCH);
+    let etag = entity_tag(length, modified);
+    let last_modified = httpdate::fmt_http_date(modified);
+
+    let mut headers = vec![
+        header("ETag", &etag),
+        header("Last-Modified", &last_modified),
+        header("Accept-Ranges", "bytes"),
+    ];
+
+    if is_not_modified(&request, &etag, modified) {
+        info!("{} not modified since last request", path.display());
+        return request.send_response(Response::new(StatusCode(304), headers, io::empty(), Some(0), None));
+    }
+
+    headers.push(header("Content-Type", media_type(path)));
+    if let Some(name) = attachment_name {
+        headers.push(header("Content-Disposition", &format!("attachment; filename=\"{}\"", name.replace('"', ""))));
+    }
+
+    let range = request_header(&request, "Range")
+        .filter(|_| request_header(&request, "If-Range").is_none_or(|if_range| if_range == etag))
+        .map(|range| parse_range(&range, length));
+
+    match range {
+        Some(Ok(Some((start, end)))) => {
+            let range_length = end - start + 1;
+            info!("Sending bytes {}-{} of {}", start, end, path.display());
+            file.seek(SeekFrom::Start(start))?;
+            headers.push(header("Content-Range", &format!("bytes {}-{}/{}", start, end, length)));
+            request.send_response(Response::new(StatusCode(206), headers, file.take(range_length), Some(range_length as usize), None))
+        }
+        Some(Err(UnsatisfiableRange)) => {
+            headers.push(header("Content-Range", &format!("bytes */{}", length)));
+            request.send_response(Response::new(StatusCode(416), headers, io::empty(), Some(0), None))
+        }
+        Some(Ok(None)) | None => {
+            info!("Sending {} ({} bytes)", path.display(), length);
+            request.send_response(Response::new(StatusCode(200), headers, file, Some(length as usize), None))
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct UnsatisfiableRange;
+
+/// Parses a `Range` header against a resource of `length` bytes into an inclusive byte range.
+/// Returns `Ok(None)` for ranges that should be ignored (other units or multiple ranges).
+pub fn parse_range(range: &str, length: u64) -> Result<Option<(u64, u64)>, UnsatisfiableRange> {
+    let spec = match range.trim().strip_prefix("bytes=") {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return Ok(None),
+    };
+    let (start, end) = spec.split_once('-').ok_or(UnsatisfiableRange)?;
+
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix = suffix.parse::<u64>().map_err(|_| UnsatisfiableRange)?;
+            if suffix == 0 || length == 0 {
+                return Err(UnsatisfiableRange);
+            }
+            (length.saturating_sub(suffix), length - 1)
+        }
+        (start, "") => (start.parse::<u64>().map_err(|_| UnsatisfiableRange)?, length.saturating_sub(1)),
+        (start, end) => {
+            let start = start.parse::<u64>().map_err(|_| UnsatisfiableRange)?;
+            let end = end.parse::<u64>().map_err(|_| UnsatisfiableRange)?;
+            (start, end.min(length.saturating_sub(1)))
+        }
+    };
+
+    if start >= length || start > end {
+        return Err(UnsatisfiableRange);
+    }
+    Ok(Some((start, end)))
+}
+
+fn is_not_modified(request: &Request, etag: &str, modified: SystemTime) -> bool {
+    if let Some(if_none_match) = request_header(request, "If-None-Match") {
+        return if_none_match
+            .split(',')
+            .map(|tag| tag.trim().trim_start_matches("W/"))
+            .any(|tag| tag == "*" || tag == etag);
+    }
+
+    request_header(request, "If-Modified-Since")
+        .and_then(|since| httpdate::parse_http_date(&since).ok())
+        .is_some_and(|since| {
+            // Http dates only have second precision
+            let modified = modified.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+            let since = since.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+            modified <= since
+        })
+}
+
+fn entity_tag(length: u64, modified: SystemTime) -> String {
+    let modified = modified.duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0);
+    format!("\"{:x}-{:x}\"", length, modified)
+}
+
+pub fn request_header(request: &Request, name: &str) -> Option<String> {
+    request
+        .headers()
+        .iter()
+        .find(|h| h.field.as_str().as_str().eq_ignore_ascii_case(name))
+        .map(|h| h.value.as_str().to_owned())
+}
+
+pub fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::thread;
+    use tempfile::TempDir;
+    use tiny_http::Server;
+    use super::*;
+
+    /// Serves `path` for a single request and returns its status, headers and body
+    fn fetch(path: &Path, headers: &[(&str, &str)]) -> (u16, ureq::Response) {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/clue.mp4", server.server_addr().to_ip().unwrap());
+        let path = path.to_path_buf();
+        let serving = thread::spawn(move || serve_file(server.recv().unwrap(), &path, None).unwrap());
+
+        let request = headers.iter().fold(ureq::get(&url), |request, (name, value)| request.set(name, value));
+        let response = match request.call() {
+            Ok(response) => response,
+            Err(ureq::Error::Status(_, response)) => response,
+            Err(err) => panic!("{}", err),
+        };
+        serving.join().unwrap();
+        (response.status(), response)
+    }
+
+    #[test]
+    fn parses_single_byte_ranges() {
+        assert_eq!(parse_range("bytes=0-", 10), Ok(Some((0, 9))));
+        assert_eq!(parse_range("bytes=2-4", 10), Ok(Some((2, 4))));
+        assert_eq!(parse_range("bytes=5-100", 10), Ok(Some((5, 9))));
+        assert_eq!(parse_range("bytes=-3", 10), Ok(Some((7, 9))));
+        assert_eq!(parse_range("bytes=-30", 10), Ok(Some((0, 9))));
+    }
+
+    #[test]
+    fn ignores_other_units_and_multiple_ranges() {
+        assert_eq!(parse_range("bytes=0-1,4-5", 10), Ok(None));
+        assert_eq!(parse_range("items=0-1", 10), Ok(None));
+    }
+
+    #[test]
+    fn rejects_unsatisfiable_and_malformed_ranges() {
+        for range in ["bytes=10-", "bytes=12-20", "bytes=5-2", "bytes=-0", "bytes=abc", "bytes=a-b", "bytes=1-x", "bytes="] {
+            assert_eq!(parse_range(range, 10), Err(UnsatisfiableRange), "{} was accepted", range);
+        }
+        assert_eq!(parse_range("bytes=0-", 0), Err(UnsatisfiableRange));
+        assert_eq!(parse_range("bytes=-1", 0), Err(UnsatisfiableRange));
+    }
+
+    #[test]
+    fn serves_ranges_and_answers_conditional_requests() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("clue.mp4");
+        fs::write(&path, b"0123456789").unwrap();
+
+        let (status, response) = fetch(&path, &[]);
+        assert_eq!(status, 200);
+        let etag = response.header("ETag").unwrap().to_owned();
+        let last_modified = response.header("Last-Modified").unwrap().to_owned();
+        assert_eq!(response.into_string().unwrap(), "0123456789");
+
+        let (status, response) = fetch(&path, &[("Range", "bytes=-3")]);
+        assert_eq!((status, response.header("Content-Range")), (206, Some("bytes 7-9/10")));
+        assert_eq!(response.into_string().unwrap(), "789");
+
+        let (status, response) = fetch(&path, &[("Range", "bytes=10-")]);
+        assert_eq!((status, response.header("Content-Range")), (416, Some("bytes */10")));
+
+        // A range for an older version of the file gets the whole file
+        let (status, _) = fetch(&path, &[("Range", "bytes=0-1"), ("If-Range", "\"old\"")]);
+        assert_eq!(status, 200);
+
+        assert_eq!(fetch(&path, &[("If-None-Match", &etag)]).0, 304);
+        assert_eq!(fetch(&path, &[("If-None-Match", &format!("\"old\", W/{}", etag))]).0, 304);
+        assert_eq!(fetch(&path, &[("If-None-Match", "\"old\"")]).0, 200);
+        assert_eq!(fetch(&path, &[("If-Modified-Since", &last_modified)]).0, 304);
+        assert_eq!(fetch(&path, &[("If-Modified-Since", "Thu, 01 Jan 1970 00:00:00 GMT")]).0, 200);
+        // If-None-Match wins over If-Modified-Since
+        assert_eq!(fetch(&path, &[("If-None-Match", "\"old\""), ("If-Modified-Since", &last_modified)]).0, 200);
+    }
+
+    #[test]
+    fn serves_empty_files() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("empty.mp4");
+        fs::write(&path, b"").unwrap();
+
+        let (status, response) = fetch(&path, &[]);
+        assert_eq!((status, response.header("Content-Length")), (200, Some("0")));
+        assert_eq!(fetch(&path, &[("Range", "bytes=0-")]).0, 416);
+    }
+}
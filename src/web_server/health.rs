@@ -0,0 +1,174 @@
+use std::error::Error;
+use std::io;
+
+use serde::Serialize;
+use tiny_http::Request;
+use crate::rfid::rfid_manger::ReaderState;
+use crate::web_server::api::json_response;
+use crate::web_server::app_state::AppState;
+use crate::web_server::router::{PathParams, SendResponse};
+
+/// Below this the disk is reported as degraded, uploads will start failing soon.
+const LOW_DISK_SPACE_BYTES: u64 = 256 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum ComponentStatus {
+    Ok,
+    Degraded,
+    Down,
+}
+
+/// Status of every subsystem, the overall status is the worst of the ones the device needs.
+#[derive(Debug, Serialize)]
+pub struct Health {
+    status: ComponentStatus,
+    http: HttpHealth,
+    player: PlayerHealth,
+    rfid: RfidHealth,
+    database: DatabaseHealth,
+    disk: DiskHealth,
+}
+
+#[derive(Debug, Serialize)]
+struct HttpHealth {
+    status: ComponentStatus,
+    workers: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct PlayerHealth {
+    status: ComponentStatus,
+    running: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct RfidHealth {
+    status: ComponentStatus,
+    state: ReaderState,
+}
+
+#[derive(Debug, Serialize)]
+struct DatabaseHealth {
+    status: ComponentStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    paired_cards: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct DiskHealth {
+    status: ComponentStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    free_bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total_bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Reports every subsystem, responding with 503 when one the device can't work without is down.
+/// Public so monitoring doesn't need a session.
+pub fn healthz(request: Request, state: &AppState, _: &PathParams) -> Result<(), Box<dyn Error>> {
+    let health = check_health(state);
+    request.send_response(json_response(health.status_code(), &health))?;
+    Ok(())
+}
+
+pub fn check_health(state: &AppState) -> Health {
+    let running = state.media_manager.is_running();
+    let player = PlayerHealth { status: if running { ComponentStatus::Ok } else { ComponentStatus::Down }, running };
+
+    let database = match state.rfid.cards().health() {
+        Ok(paired_cards) => DatabaseHealth { status: ComponentStatus::Ok, paired_cards: Some(paired_cards), error: None },
+        Err(err) => DatabaseHealth { status: ComponentStatus::Down, paired_cards: None, error: Some(err.to_string()) },
+    };
+
+    let files_dir = state.files_dir();
+    let space = fs2::available_space(&files_dir).and_then(|free| Ok((free, fs2::total_space(&files_dir)?)));
+
+    let http = HttpHealth { status: ComponentStatus::Ok, workers: state.config().http_workers };
+    Health::new(http, player, rfid_health(state.rfid.reader_state()), database, disk_health(space))
+}
+
+impl Health {
+    fn new(http: HttpHealth, player: PlayerHealth, rfid: RfidHealth, database: DatabaseHealth, disk: DiskHealth) -> Health {
+        let status = [http.status, player.status, rfid.status, database.status, disk.status]
+            .into_iter()
+            .fold(ComponentStatus::Ok, |worst, status| if status > worst { status } else { worst });
+
+        Health { status, http, player, rfid, database, disk }
+    }
+
+    fn status_code(&self) -> u16 {
+        if self.status == ComponentStatus::Down { 503 } else { 200 }
+    }
+}
+
+fn rfid_health(reader_state: ReaderState) -> RfidHealth {
+    RfidHealth {
+        status: match reader_state {
+            ReaderState::Running | ReaderState::NotRaspberryPi => ComponentStatus::Ok,
+            ReaderState::Starting | ReaderState::Retrying => ComponentStatus::Degraded,
+            ReaderState::Absent => ComponentStatus::Down,
+        },
+        state: reader_state,
+    }
+}
+
+/// `space` is the free and total bytes of the disk holding the library.
+fn disk_health(space: io::Result<(u64, u64)>) -> DiskHealth {
+    match space {
+        Ok((free, total)) => DiskHealth {
+            status: if free < LOW_DISK_SPACE_BYTES { ComponentStatus::Degraded } else { ComponentStatus::Ok },
+            free_bytes: Some(free),
+            total_bytes: Some(total),
+            error: None,
+        },
+        Err(err) => DiskHealth { status: ComponentStatus::Degraded, free_bytes: None, total_bytes: None, error: Some(err.to_string()) },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GIGABYTE: u64 = 1024 * 1024 * 1024;
+
+    fn health(reader_state: ReaderState, free: u64) -> Health {
+        Health::new(
+            HttpHealth { status: ComponentStatus::Ok, workers: 4 },
+            PlayerHealth { status: ComponentStatus::Ok, running: true },
+            rfid_health(reader_state),
+            DatabaseHealth { status: ComponentStatus::Ok, paired_cards: Some(3), error: None },
+            disk_health(Ok((free, 16 * GIGABYTE))),
+        )
+    }
+
+    #[test]
+    fn healthy_when_everything_is_ok() {
+        for reader_state in [ReaderState::Running, ReaderState::NotRaspberryPi] {
+            let health = health(reader_state, 8 * GIGABYTE);
+            assert_eq!((health.status, health.status_code()), (ComponentStatus::Ok, 200));
+        }
+    }
+
+    #[test]
+    fn down_without_a_reader() {
+        let health = health(ReaderState::Absent, 8 * GIGABYTE);
+        assert_eq!((health.rfid.status, health.status, health.status_code()), (ComponentStatus::Down, ComponentStatus::Down, 503));
+    }
+
+    #[test]
+    fn degraded_while_the_reader_retries_or_the_disk_fills_up() {
+        for health in [health(ReaderState::Retrying, 8 * GIGABYTE), health(ReaderState::Starting, 8 * GIGABYTE), health(ReaderState::Running, LOW_DISK_SPACE_BYTES - 1)] {
+            assert_eq!((health.status, health.status_code()), (ComponentStatus::Degraded, 200));
+        }
+
+        let unreadable = disk_health(Err(io::Error::other("no disk")));
+        assert_eq!((unreadable.status, unreadable.error.as_deref()), (ComponentStatus::Degraded, Some("no disk")));
+        // A degraded disk does not hide a component that is down
+        assert_eq!(health(ReaderState::Absent, LOW_DISK_SPACE_BYTES - 1).status_code(), 503);
+    }
+}
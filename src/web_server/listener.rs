@@ -0,0 +1,24 @@
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+use log::info;
+use tiny_http::{Server, SslConfig};
+use crate::config::setup::TlsConfiguration;
+
+/// Binds the web server to `address`, using https when a certificate is configured.
+pub fn bind_server(address: &str, tls: Option<&TlsConfiguration>, project_dir: &Path) -> Result<Server, Box<dyn Error + Send + Sync + 'static>> {
+    let server = match tls {
+        Some(tls) => {
+            let ssl = SslConfig {
+                certificate: fs::read(project_dir.join(&tls.certificate))?,
+                private_key: fs::read(project_dir.join(&tls.private_key))?,
+            };
+            Server::https(address, ssl)?
+        }
+        None => Server::http(address)?,
+    };
+
+    info!("Listening on {}://{}", if tls.is_some() { "https" } else { "http" }, address);
+    Ok(server)
+}
@@ -0,0 +1,227 @@
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+
+use log::warn;
+use mp4::TrackType;
+use serde::Serialize;
+use crate::video_handler::default_images::is_generated_screen;
+use crate::web_server::api::media_type;
+
+/// Lists the files in the media library along with what probing them found out.
+///
+/// Probing reads the mp4 header or image header, so results are cached and only
+/// redone when a file's size or modification time changes.
+pub struct MediaLibrary {
+    files_dir: PathBuf,
+    cache: Mutex<HashMap<String, MediaInfo>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MediaInfo {
+    pub name: String,
+    pub size: u64,
+    pub media_type: String,
+    /// Seconds
+    pub duration: Option<f64>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub codec: Option<String>,
+    /// Seconds since the unix epoch the file was last written, which is when its upload finished
+    pub uploaded: Option<u64>,
+}
+
+impl MediaLibrary {
+    pub fn new(files_dir: PathBuf) -> MediaLibrary {
+        MediaLibrary {
+            files_dir,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Every file in the library sorted by name, hidden files and the screens the player generates are skipped.
+    ///
+    /// The cache is only locked to look files up and store results, never while probing,
+    /// so a slow probe does not hold up other listings.
+    pub fn list(&self) -> io::Result<Vec<MediaInfo>> {
+        let mut media = Vec::new();
+        let mut changed = Vec::new();
+        {
+            let cache = self.cache.lock().unwrap();
+            for entry in fs::read_dir(&self.files_dir)?.filter_map(|entry| entry.ok()) {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                let Ok(metadata) = entry.metadata() else {
+                    continue;
+                };
+                if name.starts_with('.') || is_generated_screen(&name) || !metadata.is_file() {
+                    continue;
+                }
+
+                let uploaded = metadata.modified().ok()
+                    .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                    .map(|modified| modified.as_secs());
+                match cache.get(&name) {
+                    Some(info) if info.size == metadata.len() && info.uploaded == uploaded => media.push(info.clone()),
+                    _ => changed.push((entry.path(), name, metadata.len(), uploaded)),
+                }
+            }
+        }
+
+        let probed = changed.into_iter()
+            .map(|(path, name, size, uploaded)| probe(&path, name, size, uploaded))
+            .collect::<Vec<_>>();
+        media.extend(probed.iter().cloned());
+        media.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let mut cache = self.cache.lock().unwrap();
+        cache.extend(probed.into_iter().map(|info| (info.name.clone(), info)));
+        // Forget files that are no longer in the library
+        cache.retain(|name, _| media.iter().any(|info| &info.name == name));
+        Ok(media)
+    }
+}
+
+fn probe(path: &Path, name: String, size: u64, uploaded: Option<u64>) -> MediaInfo {
+    let mut info = MediaInfo {
+        name,
+        size,
+        media_type: media_type(path).to_owned(),
+        duration: None,
+        width: None,
+        height: None,
+        codec: None,
+        uploaded,
+    };
+
+    match info.media_type.as_str() {
+        "video/mp4" => probe_mp4(path, &mut info),
+        "image/png" | "image/jpeg" => probe_image(path, &mut info),
+        _ => {}
+    }
+    info
+}
+
+fn probe_mp4(path: &Path, info: &mut MediaInfo) {
+    let mp4 = match File::open(path).map_err(mp4::Error::IoError)
+        .and_then(|file| mp4::Mp4Reader::read_header(BufReader::new(file), info.size)) {
+        Ok(mp4) => mp4,
+        Err(err) => {
+            warn!("Failed to read mp4 header of {}: {:?}", path.display(), err);
+            return;
+        }
+    };
+
+    let mut tracks = mp4.tracks().values().collect::<Vec<_>>();
+    tracks.sort_by_key(|track| track.track_id());
+
+    if let Some(video) = tracks.iter().find(|track| matches!(track.track_type(), Ok(TrackType::Video))) {
+        info.width = Some(video.width() as u32);
+        info.height = Some(video.height() as u32);
+    }
+
+    let codecs = tracks.iter()
+        .filter_map(|track| track.media_type().ok())
+        .map(|media_type| media_type.to_string())
+        .collect::<Vec<_>>();
+    if !codecs.is_empty() {
+        info.codec = Some(codecs.join(", "));
+    }
+    info.duration = Some(mp4.duration().as_secs_f64());
+}
+
+fn probe_image(path: &Path, info: &mut MediaInfo) {
+    match image::image_dimensions(path) {
+        Ok((width, height)) => {
+            info.width = Some(width);
+            info.height = Some(height);
+            info.codec = path.extension().map(|ext| ext.to_string_lossy().to_lowercase());
+        }
+        Err(err) => warn!("Failed to read image header of {}: {:?}", path.display(), err),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use mp4::{AvcConfig, FourCC, MediaConfig, Mp4Config, Mp4Sample, Mp4Writer, TrackConfig};
+    use tempfile::TempDir;
+    use super::*;
+
+    struct TestLibrary {
+        dir: PathBuf,
+        _temp: TempDir,
+    }
+
+    impl TestLibrary {
+        fn new() -> TestLibrary {
+            let temp = TempDir::new().unwrap();
+            TestLibrary { dir: temp.path().to_path_buf(), _temp: temp }
+        }
+    }
+
+    /// A 2 second 1280x720 h264 video made of two empty samples
+    fn write_mp4(path: &Path) {
+        let config = Mp4Config {
+            major_brand: "isom".parse::<FourCC>().unwrap(),
+            minor_version: 512,
+            compatible_brands: vec!["isom".parse().unwrap(), "mp41".parse().unwrap()],
+            timescale: 1000,
+        };
+        let mut writer = Mp4Writer::write_start(Cursor::new(Vec::new()), &config).unwrap();
+        writer.add_track(&TrackConfig {
+            track_type: TrackType::Video,
+            timescale: 1000,
+            language: "und".to_owned(),
+            media_conf: MediaConfig::AvcConfig(AvcConfig { width: 1280, height: 720, seq_param_set: vec![0x67, 0x64, 0x00, 0x1f], pic_param_set: vec![0x68] }),
+        }).unwrap();
+        for i in 0..2 {
+            writer.write_sample(1, &Mp4Sample { start_time: i * 1000, duration: 1000, rendering_offset: 0, is_sync: true, bytes: vec![0; 16].into() }).unwrap();
+        }
+        writer.write_end().unwrap();
+        fs::write(path, writer.into_writer().into_inner()).unwrap();
+    }
+
+    #[test]
+    fn probes_video_and_image_metadata() {
+        let library = TestLibrary::new();
+        write_mp4(&library.dir.join("clue.mp4"));
+        image::RgbImage::new(64, 32).save(library.dir.join("idle.png")).unwrap();
+        fs::write(library.dir.join(".hidden"), b"hidden").unwrap();
+        image::RgbImage::new(64, 32).save(library.dir.join("startup.png")).unwrap();
+
+        let media = MediaLibrary::new(library.dir.clone()).list().unwrap();
+        assert_eq!(media.len(), 2);
+
+        let video = &media[0];
+        assert_eq!(video.name, "clue.mp4");
+        assert_eq!((video.width, video.height), (Some(1280), Some(720)));
+        assert_eq!(video.duration, Some(2.0));
+        assert_eq!(video.codec.as_deref(), Some("h264"));
+
+        let image = &media[1];
+        assert_eq!((image.width, image.height), (Some(64), Some(32)));
+        assert_eq!(image.codec.as_deref(), Some("png"));
+        assert!(image.duration.is_none());
+    }
+
+    #[test]
+    fn reprobes_files_that_changed() {
+        let library = TestLibrary::new();
+        let media = MediaLibrary::new(library.dir.clone());
+        fs::write(library.dir.join("clue.mp4"), b"not a video").unwrap();
+        assert!(media.list().unwrap()[0].width.is_none());
+
+        write_mp4(&library.dir.join("clue.mp4"));
+        assert_eq!(media.list().unwrap()[0].width, Some(1280));
+
+        fs::remove_file(library.dir.join("clue.mp4")).unwrap();
+        assert!(media.list().unwrap().is_empty());
+        assert!(media.cache.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,151 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::{fmt, io};
+use std::path::{Component, Path, PathBuf};
+
+use crate::web_server::media_path::MediaPathError::{InvalidName, IoError, NotFound, OutsideLibrary};
+
+/// Resolves a file name taken from a request to a path of an existing file inside `library_root`.
+///
+/// Only a single plain file name is accepted, and the canonical path of the file
+/// (after following symlinks) still has to be inside the canonical library root.
+pub fn resolve_media_path(library_root: &Path, name: &str) -> Result<PathBuf, MediaPathError> {
+    let path = library_root.join(validate_name(name)?);
+    if !path.is_file() {
+        return Err(NotFound(name.to_owned()));
+    }
+
+    let root = library_root.canonicalize()?;
+    let canonical = path.canonicalize()?;
+    if !canonical.starts_with(&root) {
+        return Err(OutsideLibrary(name.to_owned()));
+    }
+
+    Ok(canonical)
+}
+
+/// Resolves a file name for a file that is about to be created inside `library_root`.
+pub fn resolve_new_media_path(library_root: &Path, name: &str) -> Result<PathBuf, MediaPathError> {
+    let path = library_root.canonicalize()?.join(validate_name(name)?);
+
+    // An existing symlink would make the write land wherever it points
+    if path.symlink_metadata().is_ok_and(|metadata| metadata.file_type().is_symlink()) {
+        return Err(OutsideLibrary(name.to_owned()));
+    }
+
+    Ok(path)
+}
+
+fn validate_name(name: &str) -> Result<&str, MediaPathError> {
+    if name.is_empty() || name.contains(['/', '\\', '\0']) {
+        return Err(InvalidName(name.to_owned()));
+    }
+
+    let mut components = Path::new(name).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) => Ok(name),
+        _ => Err(InvalidName(name.to_owned())),
+    }
+}
+
+#[derive(Debug)]
+pub enum MediaPathError {
+    InvalidName(String),
+    OutsideLibrary(String),
+    NotFound(String),
+    IoError(io::Error),
+}
+
+impl Display for MediaPathError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            InvalidName(name) => {write!(f, "Invalid file name: {}", name)}
+            OutsideLibrary(name) => {write!(f, "File is outside of the media library: {}", name)}
+            NotFound(name) => {write!(f, "File not found: {}", name)}
+            IoError(error) => {write!(f, "Io operation failed: {}", error)}
+        }
+    }
+}
+
+impl From<io::Error> for MediaPathError {
+    fn from(error: io::Error) -> Self {
+        IoError(error)
+    }
+}
+
+impl Error for MediaPathError {}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use tempfile::TempDir;
+    use super::*;
+
+    struct TestLibrary {
+        dir: TempDir,
+    }
+
+    impl TestLibrary {
+        /// Creates `files/clue.mp4` and `config/Config.yaml` in a temporary directory
+        fn new() -> TestLibrary {
+            let dir = TempDir::new().unwrap();
+            fs::create_dir_all(dir.path().join("files")).unwrap();
+            fs::create_dir_all(dir.path().join("config")).unwrap();
+            fs::write(dir.path().join("files").join("clue.mp4"), b"clue").unwrap();
+            fs::write(dir.path().join("config").join("Config.yaml"), b"secret").unwrap();
+            TestLibrary { dir }
+        }
+
+        fn root(&self) -> PathBuf {
+            self.dir.path().join("files")
+        }
+    }
+
+    #[test]
+    fn resolves_file_in_library() {
+        let library = TestLibrary::new();
+        let path = resolve_media_path(&library.root(), "clue.mp4").unwrap();
+        assert_eq!(path, library.root().canonicalize().unwrap().join("clue.mp4"));
+    }
+
+    #[test]
+    fn missing_file_is_not_found() {
+        let library = TestLibrary::new();
+        assert!(matches!(resolve_media_path(&library.root(), "missing.mp4"), Err(NotFound(_))));
+    }
+
+    #[test]
+    fn rejects_traversal() {
+        let library = TestLibrary::new();
+        for name in ["../config/Config.yaml", "..", ".", "clue.mp4/../../config/Config.yaml", "./clue.mp4", "..\\config\\Config.yaml", ""] {
+            assert!(matches!(resolve_media_path(&library.root(), name), Err(InvalidName(_))), "{} was accepted", name);
+            assert!(matches!(resolve_new_media_path(&library.root(), name), Err(InvalidName(_))), "{} was accepted", name);
+        }
+    }
+
+    #[test]
+    fn rejects_absolute_paths() {
+        let library = TestLibrary::new();
+        let absolute = library.dir.path().join("config").join("Config.yaml").display().to_string();
+        for name in [absolute.as_str(), "/etc/passwd"] {
+            assert!(matches!(resolve_media_path(&library.root(), name), Err(InvalidName(_))), "{} was accepted", name);
+            assert!(matches!(resolve_new_media_path(&library.root(), name), Err(InvalidName(_))), "{} was accepted", name);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rejects_symlink_out_of_library() {
+        let library = TestLibrary::new();
+        std::os::unix::fs::symlink(library.dir.path().join("config").join("Config.yaml"), library.root().join("link.mp4")).unwrap();
+        assert!(matches!(resolve_media_path(&library.root(), "link.mp4"), Err(OutsideLibrary(_))));
+        assert!(matches!(resolve_new_media_path(&library.root(), "link.mp4"), Err(OutsideLibrary(_))));
+    }
+
+    #[test]
+    fn new_path_stays_in_library() {
+        let library = TestLibrary::new();
+        let path = resolve_new_media_path(&library.root(), "new clue.mp4").unwrap();
+        assert_eq!(path, library.root().canonicalize().unwrap().join("new clue.mp4"));
+    }
+}
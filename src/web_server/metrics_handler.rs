@@ -0,0 +1,13 @@
+use std::error::Error;
+
+use tiny_http::{Header, Request, Response};
+use crate::web_server::app_state::AppState;
+use crate::web_server::router::{PathParams, SendResponse};
+
+/// Prometheus scrape endpoint, left public so scrapers don't need a session.
+pub fn metrics(request: Request, state: &AppState, _: &PathParams) -> Result<(), Box<dyn Error>> {
+    let response = Response::from_string(state.metrics.render(&state.files_dir()))
+        .with_header("Content-Type: text/plain; version=0.0.4".parse::<Header>().unwrap());
+    request.send_response(response)?;
+    Ok(())
+}
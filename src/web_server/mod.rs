@@ -1 +1,22 @@
-pub mod file_action_handler;
\ No newline at end of file
+pub mod file_action_handler;
+pub mod api;
+pub mod upload_handler;
+pub mod router;
+pub mod routes;
+pub mod app_state;
+pub mod page_handler;
+pub mod file_server;
+pub mod auth;
+pub mod media_path;
+pub mod worker_pool;
+pub mod event_stream;
+pub mod chunked_upload;
+pub mod card_api;
+pub mod media_library;
+pub mod thumbnails;
+pub mod metrics_handler;
+pub mod health;
+pub mod listener;
+pub mod settings_api;
+pub mod backup_api;
+pub mod output_api;
@@ -0,0 +1,31 @@
+use std::error::Error;
+use std::io;
+
+use tiny_http::Request;
+use crate::gpio::outputs::{OutputAction, OutputMode};
+use crate::web_server::api::{json_response, respond, Message};
+use crate::web_server::api::ApiError::{BadRequest, IoError, NotFound};
+use crate::web_server::app_state::AppState;
+use crate::web_server::router::PathParams;
+
+pub fn list_outputs(request: Request, state: &AppState, _: &PathParams) -> Result<(), Box<dyn Error>> {
+    respond(request, Ok(json_response(200, &state.outputs.list())))
+}
+
+/// Switches an output by hand, so the wiring can be tested without scanning a card.
+pub fn switch_output(mut request: Request, state: &AppState, params: &PathParams) -> Result<(), Box<dyn Error>> {
+    let output = params.get("name").unwrap_or_default().to_owned();
+    let mode = serde_json::from_reader::<_, OutputMode>(request.as_reader());
+
+    let result = mode
+        .map_err(|err| BadRequest(err.to_string()))
+        .and_then(|mode| {
+            if !state.outputs.contains(&output) {
+                return Err(NotFound(output.clone()));
+            }
+            state.outputs.run(&OutputAction { output, mode })
+                .map(|message| json_response(200, &Message::new(message)))
+                .map_err(|err| IoError(io::Error::other(err)))
+        });
+    respond(request, result)
+}
@@ -0,0 +1,133 @@
+use std::error::Error;
+use std::process::Command;
+
+use log::{error, info, warn};
+use tera::Context;
+use tiny_http::{Header, Request, Response, StatusCode};
+use crate::config::users::Role;
+use crate::events::event_bus::DeviceEvent;
+use crate::rfid::rfid_manger::is_raspberry_pi;
+use crate::web_server::app_state::AppState;
+use crate::web_server::file_action_handler::route_action_form;
+use crate::web_server::file_server::serve_file;
+use crate::web_server::media_path::resolve_media_path;
+use crate::web_server::router::{query_param, PathParams, SendResponse};
+use crate::web_server::api::{json_response, Message};
+use crate::web_server::upload_handler::{save_multipart_upload, UploadPolicy};
+
+pub fn index(request: Request, state: &AppState, _: &PathParams) -> Result<(), Box<dyn Error>> {
+    respond_with_index(request, state)
+}
+
+/// Responds with the upload report as json so the page can tell the user what was rejected.
+pub fn upload(mut request: Request, state: &AppState, _: &PathParams) -> Result<(), Box<dyn Error>> {
+    let policy = UploadPolicy::from_config(&state.config());
+    match save_multipart_upload(&mut request, &state.files_dir(), &policy) {
+        Ok(report) => {
+            if !report.accepted.is_empty() {
+                state.events.publish(DeviceEvent::UploadCompleted { files: report.accepted_names() });
+                state.thumbnails.generate_in_background(report.accepted_names());
+            }
+            state.metrics.upload_bytes.inc_by(report.accepted_bytes());
+            request.send_response(json_response(report.status_code(), &report))?;
+        }
+        Err(err) => {
+            error!("Failed to save uploaded files: {:?}", err);
+            request.send_response(json_response(400, &Message::new(format!("Upload failed: {}", err))))?;
+        }
+    }
+    Ok(())
+}
+
+pub fn action(request: Request, state: &AppState, _: &PathParams) -> Result<(), Box<dyn Error>> {
+    let role = state.sessions.authenticate(&request).map_or(Role::GameMaster, |session| session.role);
+    let action = route_action_form(request, state, role)?;
+    info!("Media Action Form routed successfully. Action preformed : {:?}", action);
+    Ok(())
+}
+
+pub fn download(request: Request, state: &AppState, _: &PathParams) -> Result<(), Box<dyn Error>> {
+    let filename = query_param(request.url(), "filename").unwrap_or_default();
+    info!("Decoded filename: {}", filename);
+
+    let filepath = match resolve_media_path(&state.files_dir(), &filename) {
+        Ok(filepath) => filepath,
+        Err(err) => {
+            error!("Could not find file: {}", err);
+            request.send_response(Response::from_string("").with_status_code(400))?;
+            return Ok(());
+        }
+    };
+
+    serve_file(request, &filepath, None)?;
+    info!("Sent file to client");
+    Ok(())
+}
+
+/// Lists every paired card, admins can unpair or reassign them from here.
+pub fn cards(request: Request, state: &AppState, _: &PathParams) -> Result<(), Box<dyn Error>> {
+    let cards = state.rfid.cards().list()?;
+    let media = state.media.list()?
+        .into_iter()
+        .map(|info| info.name)
+        .collect::<Vec<_>>();
+    let role = state.sessions.authenticate(&request).map(|session| session.role);
+
+    let mut context = Context::new();
+    context.insert("cards", &cards);
+    context.insert("media", &media);
+    context.insert("outputs", &state.outputs.list());
+    context.insert("deviceId", &state.config().device_uuid);
+    context.insert("isAdmin", &(role == Some(Role::Admin)));
+
+    respond_with_html(request, state.tera.render("cards.html", &context)?)
+}
+
+/// The form is filled in and saved through the settings api.
+pub fn settings(request: Request, state: &AppState, _: &PathParams) -> Result<(), Box<dyn Error>> {
+    let mut context = Context::new();
+    context.insert("deviceId", &state.config().device_uuid);
+    respond_with_html(request, state.tera.render("settings.html", &context)?)
+}
+
+pub fn reboot(request: Request, _: &AppState, _: &PathParams) -> Result<(), Box<dyn Error>> {
+    if is_raspberry_pi() {
+        info!("Rebooting...");
+        request.send_response(Response::from_string(""))?;
+        Command::new("sudo")
+            .arg("reboot")
+            .arg("-f")
+            .output()
+            .expect("failed to execute reboot command");
+        panic!();
+    } else {
+        warn!("Did not reboot because it is not on a pi");
+        request.send_response(Response::from_string("Not a raspberry pi").with_status_code(501))?;
+    }
+    Ok(())
+}
+
+fn respond_with_index(request: Request, state: &AppState) -> Result<(), Box<dyn Error>> {
+    let media = state.media.list()?;
+
+    let role = state.sessions.authenticate(&request).map(|session| session.role);
+
+    let mut context = Context::new();
+    context.insert("items", &media);
+    context.insert("deviceId", &state.config().device_uuid);
+    context.insert("isAdmin", &(role == Some(Role::Admin)));
+
+    respond_with_html(request, state.tera.render("index.html", &context)?)
+}
+
+pub fn respond_with_html(request: Request, rendered: String) -> Result<(), Box<dyn Error>> {
+    let response = Response::new(
+        StatusCode(200),
+        vec![Header::from_bytes(&b"Content-Type"[..], &b"text/html"[..]).unwrap()],
+        rendered.as_bytes(),
+        None,
+        None
+    );
+    request.send_response(response)?;
+    Ok(())
+}
@@ -0,0 +1,408 @@
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::error::Error;
+use std::io;
+use std::io::Read;
+
+use log::{error, info, warn};
+use percent_encoding::percent_decode_str;
+use tiny_http::{Header, Method, Request, Response};
+use crate::web_server::api::{json_response, ApiError, Message};
+use crate::config::users::UserAccount;
+use crate::web_server::app_state::AppState;
+use crate::web_server::auth::{Access, SessionStore};
+
+/// A route handler is responsible for responding to the request itself.
+/// Any error it returns is logged by the router.
+pub type Handler<S = AppState> = fn(Request, &S, &PathParams) -> Result<(), Box<dyn Error>>;
+
+/// What the router, worker pool and login handlers need from the state shared with the handlers,
+/// so the device and the fleet controller can both be served by them.
+pub trait ServerState: Send + Sync + 'static {
+    fn sessions(&self) -> &SessionStore;
+
+    fn find_user(&self, username: &str) -> Option<UserAccount>;
+
+    /// Saves a new password for the account, it is used from the next login on.
+    fn save_password(&self, username: &str, password: &str) -> Result<(), ApiError>;
+
+    /// Counts a routed request, `route` is the pattern it matched.
+    fn http_request(&self, route: &str, status: u16);
+}
+
+/// Route label used in the request metrics for requests that matched no route.
+pub const UNMATCHED_ROUTE: &str = "unmatched";
+
+thread_local! {
+    /// Status of the last response sent from this worker thread, read back by the router to count it.
+    static RESPONSE_STATUS: Cell<Option<u16>> = const { Cell::new(None) };
+}
+
+/// Responds like `Request::respond` but remembers the status code for the request metrics.
+/// Handlers should always respond through this.
+pub trait SendResponse {
+    fn send_response<R: Read>(self, response: Response<R>) -> io::Result<()>;
+}
+
+impl SendResponse for Request {
+    fn send_response<R: Read>(self, response: Response<R>) -> io::Result<()> {
+        record_response_status(response.status_code().0);
+        self.respond(response)
+    }
+}
+
+/// For handlers that write their response by hand instead of using `send_response`.
+pub fn record_response_status(status: u16) {
+    RESPONSE_STATUS.with(|cell| cell.set(Some(status)));
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Literal(String),
+    Param(String),
+}
+
+struct Route<S> {
+    method: Method,
+    pattern: String,
+    segments: Vec<Segment>,
+    access: Access,
+    handler: Handler<S>,
+}
+
+impl<S> Route<S> {
+    fn new(method: Method, pattern: &str, access: Access, handler: Handler<S>) -> Route<S> {
+        let segments = split_path(pattern)
+            .map(|segment| {
+                if segment.starts_with('{') && segment.ends_with('}') {
+                    Segment::Param(segment[1..segment.len() - 1].to_owned())
+                } else {
+                    Segment::Literal(segment.to_owned())
+                }
+            })
+            .collect();
+
+        Route {
+            method,
+            pattern: pattern.to_owned(),
+            segments,
+            access,
+            handler,
+        }
+    }
+
+    fn match_path(&self, path: &str) -> Option<PathParams> {
+        let parts = split_path(path).collect::<Vec<_>>();
+        if parts.len() != self.segments.len() {
+            return None;
+        }
+
+        let mut params = HashMap::new();
+        for (segment, part) in self.segments.iter().zip(parts) {
+            let part = percent_decode_str(part).decode_utf8_lossy().into_owned();
+            match segment {
+                Segment::Literal(literal) if *literal == part => {}
+                Segment::Literal(_) => return None,
+                Segment::Param(name) => {
+                    params.insert(name.clone(), part);
+                }
+            }
+        }
+
+        Some(PathParams(params))
+    }
+}
+
+/// Path parameters captured from `{name}` segments of a route pattern, already percent-decoded.
+#[derive(Debug, Default)]
+pub struct PathParams(HashMap<String, String>);
+
+impl PathParams {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0.get(name).map(String::as_str)
+    }
+}
+
+pub struct Router<S = AppState> {
+    routes: Vec<Route<S>>,
+}
+
+impl<S> Default for Router<S> {
+    fn default() -> Self {
+        Router { routes: Vec::new() }
+    }
+}
+
+impl<S: ServerState> Router<S> {
+    pub fn new() -> Router<S> {
+        Router::default()
+    }
+
+    pub fn route(mut self, method: Method, pattern: &str, access: Access, handler: Handler<S>) -> Router<S> {
+        self.routes.push(Route::new(method, pattern, access, handler));
+        self
+    }
+
+    pub fn get(self, pattern: &str, access: Access, handler: Handler<S>) -> Router<S> {
+        self.route(Method::Get, pattern, access, handler)
+    }
+
+    pub fn post(self, pattern: &str, access: Access, handler: Handler<S>) -> Router<S> {
+        self.route(Method::Post, pattern, access, handler)
+    }
+
+    pub fn put(self, pattern: &str, access: Access, handler: Handler<S>) -> Router<S> {
+        self.route(Method::Put, pattern, access, handler)
+    }
+
+    pub fn delete(self, pattern: &str, access: Access, handler: Handler<S>) -> Router<S> {
+        self.route(Method::Delete, pattern, access, handler)
+    }
+
+    /// Finds the route matching the request path and method and hands the request to it.
+    /// Responds with 404 when no pattern matches, 405 when only the method differs
+    /// and 401/403 when the caller's session does not grant the route's access level.
+    pub fn dispatch(&self, request: Request, state: &S) {
+        RESPONSE_STATUS.with(|cell| cell.set(None));
+        let route = self.route_request(request, state);
+        // A handler that failed without responding leaves tiny_http to answer with a 500
+        let status = RESPONSE_STATUS.with(|cell| cell.take()).unwrap_or(500);
+        state.http_request(&route, status);
+    }
+
+    /// Routes the request returning the pattern it was routed to for the request metrics.
+    fn route_request(&self, request: Request, state: &S) -> String {
+        let path = request_path(request.url()).to_owned();
+        let mut allowed = Vec::new();
+
+        for route in &self.routes {
+            if let Some(params) = route.match_path(&path) {
+                if route.method == *request.method() {
+                    if route.access != Access::Public {
+                        let session = state.sessions().authenticate(&request);
+                        let role = session.as_ref().map(|session| session.role);
+                        if !route.access.allows(role) {
+                            warn!("Denied {} {} to {:?} session", request.method(), path, role);
+                            respond_unauthorized(request, &path, role.is_some());
+                            return route.pattern.clone();
+                        }
+                        info!("Routing {} {} to {} for {}", request.method(), path, route.pattern,
+                            session.map(|session| session.username).unwrap_or_default());
+                    } else {
+                        info!("Routing {} {} to {}", request.method(), path, route.pattern);
+                    }
+                    if let Err(err) = (route.handler)(request, state, &params) {
+                        error!("Handler for {} {} failed: {}", route.method, route.pattern, err);
+                    }
+                    return route.pattern.clone();
+                }
+                allowed.push(route.method.to_string());
+            }
+        }
+
+        let response = if allowed.is_empty() {
+            info!("No route found for {} {}", request.method(), path);
+            Response::from_string("Not Found").with_status_code(404)
+        } else {
+            info!("Method {} not allowed for {}", request.method(), path);
+            Response::from_string("Method Not Allowed")
+                .with_status_code(405)
+                .with_header(Header::from_bytes(&b"Allow"[..], allowed.join(", ").as_bytes()).unwrap())
+        };
+
+        request.send_response(response).unwrap_or_else(|err| {
+            error!("Failed to send response to client: {:?}", err);
+        });
+        UNMATCHED_ROUTE.to_owned()
+    }
+}
+
+/// Browsers asking for a page are sent to the login page, everything else gets a status code.
+fn respond_unauthorized(request: Request, path: &str, logged_in: bool) {
+    let result = if logged_in {
+        request.send_response(json_response(403, &Message::new("Your account is not allowed to do this")))
+    } else if *request.method() == Method::Get && !path.starts_with("/api/") {
+        request.send_response(Response::empty(303).with_header(Header::from_bytes(&b"Location"[..], &b"/login"[..]).unwrap()))
+    } else {
+        request.send_response(json_response(401, &Message::new("Login required")))
+    };
+
+    result.unwrap_or_else(|err| {
+        error!("Failed to send response to client: {:?}", err);
+    });
+}
+
+/// Returns the url without its query string.
+pub fn request_path(url: &str) -> &str {
+    url.split('?').next().unwrap_or_default()
+}
+
+/// Returns the decoded value of `key` from the url's query string.
+pub fn query_param(url: &str, key: &str) -> Option<String> {
+    let (_, query) = url.split_once('?')?;
+    url::form_urlencoded::parse(query.as_bytes())
+        .find(|(name, _)| name == key)
+        .map(|(_, value)| value.into_owned())
+}
+
+fn split_path(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|segment| !segment.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use tiny_http::Server;
+    use crate::config::users::Role;
+    use super::*;
+
+    struct TestState {
+        sessions: SessionStore,
+        users: Vec<UserAccount>,
+        requests: Mutex<Vec<(String, u16)>>,
+    }
+
+    impl ServerState for TestState {
+        fn sessions(&self) -> &SessionStore {
+            &self.sessions
+        }
+
+        fn find_user(&self, username: &str) -> Option<UserAccount> {
+            self.users.iter().find(|user| user.username == username).cloned()
+        }
+
+        fn save_password(&self, _: &str, _: &str) -> Result<(), ApiError> {
+            unimplemented!()
+        }
+
+        fn http_request(&self, route: &str, status: u16) {
+            self.requests.lock().unwrap().push((route.to_owned(), status));
+        }
+    }
+
+    /// Serves the test router until dropped.
+    struct TestServer {
+        server: Arc<Server>,
+        state: Arc<TestState>,
+        url: String,
+    }
+
+    impl TestServer {
+        fn start() -> TestServer {
+            let router = Router::new()
+                .get("/", Access::Public, echo_params)
+                .get("/cards/{id}", Access::GameMaster, echo_params)
+                .get("/api/cards/{id}/steps/{step}", Access::GameMaster, echo_params)
+                .delete("/api/cards/{id}", Access::Admin, echo_params)
+                .get("/api/failing", Access::Public, |_, _, _| Err("failed".into()));
+            let state = Arc::new(TestState {
+                sessions: SessionStore::new(),
+                users: vec![
+                    UserAccount::for_tests("gamemaster", "secret", Role::GameMaster),
+                    UserAccount::for_tests("admin", "secret", Role::Admin),
+                ],
+                requests: Mutex::new(Vec::new()),
+            });
+            let server = Arc::new(Server::http("127.0.0.1:0").unwrap());
+            let url = format!("http://{}", server.server_addr().to_ip().unwrap());
+
+            let (serving, serving_state) = (server.clone(), state.clone());
+            thread::spawn(move || {
+                for request in serving.incoming_requests() {
+                    router.dispatch(request, &serving_state);
+                }
+            });
+            TestServer { server, state, url }
+        }
+
+        fn token(&self, username: &str) -> String {
+            let user = self.state.find_user(username).unwrap();
+            self.state.sessions.login(username, Some(&user), "secret", None).unwrap().0
+        }
+
+        /// Sends the request without following redirects, returning the status, Allow or Location header and body.
+        fn send(&self, method: &str, path: &str, token: Option<&str>) -> (u16, Option<String>, String) {
+            let agent = ureq::AgentBuilder::new().redirects(0).build();
+            let mut request = agent.request(method, &format!("{}{}", self.url, path));
+            if let Some(token) = token {
+                request = request.set("Authorization", &format!("Bearer {}", token));
+            }
+            let response = match request.call() {
+                Ok(response) => response,
+                Err(ureq::Error::Status(_, response)) => response,
+                Err(err) => panic!("{}", err),
+            };
+            let header = response.header("Allow").or(response.header("Location")).map(str::to_owned);
+            (response.status(), header, response.into_string().unwrap())
+        }
+    }
+
+    impl Drop for TestServer {
+        fn drop(&mut self) {
+            self.server.unblock();
+        }
+    }
+
+    fn echo_params(request: Request, _: &TestState, params: &PathParams) -> Result<(), Box<dyn Error>> {
+        let mut params = params.0.iter().map(|(name, value)| format!("{}={}", name, value)).collect::<Vec<_>>();
+        params.sort();
+        request.send_response(Response::from_string(params.join("&")))?;
+        Ok(())
+    }
+
+    #[test]
+    fn matches_params_and_decodes_them() {
+        let route = Route::<TestState>::new(Method::Get, "/api/cards/{id}/steps/{step}", Access::Public, echo_params);
+        let params = route.match_path("/api/cards/red%20card/steps/2/").unwrap();
+        assert_eq!((params.get("id"), params.get("step")), (Some("red card"), Some("2")));
+        assert_eq!(route.match_path("/api/cards/%E2%9C%93/steps/%2F").unwrap().get("step"), Some("/"));
+        assert!(route.match_path("/api/cards/red/steps").is_none());
+        assert!(route.match_path("/api/cards/red/steps/2/extra").is_none());
+        assert!(route.match_path("/api/decks/red/steps/2").is_none());
+
+        let literal = Route::<TestState>::new(Method::Get, "/api/media library", Access::Public, echo_params);
+        assert!(literal.match_path("/api/media%20library").is_some());
+        assert_eq!(request_path("/api/cards?page=2"), "/api/cards");
+        assert_eq!(query_param("/api/cards?name=red%20card&page=2", "name").as_deref(), Some("red card"));
+        assert_eq!(query_param("/api/cards", "name"), None);
+    }
+
+    #[test]
+    fn unmatched_paths_and_methods_are_refused() {
+        let server = TestServer::start();
+        let admin = server.token("admin");
+        assert_eq!(server.send("GET", "/api/cards/red%20card/steps/1?page=2", Some(&admin)),
+            (200, None, "id=red card&step=1".to_owned()));
+        assert_eq!(server.send("GET", "/api/decks", Some(&admin)).0, 404);
+
+        let (status, allow, _) = server.send("PUT", "/api/cards/red", Some(&admin));
+        assert_eq!((status, allow.as_deref()), (405, Some("DELETE")));
+
+        assert_eq!(server.send("GET", "/api/failing", None).0, 500);
+        assert_eq!(*server.state.requests.lock().unwrap(), vec![
+            ("/api/cards/{id}/steps/{step}".to_owned(), 200),
+            (UNMATCHED_ROUTE.to_owned(), 404),
+            (UNMATCHED_ROUTE.to_owned(), 405),
+            ("/api/failing".to_owned(), 500),
+        ]);
+    }
+
+    #[test]
+    fn routes_are_refused_below_their_access_level() {
+        let server = TestServer::start();
+        let (gamemaster, admin) = (server.token("gamemaster"), server.token("admin"));
+
+        assert_eq!(server.send("GET", "/", None).0, 200);
+        // Pages send browsers to the login page, the API answers with a status code
+        assert_eq!(server.send("GET", "/cards/red", None).0, 303);
+        assert_eq!(server.send("GET", "/cards/red", None).1.as_deref(), Some("/login"));
+        assert_eq!(server.send("GET", "/api/cards/red/steps/1", None).0, 401);
+        assert_eq!(server.send("DELETE", "/api/cards/red", None).0, 401);
+        assert_eq!(server.send("GET", "/api/cards/red/steps/1", Some("unknown")).0, 401);
+
+        assert_eq!(server.send("GET", "/cards/red", Some(&gamemaster)).0, 200);
+        assert_eq!(server.send("DELETE", "/api/cards/red", Some(&gamemaster)).0, 403);
+        assert_eq!(server.send("DELETE", "/api/cards/red", Some(&admin)), (200, None, "id=red".to_owned()));
+    }
+}
@@ -0,0 +1,48 @@
+use crate::web_server::{api, auth, backup_api, card_api, chunked_upload, event_stream, health, metrics_handler, output_api, page_handler, settings_api};
+use crate::web_server::auth::Access::{Admin, GameMaster, Public};
+use crate::web_server::router::Router;
+
+/// Every endpoint the device serves. New endpoints only need a line here and a handler.
+pub fn build_router() -> Router {
+    Router::new()
+        .get("/login", Public, auth::login_page)
+        .post("/login", Public, auth::login_form)
+        .post("/logout", Public, auth::logout)
+        .post("/api/login", Public, auth::login_api)
+        .put("/api/password", GameMaster, auth::change_password)
+        .get("/metrics", Public, metrics_handler::metrics)
+        .get("/healthz", Public, health::healthz)
+        .get("/api/device", Public, api::device_info)
+        .get("/", GameMaster, page_handler::index)
+        .get("/download", Admin, page_handler::download)
+        .post("/upload", Admin, page_handler::upload)
+        .post("/action", GameMaster, page_handler::action)
+        .post("/reboot", Admin, page_handler::reboot)
+        .get("/cards", GameMaster, page_handler::cards)
+        .get("/settings", Admin, page_handler::settings)
+        .get("/events", GameMaster, event_stream::events)
+        .get("/api/media", GameMaster, api::get_media)
+        .post("/api/media", Admin, api::post_media)
+        .get("/api/media/{name}", Admin, api::download_media)
+        .delete("/api/media/{name}", Admin, api::delete_media)
+        .get("/api/media/{name}/thumbnail", GameMaster, api::media_thumbnail)
+        .post("/api/media/{name}/play", GameMaster, api::play_media)
+        .post("/api/media/{name}/pair", Admin, api::pair_media)
+        .get("/api/cards", GameMaster, card_api::list_cards)
+        .delete("/api/cards", Admin, card_api::clear_cards)
+        .put("/api/cards/{id}", Admin, card_api::reassign_card)
+        .delete("/api/cards/{id}", Admin, card_api::unpair_card)
+        .put("/api/cards/{id}/actions", Admin, card_api::set_card_actions)
+        .put("/api/cards/{id}/outputs", Admin, card_api::set_card_outputs)
+        .get("/api/outputs", GameMaster, output_api::list_outputs)
+        .post("/api/outputs/{name}", Admin, output_api::switch_output)
+        .post("/api/uploads", Admin, chunked_upload::create_upload)
+        .get("/api/uploads/{id}", Admin, chunked_upload::upload_status)
+        .put("/api/uploads/{id}", Admin, chunked_upload::upload_chunk)
+        .delete("/api/uploads/{id}", Admin, chunked_upload::abort_upload)
+        .post("/api/uploads/{id}/finalize", Admin, chunked_upload::finalize_upload)
+        .get("/api/settings", Admin, settings_api::get_settings)
+        .put("/api/settings", Admin, settings_api::update_settings)
+        .get("/api/backup", Admin, backup_api::export_backup)
+        .post("/api/backup", Admin, backup_api::import_backup)
+}
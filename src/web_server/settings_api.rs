@@ -0,0 +1,120 @@
+use std::error::Error;
+use std::path::Path;
+
+use log::info;
+use serde::Serialize;
+use tiny_http::Request;
+use crate::config::settings::{FieldError, Settings, LIVE_SETTINGS};
+use crate::config::setup::DeviceConfiguration;
+use crate::web_server::api::ApiError::BadRequest;
+use crate::web_server::api::{json_response, respond, ApiError};
+use crate::web_server::app_state::AppState;
+use crate::web_server::router::{PathParams, SendResponse};
+
+#[derive(Debug, Serialize)]
+struct SettingsView {
+    /// What is saved in `config/Config.yaml`
+    settings: Settings,
+    /// Settings that take effect as soon as they are saved
+    live: Vec<&'static str>,
+    /// Saved settings the running device is not using yet
+    restart_required: Vec<&'static str>,
+}
+
+#[derive(Debug, Serialize)]
+struct InvalidSettings {
+    message: String,
+    errors: Vec<FieldError>,
+}
+
+pub fn get_settings(request: Request, state: &AppState, _: &PathParams) -> Result<(), Box<dyn Error>> {
+    let result = settings_view(state).map(|view| json_response(200, &view));
+    respond(request, result)
+}
+
+/// Validates and saves every setting, applying the ones that can change while running straight away.
+/// Responds with 400 and the problem with each field when validation fails.
+pub fn update_settings(mut request: Request, state: &AppState, _: &PathParams) -> Result<(), Box<dyn Error>> {
+    let settings = match serde_json::from_reader::<_, Settings>(request.as_reader()) {
+        Ok(settings) => settings,
+        Err(err) => return respond(request, Err(BadRequest(err.to_string()))),
+    };
+
+    let settings = match settings.validate() {
+        Ok(settings) => settings,
+        Err(errors) => {
+            let invalid = InvalidSettings { message: "Some settings are not valid".to_owned(), errors };
+            request.send_response(json_response(400, &invalid))?;
+            return Ok(());
+        }
+    };
+
+    let result = save_settings(state, &settings)
+        .and_then(|_| settings_view(state))
+        .map(|view| json_response(200, &view));
+    respond(request, result)
+}
+
+fn save_settings(state: &AppState, settings: &Settings) -> Result<(), ApiError> {
+    // Held while saving so two edits can't interleave between the file and the running config
+    let mut running = state.device_config.write().unwrap();
+    write_settings(&state.config_path(), &mut running, settings)?;
+    state.rfid.set_clue_timeout(settings.clue_timeout);
+    info!("Saved settings: {:?}", settings);
+    Ok(())
+}
+
+/// Saves the settings over the configuration on disk rather than the running one, so whatever
+/// was saved but isn't live yet, like a restored backup, is kept.
+fn write_settings(config_path: &Path, running: &mut DeviceConfiguration, settings: &Settings) -> Result<(), ApiError> {
+    let mut saved = DeviceConfiguration::read(config_path)?;
+    settings.apply_to(&mut saved);
+    saved.save(config_path.to_path_buf())?;
+
+    settings.apply_live_to(running);
+    Ok(())
+}
+
+fn settings_view(state: &AppState) -> Result<SettingsView, ApiError> {
+    let saved = Settings::from_config(&DeviceConfiguration::read(&state.config_path())?);
+    let running = Settings::from_config(&state.config());
+    Ok(SettingsView {
+        restart_required: saved.changed_from(&running),
+        settings: saved,
+        live: LIVE_SETTINGS.to_vec(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+    use crate::config::setup::{MqttConfiguration, WebhookConfiguration};
+    use super::*;
+
+    #[test]
+    fn saving_settings_keeps_what_is_only_on_disk() {
+        let dir = TempDir::new().unwrap();
+        let config_path = dir.path().join("Config.yaml");
+        let mut running = DeviceConfiguration::new();
+
+        // Like a restored backup, saved but not running yet
+        let mut restored = running.clone();
+        restored.webhooks = vec![WebhookConfiguration { url: "http://automation.local/hooks/clue".to_owned(), events: Vec::new() }];
+        restored.mqtt = Some(serde_yaml::from_str::<MqttConfiguration>("host: broker.local").unwrap());
+        restored.port = 9000;
+        restored.save(config_path.clone()).unwrap();
+
+        let mut settings = Settings::from_config(&running);
+        settings.clue_timeout = running.clue_timeout + 5;
+        write_settings(&config_path, &mut running, &settings).unwrap();
+
+        let saved = DeviceConfiguration::read(&config_path).unwrap();
+        assert_eq!(saved.webhooks.len(), 1);
+        assert_eq!(saved.mqtt.unwrap().host, "broker.local");
+        assert_eq!(saved.clue_timeout, settings.clue_timeout);
+        // The settings were edited starting from the running device, so the restored port is replaced
+        assert_eq!(saved.port, settings.port);
+        assert_eq!(running.clue_timeout, settings.clue_timeout);
+        assert!(running.webhooks.is_empty());
+    }
+}
@@ -0,0 +1,310 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::{fmt, fs, io, thread};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use image::ImageFormat;
+use log::{error, info};
+use uuid::Uuid;
+use crate::video_handler::frame_grab::grab_frame;
+use crate::web_server::api::media_type;
+use crate::web_server::media_path::resolve_media_path;
+use crate::web_server::thumbnails::ThumbnailError::{Failed, FrameGrabFailed, ImageError, IoError, Pending, Unsupported};
+
+const THUMBNAILS_DIR: &str = ".thumbnails";
+const THUMBNAIL_WIDTH: u32 = 320;
+const THUMBNAIL_HEIGHT: u32 = 180;
+
+/// Small jpeg previews of the library kept in `files/.thumbnails`, one per media file.
+///
+/// A thumbnail is regenerated whenever its source file is newer than it. Generation only happens
+/// on a single background thread since decoding a video frame is heavy on a Pi and can take a
+/// while, requests never wait for it. A file that fails is not tried again until it changes.
+#[derive(Clone)]
+pub struct Thumbnails {
+    files_dir: PathBuf,
+    jobs: Sender<Job>,
+    queue: Arc<Mutex<Queue>>,
+}
+
+enum Job {
+    Generate(String),
+    RemoveOrphans,
+}
+
+#[derive(Default)]
+struct Queue {
+    queued: HashSet<String>,
+    /// Why each file failed, with the modification time it had back then
+    failed: HashMap<String, (Option<SystemTime>, String)>,
+}
+
+impl Thumbnails {
+    /// Starts the thread that generates the thumbnails of the library in `files_dir`.
+    pub fn new(files_dir: PathBuf) -> Thumbnails {
+        let (jobs, receiver) = channel();
+        let queue = Arc::new(Mutex::new(Queue::default()));
+        let worker = Worker { files_dir: files_dir.clone(), queue: queue.clone() };
+        thread::spawn(move || worker.run(receiver));
+        Thumbnails { files_dir, jobs, queue }
+    }
+
+    /// Returns the thumbnail for `name` when it is up to date, otherwise it is queued for generation.
+    pub fn get(&self, name: &str) -> Result<PathBuf, ThumbnailError> {
+        let source = resolve_media_path(&self.files_dir, name).map_err(|err| Unsupported(err.to_string()))?;
+        let thumbnail = thumbnail_path(&self.files_dir, name);
+        if is_fresh(&thumbnail, &source) {
+            return Ok(thumbnail);
+        }
+
+        let mut queue = self.queue.lock().unwrap();
+        if let Some((modified, reason)) = queue.failed.get(name) {
+            if *modified == modified_time(&source) {
+                return Err(Failed(reason.clone()));
+            }
+            queue.failed.remove(name);
+        }
+        if queue.queued.insert(name.to_owned()) {
+            let _ = self.jobs.send(Job::Generate(name.to_owned()));
+        }
+        Err(Pending(name.to_owned()))
+    }
+
+    /// Creates thumbnails for freshly uploaded files without holding up the upload response.
+    pub fn generate_in_background(&self, names: Vec<String>) {
+        for name in names {
+            // Only queues the file, there is nothing to report yet
+            let _ = self.get(&name);
+        }
+        let _ = self.jobs.send(Job::RemoveOrphans);
+    }
+
+    pub fn remove(&self, name: &str) {
+        self.queue.lock().unwrap().failed.remove(name);
+        remove_thumbnail(&self.files_dir, name);
+    }
+}
+
+/// Runs the queued jobs one after another.
+struct Worker {
+    files_dir: PathBuf,
+    queue: Arc<Mutex<Queue>>,
+}
+
+impl Worker {
+    fn run(&self, jobs: Receiver<Job>) {
+        for job in jobs {
+            match job {
+                Job::Generate(name) => self.generate(&name),
+                Job::RemoveOrphans => self.remove_orphans(),
+            }
+        }
+    }
+
+    fn generate(&self, name: &str) {
+        if let Err(err) = self.try_generate(name) {
+            error!("Failed to create thumbnail for {}: {}", name, err);
+        }
+        self.queue.lock().unwrap().queued.remove(name);
+    }
+
+    fn try_generate(&self, name: &str) -> Result<(), ThumbnailError> {
+        let source = resolve_media_path(&self.files_dir, name).map_err(|err| Unsupported(err.to_string()))?;
+        let thumbnail = thumbnail_path(&self.files_dir, name);
+        if is_fresh(&thumbnail, &source) {
+            return Ok(());
+        }
+
+        let modified = modified_time(&source);
+        fs::create_dir_all(thumbnails_dir(&self.files_dir))?;
+        generate(&source, &thumbnail).inspect_err(|err| {
+            self.queue.lock().unwrap().failed.insert(name.to_owned(), (modified, err.to_string()));
+        })?;
+        info!("Created thumbnail for {}", name);
+        Ok(())
+    }
+
+    /// Removes thumbnails whose file was deleted from the library.
+    fn remove_orphans(&self) {
+        let Ok(entries) = fs::read_dir(thumbnails_dir(&self.files_dir)) else {
+            return;
+        };
+
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if let Some(source) = source_name(&name) {
+                if !self.files_dir.join(source).is_file() {
+                    remove_thumbnail(&self.files_dir, source);
+                }
+            }
+        }
+    }
+}
+
+fn thumbnails_dir(files_dir: &Path) -> PathBuf {
+    files_dir.join(THUMBNAILS_DIR)
+}
+
+fn thumbnail_path(files_dir: &Path, name: &str) -> PathBuf {
+    thumbnails_dir(files_dir).join(format!("{}.jpg", name))
+}
+
+/// The media file a file in the thumbnails directory belongs to.
+fn source_name(thumbnail_name: &str) -> Option<&str> {
+    thumbnail_name.strip_suffix(".jpg").filter(|name| !name.is_empty())
+}
+
+fn remove_thumbnail(files_dir: &Path, name: &str) {
+    let thumbnail = thumbnail_path(files_dir, name);
+    if thumbnail.is_file() {
+        if let Err(err) = fs::remove_file(&thumbnail) {
+            error!("Failed to remove thumbnail {}: {:?}", thumbnail.display(), err);
+        }
+    }
+}
+
+fn generate(source: &Path, thumbnail: &Path) -> Result<(), ThumbnailError> {
+    match media_type(source) {
+        "image/png" | "image/jpeg" => save_thumbnail(source, thumbnail),
+        "video/mp4" => {
+            let work_dir = thumbnail.with_file_name(format!(".frame-{}", Uuid::new_v4()));
+            let result = grab_frame(source, &work_dir)
+                .map_err(|err| FrameGrabFailed(err.to_string()))
+                .and_then(|frame| save_thumbnail(&frame, thumbnail));
+            fs::remove_dir_all(&work_dir).unwrap_or_else(|err| {
+                error!("Failed to remove {}: {:?}", work_dir.display(), err);
+            });
+            result
+        }
+        _ => Err(Unsupported(source.display().to_string())),
+    }
+}
+
+fn save_thumbnail(image: &Path, thumbnail: &Path) -> Result<(), ThumbnailError> {
+    image::open(image)?
+        .thumbnail(THUMBNAIL_WIDTH, THUMBNAIL_HEIGHT)
+        .to_rgb8()
+        .save_with_format(thumbnail, ImageFormat::Jpeg)?;
+    Ok(())
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+fn is_fresh(thumbnail: &Path, source: &Path) -> bool {
+    match (modified_time(thumbnail), modified_time(source)) {
+        (Some(thumbnail), Some(source)) => thumbnail >= source,
+        _ => false,
+    }
+}
+
+#[derive(Debug)]
+pub enum ThumbnailError {
+    Unsupported(String),
+    /// Queued for generation, not there yet
+    Pending(String),
+    /// Generation failed before and the file hasn't changed since
+    Failed(String),
+    FrameGrabFailed(String),
+    ImageError(image::ImageError),
+    IoError(io::Error),
+}
+
+impl Display for ThumbnailError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Unsupported(name) => {write!(f, "No thumbnail can be made for: {}", name)}
+            Pending(name) => {write!(f, "Thumbnail is still being created for: {}", name)}
+            Failed(reason) => {write!(f, "Thumbnail could not be created: {}", reason)}
+            FrameGrabFailed(reason) => {write!(f, "Failed to decode a video frame: {}", reason)}
+            ImageError(error) => {write!(f, "Failed to scale image: {}", error)}
+            IoError(error) => {write!(f, "Io operation failed: {}", error)}
+        }
+    }
+}
+
+impl From<io::Error> for ThumbnailError {
+    fn from(error: io::Error) -> Self {
+        IoError(error)
+    }
+}
+
+impl From<image::ImageError> for ThumbnailError {
+    fn from(error: image::ImageError) -> Self {
+        ImageError(error)
+    }
+}
+
+impl Error for ThumbnailError {}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::time::{Duration, Instant};
+    use tempfile::TempDir;
+    use super::*;
+
+    fn set_modified(path: &Path, time: SystemTime) {
+        File::options().write(true).open(path).unwrap().set_modified(time).unwrap();
+    }
+
+    /// Asks for the thumbnail until the background thread is done with it
+    fn wait_for(thumbnails: &Thumbnails, name: &str) -> Result<PathBuf, ThumbnailError> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            match thumbnails.get(name) {
+                Err(Pending(_)) if Instant::now() < deadline => thread::sleep(Duration::from_millis(10)),
+                result => return result,
+            }
+        }
+    }
+
+    #[test]
+    fn thumbnail_is_fresh_when_newer_than_its_source() {
+        let dir = TempDir::new().unwrap();
+        let source = dir.path().join("clue.png");
+        let thumbnail = dir.path().join("clue.png.jpg");
+        fs::write(&source, b"png").unwrap();
+        assert!(!is_fresh(&thumbnail, &source));
+
+        fs::write(&thumbnail, b"jpg").unwrap();
+        let now = SystemTime::now();
+        set_modified(&source, now);
+        set_modified(&thumbnail, now - Duration::from_secs(60));
+        assert!(!is_fresh(&thumbnail, &source));
+        set_modified(&thumbnail, now);
+        assert!(is_fresh(&thumbnail, &source));
+    }
+
+    #[test]
+    fn maps_media_names_to_thumbnails_and_back() {
+        let files_dir = Path::new("/device/files");
+        assert_eq!(thumbnail_path(files_dir, "clue 1.mp4"), Path::new("/device/files/.thumbnails/clue 1.mp4.jpg"));
+        assert_eq!(source_name("clue 1.mp4.jpg"), Some("clue 1.mp4"));
+        assert_eq!(source_name(".jpg"), None);
+        assert_eq!(source_name(".frame-1234"), None);
+    }
+
+    #[test]
+    fn generates_in_the_background_and_does_not_retry_failures() {
+        let dir = TempDir::new().unwrap();
+        image::RgbImage::new(64, 32).save(dir.path().join("idle.png")).unwrap();
+        fs::write(dir.path().join("broken.png"), b"not a png").unwrap();
+        let thumbnails = Thumbnails::new(dir.path().to_path_buf());
+
+        assert!(matches!(thumbnails.get("idle.png"), Err(Pending(_))));
+        assert_eq!(wait_for(&thumbnails, "idle.png").unwrap(), thumbnail_path(dir.path(), "idle.png"));
+
+        assert!(matches!(wait_for(&thumbnails, "broken.png"), Err(Failed(_))));
+        assert!(matches!(thumbnails.get("broken.png"), Err(Failed(_))));
+        assert!(thumbnails.queue.lock().unwrap().queued.is_empty());
+
+        set_modified(&dir.path().join("broken.png"), SystemTime::now() + Duration::from_secs(60));
+        assert!(matches!(thumbnails.get("broken.png"), Err(Pending(_))));
+    }
+}
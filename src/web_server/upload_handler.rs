@@ -0,0 +1,369 @@
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+use log::{error, info, warn};
+use multipart::server::Multipart;
+use multipart::server::save::{PartialReason, SaveResult, SavedData};
+use serde::Serialize;
+use tiny_http::Request;
+use uuid::Uuid;
+use crate::backup::archive::RESTORE_DIR_PREFIX;
+use crate::config::setup::{DeviceConfiguration, DuplicatePolicy};
+use crate::web_server::media_path::resolve_new_media_path;
+
+const STAGING_DIR: &str = ".staging";
+const MAX_FILE_NAME_LENGTH: usize = 255;
+
+/// The limits an upload is checked against, taken from the device configuration.
+#[derive(Debug, Clone)]
+pub struct UploadPolicy {
+    pub max_size: u64,
+    pub allowed_extensions: Vec<String>,
+    pub duplicates: DuplicatePolicy,
+}
+
+impl UploadPolicy {
+    pub fn from_config(config: &DeviceConfiguration) -> UploadPolicy {
+        UploadPolicy {
+            max_size: config.max_upload_size,
+            allowed_extensions: config.allowed_extensions.iter().map(|ext| ext.to_lowercase()).collect(),
+            duplicates: config.duplicate_uploads,
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct UploadReport {
+    pub accepted: Vec<AcceptedUpload>,
+    pub rejected: Vec<RejectedUpload>,
+}
+
+impl UploadReport {
+    pub fn accepted_names(&self) -> Vec<String> {
+        self.accepted.iter().map(|accepted| accepted.name.clone()).collect()
+    }
+
+    pub fn accepted_bytes(&self) -> u64 {
+        self.accepted.iter().map(|accepted| accepted.size).sum()
+    }
+
+    pub fn status_code(&self) -> u16 {
+        if self.accepted.is_empty() && !self.rejected.is_empty() { 400 } else { 201 }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct AcceptedUpload {
+    pub name: String,
+    pub size: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RejectedUpload {
+    pub name: String,
+    pub reason: String,
+}
+
+/// Directory for files that are still being received, it lives inside the library
+/// so finished files can be renamed into place.
+pub fn staging_dir(files_dir: &Path) -> PathBuf {
+    files_dir.join(STAGING_DIR)
+}
+
+/// Removes anything left behind by multipart uploads and restores that were cut off before the device restarted.
+/// Other directories are left alone, they hold resumable upload sessions.
+pub fn clear_staging_dir(files_dir: &Path) {
+    let Ok(entries) = fs::read_dir(staging_dir(files_dir)) else {
+        return;
+    };
+
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let path = entry.path();
+        let result = if path.is_file() {
+            fs::remove_file(&path)
+        } else if entry.file_name().to_string_lossy().starts_with(RESTORE_DIR_PREFIX) {
+            fs::remove_dir_all(&path)
+        } else {
+            continue;
+        };
+        if let Err(err) = result {
+            error!("Failed to remove partial upload {}: {:?}", path.display(), err);
+        }
+    }
+}
+
+/// Reads a `multipart/form-data` body and moves every file that passes the policy into `files_dir`.
+///
+/// Each file is written to the staging directory first and only renamed into the library once it
+/// has been completely received and validated, so a dropped connection never leaves a truncated file behind.
+pub fn save_multipart_upload(request: &mut Request, files_dir: &Path, policy: &UploadPolicy) -> io::Result<UploadReport> {
+    let boundary = request
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv("Content-Type"))
+        .and_then(|h| h.value.as_str().split("boundary=").nth(1).map(|b| b.trim_matches('"').to_string()))
+        .ok_or(io::Error::new(io::ErrorKind::InvalidInput, "No multipart boundary found"))?;
+
+    let staging = staging_dir(files_dir);
+    fs::create_dir_all(&staging)?;
+
+    let mut multipart = Multipart::with_body(request.as_reader(), &boundary);
+    let mut report = UploadReport::default();
+
+    while let Some(mut field) = multipart.read_entry()? {
+        let Some(original_name) = field.headers.filename.clone() else {
+            continue;
+        };
+
+        let name = match sanitize_file_name(&original_name).ok_or("Invalid file name".to_owned())
+            .and_then(|name| check_extension(&name, policy).map(|_| name)) {
+            Ok(name) => name,
+            Err(reason) => {
+                warn!("Rejected upload {}: {}", original_name, reason);
+                report.rejected.push(RejectedUpload { name: original_name, reason });
+                continue;
+            }
+        };
+
+        let temp_path = staging.join(format!("{}.part", Uuid::new_v4()));
+        info!("Pulling file {} from client into {}", name, temp_path.display());
+
+        let saved = field.data.save()
+            .size_limit(policy.max_size)
+            .memory_threshold(0)
+            .ignore_text()
+            .with_path(&temp_path);
+
+        let result = match saved {
+            SaveResult::Full(SavedData::File(_, size)) => validate_media(&temp_path, &name)
+                .and_then(|_| move_into_library(&temp_path, files_dir, &name, policy.duplicates))
+                .map(|name| AcceptedUpload { name, size }),
+            SaveResult::Full(_) => Err("File was empty".to_owned()),
+            SaveResult::Partial(_, PartialReason::SizeLimit) => Err(format!("File is larger than {} bytes", policy.max_size)),
+            SaveResult::Partial(_, reason) => Err(format!("Upload was interrupted: {:?}", reason)),
+            SaveResult::Error(err) => Err(format!("Failed to save file: {}", err)),
+        };
+
+        if temp_path.exists() {
+            fs::remove_file(&temp_path)?;
+        }
+
+        match result {
+            Ok(accepted) => {
+                info!("Saved upload {} ({} bytes)", accepted.name, accepted.size);
+                report.accepted.push(accepted);
+            }
+            Err(reason) => {
+                warn!("Rejected upload {}: {}", original_name, reason);
+                report.rejected.push(RejectedUpload { name: original_name, reason });
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+/// Keeps only the last path component of a client supplied name and strips characters
+/// that would make it awkward or dangerous to use as a file name.
+pub fn sanitize_file_name(name: &str) -> Option<String> {
+    let base = name.rsplit(['/', '\\']).next()?;
+    let cleaned = base
+        .chars()
+        .filter(|c| !c.is_control())
+        .map(|c| if matches!(c, '<' | '>' | ':' | '"' | '|' | '?' | '*') { '_' } else { c })
+        .collect::<String>();
+    let cleaned = cleaned.trim().trim_start_matches('.').trim();
+
+    if cleaned.is_empty() || cleaned.len() > MAX_FILE_NAME_LENGTH {
+        return None;
+    }
+    Some(cleaned.to_owned())
+}
+
+pub fn check_extension(name: &str, policy: &UploadPolicy) -> Result<(), String> {
+    let extension = Path::new(name).extension().and_then(|ext| ext.to_str()).unwrap_or("").to_lowercase();
+    if policy.allowed_extensions.contains(&extension) {
+        Ok(())
+    } else {
+        Err(format!("Only {} files are allowed", policy.allowed_extensions.join(", ")))
+    }
+}
+
+/// Makes sure videos can be read by the same mp4 parser the rfid reader uses to time clues.
+pub fn validate_media(path: &Path, name: &str) -> Result<(), String> {
+    let is_mp4 = Path::new(name).extension().is_some_and(|ext| ext.eq_ignore_ascii_case("mp4"));
+    if !is_mp4 {
+        return Ok(());
+    }
+
+    let file = File::open(path).map_err(|err| err.to_string())?;
+    let size = file.metadata().map_err(|err| err.to_string())?.len();
+    mp4::Mp4Reader::read_header(BufReader::new(file), size)
+        .map(|_| ())
+        .map_err(|err| format!("Not a valid mp4 file: {}", err))
+}
+
+/// Moves a fully received file into the library following the duplicate policy,
+/// returning the name it was stored under.
+///
+/// Unless existing files are overwritten the new name is claimed with a hard link, which fails when
+/// the name is taken, so two uploads of the same name at once can't end up replacing each other.
+pub fn move_into_library(temp_path: &Path, files_dir: &Path, name: &str, duplicates: DuplicatePolicy) -> Result<String, String> {
+    let stored = match duplicates {
+        DuplicatePolicy::Overwrite => {
+            let destination = resolve_new_media_path(files_dir, name).map_err(|err| err.to_string())?;
+            fs::rename(temp_path, &destination).map_err(|err| format!("Failed to store file: {}", err))?;
+            return Ok(name.to_owned());
+        }
+        DuplicatePolicy::Reject => {
+            if !claim(temp_path, files_dir, name)? {
+                return Err(format!("{} already exists", name));
+            }
+            name.to_owned()
+        }
+        DuplicatePolicy::Rename => {
+            let mut candidates = std::iter::once(name.to_owned()).chain(numbered_names(name));
+            loop {
+                // The candidates never run out
+                let candidate = candidates.next().unwrap();
+                if claim(temp_path, files_dir, &candidate)? {
+                    break candidate;
+                }
+            }
+        }
+    };
+
+    if let Err(err) = fs::remove_file(temp_path) {
+        error!("Failed to remove {}: {:?}", temp_path.display(), err);
+    }
+    Ok(stored)
+}
+
+/// Links the file in under `name`, returning false when that name is already taken.
+fn claim(temp_path: &Path, files_dir: &Path, name: &str) -> Result<bool, String> {
+    let destination = resolve_new_media_path(files_dir, name).map_err(|err| err.to_string())?;
+    match fs::hard_link(temp_path, &destination) {
+        Ok(()) => Ok(true),
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => Ok(false),
+        Err(err) => Err(format!("Failed to store file: {}", err)),
+    }
+}
+
+/// `clue (1).mp4`, `clue (2).mp4` and so on for `clue.mp4`.
+fn numbered_names(name: &str) -> impl Iterator<Item = String> {
+    let path = Path::new(name);
+    let stem = path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+    let extension = path.extension().map(|ext| format!(".{}", ext.to_string_lossy())).unwrap_or_default();
+
+    (1..).map(move |i| format!("{} ({}){}", stem, i, extension))
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+    use super::*;
+
+    fn policy() -> UploadPolicy {
+        UploadPolicy { max_size: 1024, allowed_extensions: vec!["mp4".to_owned(), "png".to_owned()], duplicates: DuplicatePolicy::Reject }
+    }
+
+    /// Receives a file with `content` into the staging directory of `library`
+    fn receive(library: &TempDir, content: &[u8]) -> PathBuf {
+        let staging = staging_dir(library.path());
+        fs::create_dir_all(&staging).unwrap();
+        let temp_path = staging.join(format!("{}.part", Uuid::new_v4()));
+        fs::write(&temp_path, content).unwrap();
+        temp_path
+    }
+
+    #[test]
+    fn sanitizes_client_file_names() {
+        assert_eq!(sanitize_file_name("clue.mp4").as_deref(), Some("clue.mp4"));
+        assert_eq!(sanitize_file_name("C:\\Users\\gm\\clue.mp4").as_deref(), Some("clue.mp4"));
+        assert_eq!(sanitize_file_name("../../config/Config.yaml").as_deref(), Some("Config.yaml"));
+        assert_eq!(sanitize_file_name("a/b\\c.png").as_deref(), Some("c.png"));
+        assert_eq!(sanitize_file_name(".hidden.mp4").as_deref(), Some("hidden.mp4"));
+        assert_eq!(sanitize_file_name("what?<now>.mp4").as_deref(), Some("what__now_.mp4"));
+        for name in ["", "..", "../", ".", "  ", "clips/", &"a".repeat(MAX_FILE_NAME_LENGTH + 1)] {
+            assert_eq!(sanitize_file_name(name), None, "{} was accepted", name);
+        }
+    }
+
+    #[test]
+    fn checks_extensions_ignoring_case() {
+        assert!(check_extension("clue.MP4", &policy()).is_ok());
+        assert!(check_extension("idle.Png", &policy()).is_ok());
+        assert!(check_extension("notes.txt", &policy()).is_err());
+        assert!(check_extension("mp4", &policy()).is_err());
+        assert!(check_extension("clue.mp4.sh", &policy()).is_err());
+    }
+
+    #[test]
+    fn only_parses_videos() {
+        let library = TempDir::new().unwrap();
+        let temp_path = receive(&library, b"not a video");
+        assert!(validate_media(&temp_path, "clue.mp4").is_err());
+        assert!(validate_media(&temp_path, "clue.MP4").is_err());
+        assert!(validate_media(&temp_path, "idle.png").is_ok());
+    }
+
+    #[test]
+    fn reject_keeps_the_existing_file() {
+        let library = TempDir::new().unwrap();
+        fs::write(library.path().join("clue.mp4"), b"old").unwrap();
+        let temp_path = receive(&library, b"new");
+
+        assert!(move_into_library(&temp_path, library.path(), "clue.mp4", DuplicatePolicy::Reject).is_err());
+        assert_eq!(fs::read(library.path().join("clue.mp4")).unwrap(), b"old");
+        assert_eq!(move_into_library(&temp_path, library.path(), "other.mp4", DuplicatePolicy::Reject).unwrap(), "other.mp4");
+        assert_eq!(fs::read(library.path().join("other.mp4")).unwrap(), b"new");
+        assert!(!temp_path.exists());
+    }
+
+    #[test]
+    fn concurrent_uploads_of_one_name_never_replace_each_other() {
+        let library = TempDir::new().unwrap();
+        let uploads = (0..8).map(|i| receive(&library, format!("upload {}", i).as_bytes())).collect::<Vec<_>>();
+        let results = std::thread::scope(|scope| {
+            let threads = uploads.iter()
+                .map(|temp_path| scope.spawn(|| move_into_library(temp_path, library.path(), "clue.mp4", DuplicatePolicy::Reject)))
+                .collect::<Vec<_>>();
+            threads.into_iter().map(|thread| thread.join().unwrap()).collect::<Vec<_>>()
+        });
+        let stored = results.iter().position(|result| result.is_ok()).unwrap();
+        assert_eq!(results.iter().filter(|result| result.is_ok()).count(), 1);
+        assert_eq!(fs::read(library.path().join("clue.mp4")).unwrap(), format!("upload {}", stored).as_bytes());
+    }
+
+    #[test]
+    fn overwrite_replaces_the_existing_file() {
+        let library = TempDir::new().unwrap();
+        fs::write(library.path().join("idle.png"), b"old").unwrap();
+        let temp_path = receive(&library, b"new");
+
+        assert_eq!(move_into_library(&temp_path, library.path(), "idle.png", DuplicatePolicy::Overwrite).unwrap(), "idle.png");
+        assert_eq!(fs::read(library.path().join("idle.png")).unwrap(), b"new");
+        assert!(!temp_path.exists());
+    }
+
+    #[test]
+    fn rename_numbers_the_new_file() {
+        let library = TempDir::new().unwrap();
+        fs::write(library.path().join("clue.mp4"), b"first").unwrap();
+        fs::write(library.path().join("clue (1).mp4"), b"second").unwrap();
+
+        for (content, expected) in [(b"third", "clue (2).mp4"), (b"later", "clue (3).mp4")] {
+            let temp_path = receive(&library, content);
+            assert_eq!(move_into_library(&temp_path, library.path(), "clue.mp4", DuplicatePolicy::Rename).unwrap(), expected);
+            assert_eq!(fs::read(library.path().join(expected)).unwrap(), content);
+        }
+        let temp_path = receive(&library, b"new");
+        assert_eq!(move_into_library(&temp_path, library.path(), "notes", DuplicatePolicy::Rename).unwrap(), "notes");
+        let temp_path = receive(&library, b"newer");
+        assert_eq!(move_into_library(&temp_path, library.path(), "notes", DuplicatePolicy::Rename).unwrap(), "notes (1)");
+        assert_eq!(fs::read(library.path().join("clue.mp4")).unwrap(), b"first");
+    }
+}
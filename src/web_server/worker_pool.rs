@@ -0,0 +1,185 @@
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::thread;
+use std::thread::JoinHandle;
+
+use log::{error, info, warn};
+use tiny_http::{Request, Response};
+use crate::web_server::app_state::AppState;
+use crate::web_server::router::{Router, ServerState, UNMATCHED_ROUTE};
+
+/// How many requests may wait for a free worker per worker before new ones are turned away.
+const QUEUED_REQUESTS_PER_WORKER: usize = 4;
+
+/// A fixed number of threads that take requests off a bounded queue and route them,
+/// so a slow upload or download does not hold up everything else.
+pub struct WorkerPool<S = AppState> {
+    queue: SyncSender<Request>,
+    state: Arc<S>,
+    _workers: Vec<JoinHandle<()>>,
+}
+
+impl<S: ServerState> WorkerPool<S> {
+    pub fn new(size: usize, router: Arc<Router<S>>, state: Arc<S>) -> WorkerPool<S> {
+        let size = size.max(1);
+        let (queue, requests) = sync_channel::<Request>(size * QUEUED_REQUESTS_PER_WORKER);
+        let requests = Arc::new(Mutex::new(requests));
+
+        info!("Starting {} http workers", size);
+        let workers = (0..size)
+            .map(|id| {
+                let requests = requests.clone();
+                let router = router.clone();
+                let state = state.clone();
+                thread::Builder::new()
+                    .name(format!("http-worker-{}", id))
+                    .spawn(move || worker(requests, router, state))
+                    .expect("Failed to spawn http worker")
+            })
+            .collect();
+
+        WorkerPool {
+            queue,
+            state,
+            _workers: workers,
+        }
+    }
+
+    /// Queues the request for the next free worker, responding with 503 when the queue is full.
+    pub fn dispatch(&self, request: Request) {
+        match self.queue.try_send(request) {
+            Ok(_) => {}
+            Err(TrySendError::Full(request)) => {
+                warn!("All http workers are busy turning away {} {}", request.method(), request.url());
+                self.state.http_request(UNMATCHED_ROUTE, 503);
+                request.respond(Response::from_string("Server busy").with_status_code(503)).unwrap_or_else(|err| {
+                    error!("Failed to send response to client: {:?}", err);
+                });
+            }
+            Err(TrySendError::Disconnected(request)) => {
+                error!("All http workers have stopped dropping {} {}", request.method(), request.url());
+            }
+        }
+    }
+}
+
+fn worker<S: ServerState>(requests: Arc<Mutex<Receiver<Request>>>, router: Arc<Router<S>>, state: Arc<S>) {
+    loop {
+        // The lock is only held while waiting for the next request, not while handling it
+        let request = match requests.lock() {
+            Ok(requests) => requests.recv(),
+            Err(_) => break,
+        };
+
+        match request {
+            Ok(request) => {
+                // A panicking handler loses its request but must not take the worker with it
+                if catch_unwind(AssertUnwindSafe(|| router.dispatch(request, &state))).is_err() {
+                    error!("Http worker {:?} recovered from a panicking handler", thread::current().name());
+                }
+            }
+            Err(_) => break,
+        }
+    }
+    info!("Http worker {:?} stopped", thread::current().name());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::{Duration, Instant};
+    use tiny_http::Server;
+    use crate::config::users::UserAccount;
+    use crate::web_server::api::ApiError;
+    use crate::web_server::auth::{Access, SessionStore};
+    use crate::web_server::router::{PathParams, SendResponse};
+    use super::*;
+
+    /// Held by the test to keep `/slow` requests from finishing.
+    static GATE: Mutex<()> = Mutex::new(());
+    static SLOW_STARTED: AtomicUsize = AtomicUsize::new(0);
+
+    struct TestState {
+        sessions: SessionStore,
+        requests: Mutex<Vec<(String, u16)>>,
+    }
+
+    impl ServerState for TestState {
+        fn sessions(&self) -> &SessionStore {
+            &self.sessions
+        }
+
+        fn find_user(&self, _: &str) -> Option<UserAccount> {
+            None
+        }
+
+        fn save_password(&self, _: &str, _: &str) -> Result<(), ApiError> {
+            unimplemented!()
+        }
+
+        fn http_request(&self, route: &str, status: u16) {
+            self.requests.lock().unwrap().push((route.to_owned(), status));
+        }
+    }
+
+    fn slow(request: Request, _: &TestState, _: &PathParams) -> Result<(), Box<dyn Error>> {
+        SLOW_STARTED.fetch_add(1, Ordering::SeqCst);
+        drop(GATE.lock());
+        request.send_response(Response::from_string("slow"))?;
+        Ok(())
+    }
+
+    fn start(size: usize) -> (Server, WorkerPool<TestState>, Arc<TestState>) {
+        let router = Router::new()
+            .get("/slow", Access::Public, slow)
+            .get("/ok", Access::Public, |request, _, _| Ok(request.send_response(Response::from_string("ok"))?))
+            .get("/panic", Access::Public, |_, _, _| panic!("handler failed"));
+        let state = Arc::new(TestState { sessions: SessionStore::new(), requests: Mutex::new(Vec::new()) });
+        (Server::http("127.0.0.1:0").unwrap(), WorkerPool::new(size, Arc::new(router), state.clone()), state)
+    }
+
+    /// Sends a request from another thread and hands it to the pool, returning the client's status code.
+    fn send(server: &Server, pool: &WorkerPool<TestState>, path: &str) -> JoinHandle<u16> {
+        let url = format!("http://{}{}", server.server_addr().to_ip().unwrap(), path);
+        let client = thread::spawn(move || match ureq::get(&url).call() {
+            Ok(response) => response.status(),
+            Err(ureq::Error::Status(status, _)) => status,
+            Err(err) => panic!("{}", err),
+        });
+        pool.dispatch(server.recv().unwrap());
+        client
+    }
+
+    #[test]
+    fn turns_requests_away_when_the_queue_is_full() {
+        let (server, pool, state) = start(1);
+        let gate = GATE.lock().unwrap();
+
+        let busy = send(&server, &pool, "/slow");
+        let started = Instant::now();
+        while SLOW_STARTED.load(Ordering::SeqCst) == 0 {
+            assert!(started.elapsed() < Duration::from_secs(5), "The worker never picked up the request");
+            thread::sleep(Duration::from_millis(5));
+        }
+        let queued = (0..QUEUED_REQUESTS_PER_WORKER).map(|_| send(&server, &pool, "/ok")).collect::<Vec<_>>();
+        assert_eq!(send(&server, &pool, "/ok").join().unwrap(), 503);
+        assert_eq!(*state.requests.lock().unwrap(), vec![(UNMATCHED_ROUTE.to_owned(), 503)]);
+
+        drop(gate);
+        assert_eq!(busy.join().unwrap(), 200);
+        assert!(queued.into_iter().all(|client| client.join().unwrap() == 200));
+    }
+
+    #[test]
+    fn panicking_handlers_do_not_stop_workers() {
+        let (server, pool, _) = start(2);
+        for _ in 0..4 {
+            assert_eq!(send(&server, &pool, "/panic").join().unwrap(), 500);
+        }
+        for _ in 0..4 {
+            assert_eq!(send(&server, &pool, "/ok").join().unwrap(), 200);
+        }
+    }
+}
@@ -0,0 +1,265 @@
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::{debug, error, info, warn};
+use serde::Serialize;
+use crate::config::setup::WebhookConfiguration;
+use crate::events::event_bus::{DeviceEvent, EventBus, PlayerState};
+use crate::metrics::device_metrics::Metrics;
+use crate::web_server::api::DeviceIdentity;
+
+/// Attempts at delivering an event before it is given up on.
+const MAX_ATTEMPTS: u32 = 5;
+/// Events waiting per webhook, new events are dropped while a webhook is this far behind.
+const QUEUE_SIZE: usize = 100;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// Wait before the first retry, it doubles with every retry after that.
+pub const RETRY_DELAY: Duration = Duration::from_secs(2);
+
+pub const EVENT_NAMES: [&str; 7] = ["card_scanned", "unknown_card", "pairing_completed", "playback_started", "playback_finished", "device_booted", "card_action"];
+
+/// What is posted to webhooks, a narrower view of the device events.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum WebhookEvent {
+    /// `media` is null for a card whose actions play no file
+    CardScanned { card_id: String, uid: String, media: Option<String> },
+    UnknownCard { card_id: String, uid: String },
+    PairingCompleted { card_id: String, uid: String, media: String },
+    PlaybackStarted { media: String },
+    PlaybackFinished { media: String },
+    DeviceBooted { version: String },
+    /// Fired by a step of a card's actions
+    CardAction { card_id: String, name: String },
+}
+
+impl WebhookEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            WebhookEvent::CardScanned { .. } => "card_scanned",
+            WebhookEvent::UnknownCard { .. } => "unknown_card",
+            WebhookEvent::PairingCompleted { .. } => "pairing_completed",
+            WebhookEvent::PlaybackStarted { .. } => "playback_started",
+            WebhookEvent::PlaybackFinished { .. } => "playback_finished",
+            WebhookEvent::DeviceBooted { .. } => "device_booted",
+            WebhookEvent::CardAction { .. } => "card_action",
+        }
+    }
+}
+
+/// Body of every webhook request, the event's own fields sit next to these.
+#[derive(Debug, Serialize)]
+struct Payload<'a> {
+    #[serde(flatten)]
+    event: &'a WebhookEvent,
+    device_uuid: &'a str,
+    device_name: &'a str,
+    /// Seconds since the unix epoch
+    timestamp: u64,
+}
+
+/// Posts events to every configured webhook.
+///
+/// Each webhook has its own bounded queue and delivery thread, so a slow or unreachable
+/// endpoint only holds up its own events and never the reader or player.
+pub struct Webhooks {
+    identity: DeviceIdentity,
+    queues: Vec<WebhookQueue>,
+    metrics: Arc<Metrics>,
+}
+
+struct WebhookQueue {
+    url: String,
+    events: Vec<String>,
+    sender: SyncSender<Vec<u8>>,
+}
+
+impl Webhooks {
+    pub fn new(webhooks: &[WebhookConfiguration], identity: DeviceIdentity, metrics: Arc<Metrics>, retry_delay: Duration) -> Webhooks {
+        let queues = webhooks.iter()
+            .map(|webhook| {
+                for name in webhook.events.iter().filter(|name| !EVENT_NAMES.contains(&name.as_str())) {
+                    warn!("Webhook {} asks for unknown event {}, known events are {}", webhook.url, name, EVENT_NAMES.join(", "));
+                }
+
+                let (sender, receiver) = sync_channel(QUEUE_SIZE);
+                let url = webhook.url.clone();
+                let metrics = metrics.clone();
+                thread::spawn(move || deliver(&url, receiver, &metrics, retry_delay));
+                info!("Sending events to webhook {}", webhook.url);
+                WebhookQueue { url: webhook.url.clone(), events: webhook.events.clone(), sender }
+            })
+            .collect();
+
+        Webhooks { identity, queues, metrics }
+    }
+
+    /// Forwards events from the bus to the webhooks from now on.
+    pub fn listen(self: &Arc<Self>, events: &EventBus) {
+        if self.queues.is_empty() {
+            return;
+        }
+        let receiver = events.subscribe();
+        let webhooks = self.clone();
+        thread::spawn(move || {
+            let mut translator = EventTranslator::default();
+            for event in receiver {
+                for event in translator.translate(event) {
+                    webhooks.send(&event);
+                }
+            }
+        });
+    }
+
+    /// Queues `event` for every webhook that wants it, never waiting on a webhook that is behind.
+    pub fn send(&self, event: &WebhookEvent) {
+        let queues = self.queues.iter()
+            .filter(|queue| queue.events.is_empty() || queue.events.iter().any(|name| name == event.name()))
+            .collect::<Vec<_>>();
+        if queues.is_empty() {
+            return;
+        }
+
+        let payload = Payload {
+            event,
+            device_uuid: &self.identity.device_uuid,
+            device_name: &self.identity.name,
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+        };
+        let body = match serde_json::to_vec(&payload) {
+            Ok(body) => body,
+            Err(err) => {
+                error!("Failed to serialize webhook event {:?}: {:?}", event, err);
+                return;
+            }
+        };
+
+        for queue in queues {
+            match queue.sender.try_send(body.clone()) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => {
+                    warn!("Dropped {} event for webhook {}, its queue is full", event.name(), queue.url);
+                    self.metrics.webhook_delivery("dropped");
+                }
+                Err(TrySendError::Disconnected(_)) => error!("Delivery thread of webhook {} has stopped", queue.url),
+            }
+        }
+    }
+}
+
+/// Posts each queued body in order, retrying with a growing delay when the endpoint fails.
+fn deliver(url: &str, receiver: Receiver<Vec<u8>>, metrics: &Metrics, retry_delay: Duration) {
+    let agent = ureq::AgentBuilder::new().timeout(REQUEST_TIMEOUT).build();
+
+    for body in receiver {
+        let mut delay = retry_delay;
+        for attempt in 1..=MAX_ATTEMPTS {
+            let error = match agent.post(url).set("Content-Type", "application/json").send_bytes(&body) {
+                Ok(_) => {
+                    debug!("Delivered webhook event to {}", url);
+                    metrics.webhook_delivery("delivered");
+                    break;
+                }
+                // The endpoint understood and refused it, sending it again won't help
+                Err(ureq::Error::Status(status, _)) if (400..500).contains(&status) && status != 408 && status != 429 => {
+                    error!("Webhook {} rejected event with status {}", url, status);
+                    metrics.webhook_delivery("failed");
+                    break;
+                }
+                Err(err) => err,
+            };
+
+            if attempt == MAX_ATTEMPTS {
+                error!("Giving up on webhook event for {} after {} attempts: {}", url, MAX_ATTEMPTS, error);
+                metrics.webhook_delivery("failed");
+            } else {
+                warn!("Failed to deliver webhook event to {} retrying in {:?}: {}", url, delay, error);
+                thread::sleep(delay);
+                delay *= 2;
+            }
+        }
+    }
+}
+
+/// Turns device events into webhook events, remembering what plays so the end of a clip can be reported.
+#[derive(Default)]
+struct EventTranslator {
+    playing: Option<String>,
+}
+
+impl EventTranslator {
+    fn translate(&mut self, event: DeviceEvent) -> Vec<WebhookEvent> {
+        match event {
+            DeviceEvent::CardScanned { card_id, uid, paired: true, media } => vec![WebhookEvent::CardScanned { card_id, uid, media }],
+            DeviceEvent::CardScanned { card_id, uid, paired: false, .. } => vec![WebhookEvent::UnknownCard { card_id, uid }],
+            DeviceEvent::CardPaired { card_id, uid, media } => vec![WebhookEvent::PairingCompleted { card_id, uid, media }],
+            DeviceEvent::PlayerState { player } => {
+                let mut events = Vec::new();
+                if let Some(media) = self.playing.take() {
+                    events.push(WebhookEvent::PlaybackFinished { media });
+                }
+                if let PlayerState::Playing { media } = player {
+                    self.playing = Some(media.clone());
+                    events.push(WebhookEvent::PlaybackStarted { media });
+                }
+                events
+            }
+            DeviceEvent::Booted { version } => vec![WebhookEvent::DeviceBooted { version }],
+            DeviceEvent::RfidWaiting { .. } | DeviceEvent::UploadCompleted { .. } => Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::channel;
+    use tiny_http::{Response, Server};
+    use super::*;
+
+    #[test]
+    fn reports_the_end_of_each_clip() {
+        let mut translator = EventTranslator::default();
+        let playing = |media: &str| DeviceEvent::PlayerState { player: PlayerState::Playing { media: media.to_owned() } };
+
+        let events = [playing("a.mp4"), playing("b.mp4"), DeviceEvent::PlayerState { player: PlayerState::Idle }]
+            .into_iter()
+            .flat_map(|event| translator.translate(event))
+            .map(|event| event.name())
+            .collect::<Vec<_>>();
+        assert_eq!(events, vec!["playback_started", "playback_finished", "playback_started", "playback_finished"]);
+        assert!(translator.translate(DeviceEvent::PlayerState { player: PlayerState::Idle }).is_empty());
+    }
+
+    #[test]
+    fn retries_until_the_endpoint_accepts_and_only_sends_wanted_events() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", server.server_addr().to_ip().unwrap());
+        let (bodies, received) = channel();
+        thread::spawn(move || {
+            for (attempt, mut request) in server.incoming_requests().enumerate() {
+                let mut body = String::new();
+                request.as_reader().read_to_string(&mut body).unwrap();
+                bodies.send(body).unwrap();
+                // The first attempt fails like a restarting endpoint would
+                let _ = request.respond(Response::empty(if attempt == 0 { 503 } else { 204 }));
+            }
+        });
+
+        let config = [WebhookConfiguration { url, events: vec!["unknown_card".to_owned()] }];
+        let identity = DeviceIdentity { device_uuid: "device".to_owned(), name: "Study".to_owned(), version: "test".to_owned() };
+        let webhooks = Webhooks::new(&config, identity, Arc::new(Metrics::new()), Duration::from_millis(10));
+        webhooks.send(&WebhookEvent::DeviceBooted { version: "test".to_owned() });
+        webhooks.send(&WebhookEvent::UnknownCard { card_id: "card".to_owned(), uid: "0a0b".to_owned() });
+
+        let first = received.recv_timeout(Duration::from_secs(5)).unwrap();
+        let retry = received.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(first, retry);
+        let payload = serde_json::from_str::<serde_json::Value>(&retry).unwrap();
+        assert_eq!(payload["event"], "unknown_card");
+        assert_eq!(payload["uid"], "0a0b");
+        assert_eq!(payload["device_uuid"], "device");
+        assert!(received.recv_timeout(Duration::from_millis(200)).is_err());
+    }
+}